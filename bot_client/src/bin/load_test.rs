@@ -0,0 +1,384 @@
+//! Connects simulated players to a ship and reports request latencies, server memory and errors.
+//!
+//! Every player logs in (the account is registered on the first run if the master allows it),
+//! enters the lobby, walks around and chats. Players are then grouped into parties, the party
+//! leader accepts a quest and the whole party goes to the quest field.
+use bot_client::{load_ship_key, new_character, Bot, Error};
+use clap::Parser;
+use pso2packetlib::{
+    protocol::{chat::MessageChannel, Packet},
+    PublicKey,
+};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{oneshot, watch, Barrier};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Address of the block to connect to
+    #[arg(short, long, default_value = "127.0.0.1:13001")]
+    address: String,
+    /// Location of the ship key file (`key_file` in the ship settings)
+    #[arg(short, long, default_value = "keypair.pem")]
+    key_file: PathBuf,
+    /// Number of simulated players
+    #[arg(short, long, default_value_t = 10)]
+    players: usize,
+    /// Number of players in a party (1-4)
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u8).range(1..=4))]
+    party_size: u8,
+    /// Delay between player connections in milliseconds
+    #[arg(long, default_value_t = 50)]
+    ramp_up: u64,
+    /// Number of movement packets sent by every player in the lobby
+    #[arg(long, default_value_t = 20)]
+    steps: usize,
+    /// Number of chat messages sent by every player
+    #[arg(long, default_value_t = 5)]
+    messages: usize,
+    /// ID of the quest to run, 0 to stay in the lobby
+    #[arg(short, long, default_value_t = 1100)]
+    quest: u32,
+    /// Difficulty of the quest
+    #[arg(long, default_value_t = 0)]
+    difficulty: u16,
+    /// Prefix of the account names
+    #[arg(long, default_value = "loadbot")]
+    prefix: String,
+    /// Password of the accounts
+    #[arg(long, default_value = "password")]
+    password: String,
+    /// Timeout of a single request in seconds
+    #[arg(short, long, default_value_t = 30)]
+    timeout: u64,
+}
+
+/// Failed step of a player.
+struct Failure {
+    step: &'static str,
+    error: Error,
+}
+
+#[derive(Default)]
+struct Stats {
+    latencies: Mutex<BTreeMap<&'static str, Vec<Duration>>>,
+}
+
+impl Stats {
+    /// Runs a step of the scenario and records its latency.
+    async fn time<T>(
+        &self,
+        step: &'static str,
+        f: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Failure> {
+        let start = Instant::now();
+        let value = f.await.map_err(|error| Failure { step, error })?;
+        let elapsed = start.elapsed();
+        self.latencies
+            .lock()
+            .unwrap()
+            .entry(step)
+            .or_default()
+            .push(elapsed);
+        Ok(value)
+    }
+}
+
+/// Synchronizes the players of a single party.
+struct PartySync {
+    members: tokio::sync::Mutex<Vec<u32>>,
+    barrier: Barrier,
+}
+
+struct Player {
+    id: usize,
+    args: Arc<Args>,
+    key: PublicKey,
+    stats: Arc<Stats>,
+    party: Arc<PartySync>,
+    is_leader: bool,
+}
+
+impl Player {
+    async fn run(self) -> Result<(), Failure> {
+        let args = &self.args;
+        let stats = &self.stats;
+        let name = format!("{}{}", args.prefix, self.id);
+        let mut bot = stats
+            .time("connect", Bot::connect(&args.address, self.key.clone()))
+            .await?;
+        bot.set_timeout(Duration::from_secs(args.timeout));
+        stats
+            .time("login", bot.login(&name, &args.password))
+            .await?;
+        let characters = stats.time("character_list", bot.characters()).await?;
+        let char_id = match characters.characters.first() {
+            Some(character) => character.character_id,
+            None => {
+                stats
+                    .time(
+                        "create_character",
+                        bot.create_character(new_character(&name)),
+                    )
+                    .await?
+            }
+        };
+        stats.time("start_game", bot.start_game(char_id)).await?;
+
+        for step in 0..args.steps {
+            let angle = step as f32 * 0.3;
+            let (x, z) = (angle.cos() * 5.0, angle.sin() * 5.0);
+            bot.move_to(x, 0.0, z).await.map_err(|error| Failure {
+                step: "walk",
+                error,
+            })?;
+            if step % 5 == 4 {
+                stats.time("ping", bot.ping()).await?;
+            }
+            bot.take_received();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        for i in 0..args.messages {
+            let message = format!("Message {i} from {name}");
+            let id = bot.player_id();
+            stats
+                .time("chat", async {
+                    bot.send_chat(MessageChannel::Map, &message).await?;
+                    bot.expect("ChatMessage", |p| match p {
+                        Packet::ChatMessage(m) if m.object.id == id && m.message == message => {
+                            Some(())
+                        }
+                        _ => None,
+                    })
+                    .await
+                })
+                .await?;
+            bot.take_received();
+        }
+
+        self.join_party(&mut bot).await?;
+        if args.quest != 0 {
+            if self.is_leader {
+                stats
+                    .time(
+                        "accept_quest",
+                        bot.accept_quest(args.quest, args.difficulty),
+                    )
+                    .await?;
+            } else {
+                bot.wait_for_quest().await.map_err(|error| Failure {
+                    step: "accept_quest",
+                    error,
+                })?;
+            }
+            stats.time("to_campship", bot.to_campship()).await?;
+            stats.time("campship_down", bot.campship_down()).await?;
+        }
+        bot.send(&Packet::ClientGoodbye)
+            .await
+            .map_err(|error| Failure {
+                step: "disconnect",
+                error,
+            })
+    }
+
+    async fn join_party(&self, bot: &mut Bot) -> Result<(), Failure> {
+        let step = "party";
+        let timeout = Duration::from_secs(self.args.timeout);
+        let party = &self.party;
+        if !self.is_leader {
+            party.members.lock().await.push(bot.player_id());
+        }
+        wait(party, timeout).await?;
+        if self.is_leader {
+            let members = party.members.lock().await.clone();
+            for member in members {
+                self.stats
+                    .time(step, async {
+                        bot.invite(member).await?;
+                        bot.expect("AddMember", |p| match p {
+                            Packet::AddMember(m) if m.new_member.id == member => Some(()),
+                            _ => None,
+                        })
+                        .await
+                    })
+                    .await?;
+            }
+        } else {
+            bot.accept_invite()
+                .await
+                .map_err(|error| Failure { step, error })?;
+        }
+        bot.take_received();
+        wait(party, timeout).await
+    }
+}
+
+async fn wait(party: &PartySync, timeout: Duration) -> Result<(), Failure> {
+    tokio::time::timeout(timeout, party.barrier.wait())
+        .await
+        .map(|_| ())
+        .map_err(|_| Failure {
+            step: "party",
+            error: Error::Timeout("party members"),
+        })
+}
+
+/// Logs in a separate player that samples the server memory with `!mem` until stopped.
+async fn monitor_memory(
+    args: Arc<Args>,
+    key: PublicKey,
+    ready: oneshot::Sender<()>,
+    mut stop: watch::Receiver<bool>,
+) -> Result<Vec<String>, Error> {
+    let name = format!("{}monitor", args.prefix);
+    let mut bot = Bot::connect(&args.address, key).await?;
+    bot.set_timeout(Duration::from_secs(args.timeout));
+    bot.login(&name, &args.password).await?;
+    let characters = bot.characters().await?;
+    let char_id = match characters.characters.first() {
+        Some(character) => character.character_id,
+        None => bot.create_character(new_character(&name)).await?,
+    };
+    bot.start_game(char_id).await?;
+    let mut samples = vec![bot.command("!mem").await?];
+    let _ = ready.send(());
+    loop {
+        tokio::select! {
+            _ = stop.changed() => break,
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+        }
+        samples.push(bot.command("!mem").await?);
+        bot.take_received();
+    }
+    samples.push(bot.command("!mem").await?);
+    Ok(samples)
+}
+
+/// Parses the physical memory from the `!mem` reply (e.g. "Physical memory: 12.50 MiB").
+fn physical_memory(sample: &str) -> Option<f64> {
+    let value = sample.lines().next()?.strip_prefix("Physical memory: ")?;
+    let (number, unit) = value.split_once(' ')?;
+    let multiplier = match unit {
+        "B" => 1.0,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+    Some(number.parse::<f64>().ok()? * multiplier)
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    let i = (sorted.len() * p).div_ceil(100).saturating_sub(1);
+    sorted[i.min(sorted.len() - 1)]
+}
+
+fn print_report(stats: &Stats, failures: &[(usize, Failure)], memory: &[String], total: Duration) {
+    println!("Finished in {:.2?}", total);
+    println!(
+        "{:<18}{:>8}{:>12}{:>12}{:>12}{:>12}",
+        "step", "count", "p50", "p90", "p99", "max"
+    );
+    for (step, latencies) in stats.latencies.lock().unwrap().iter_mut() {
+        latencies.sort();
+        println!(
+            "{:<18}{:>8}{:>12.2?}{:>12.2?}{:>12.2?}{:>12.2?}",
+            step,
+            latencies.len(),
+            percentile(latencies, 50),
+            percentile(latencies, 90),
+            percentile(latencies, 99),
+            latencies[latencies.len() - 1],
+        );
+    }
+
+    println!();
+    if let (Some(first), Some(last)) = (memory.first(), memory.last()) {
+        println!("Memory before the test:\n{first}");
+        println!("Memory after the test:\n{last}");
+        let peak = memory
+            .iter()
+            .filter_map(|s| physical_memory(s))
+            .fold(0.0, f64::max);
+        println!("Peak physical memory: {:.2} MiB", peak / 1024.0 / 1024.0);
+    } else {
+        println!("No memory samples");
+    }
+
+    println!();
+    println!("Errors: {}", failures.len());
+    let mut by_step = BTreeMap::<_, usize>::new();
+    for (_, failure) in failures {
+        *by_step.entry(failure.step).or_default() += 1;
+    }
+    for (step, count) in by_step {
+        println!("  {step}: {count}");
+    }
+    for (id, failure) in failures.iter().take(10) {
+        println!(
+            "  player {id} failed at {}: {}",
+            failure.step, failure.error
+        );
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Arc::new(Args::parse());
+    let key = load_ship_key(&args.key_file)?;
+    let stats = Arc::new(Stats::default());
+
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let monitor = tokio::spawn(monitor_memory(args.clone(), key.clone(), ready_tx, stop_rx));
+    // the monitor reports its error after the test if it fails to log in
+    let _ = ready_rx.await;
+
+    let start = Instant::now();
+    let party_size = args.party_size as usize;
+    let mut players = vec![];
+    for first in (0..args.players).step_by(party_size) {
+        let size = party_size.min(args.players - first);
+        let party = Arc::new(PartySync {
+            members: Default::default(),
+            barrier: Barrier::new(size),
+        });
+        for id in first..first + size {
+            let player = Player {
+                id,
+                args: args.clone(),
+                key: key.clone(),
+                stats: stats.clone(),
+                party: party.clone(),
+                is_leader: id == first,
+            };
+            players.push((id, tokio::spawn(player.run())));
+            tokio::time::sleep(Duration::from_millis(args.ramp_up)).await;
+        }
+    }
+
+    let mut failures = vec![];
+    for (id, handle) in players {
+        if let Err(failure) = handle.await? {
+            failures.push((id, failure));
+        }
+    }
+    let total = start.elapsed();
+    let _ = stop_tx.send(true);
+    let memory = match monitor.await? {
+        Ok(samples) => samples,
+        Err(e) => {
+            println!("Memory monitor failed: {e}");
+            vec![]
+        }
+    };
+    print_report(&stats, &failures, &memory, total);
+    Ok(())
+}
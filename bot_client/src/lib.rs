@@ -0,0 +1,440 @@
+//! Headless game client used to script sessions against the ship server.
+//!
+//! The bot speaks the PC (NA) protocol, so it goes through the same login flow as the real
+//! client: encryption handshake, Sega ID login, character creation and map loading.
+use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
+use half::f16;
+use pso2packetlib::{
+    connection::{ConnectionError, PrivateKey, PublicKey},
+    protocol::{
+        chat::{ChatMessage, MessageChannel},
+        login::{
+            self, BlockSwitchResponsePacket, CharacterListPacket, LoginStatus,
+            NicknameResponsePacket,
+        },
+        models::{
+            character::{Character, Class, Race},
+            Position,
+        },
+        objects::MovementPacket,
+        party, questlist,
+        server::MapLoadedPacket,
+        ObjectHeader, ObjectType, Packet, PacketType,
+    },
+    Connection,
+};
+use rand::RngCore;
+use rsa::pkcs8::DecodePrivateKey;
+use std::{net::Ipv4Addr, path::Path, time::Duration};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Connection error: {0}")]
+    ConnectionError(#[from] ConnectionError),
+    #[error("IO error: {0}")]
+    IOError(#[from] std::io::Error),
+    #[error("Invalid key: {0}")]
+    KeyError(#[from] rsa::pkcs8::Error),
+    #[error("Login failed: {0}")]
+    LoginFailed(String),
+    #[error("Character creation failed: {0:?}")]
+    CharacterCreationFailed(login::CharacterCreationStatus),
+    #[error("Timed out waiting for {0}")]
+    Timeout(&'static str),
+    #[error("Server closed the connection")]
+    Disconnected,
+}
+
+/// Scripted client connected to a single block.
+pub struct Bot {
+    connection: Connection<Packet>,
+    key: PublicKey,
+    timeout: Duration,
+    player_id: u32,
+    position: Position,
+    /// Packets received from the server that weren't consumed by the bot.
+    received: Vec<Packet>,
+}
+
+/// Loads the public part of the ship key from the PKCS#8 private key (`key_file` in the ship
+/// settings).
+pub fn load_ship_key(path: impl AsRef<Path>) -> Result<PublicKey, Error> {
+    let key = rsa::RsaPrivateKey::read_pkcs8_pem_file(path)?;
+    Ok(PublicKey::Key(key.to_public_key()))
+}
+
+impl Bot {
+    /// Connects to a block and performs the encryption handshake.
+    pub async fn connect(addr: impl ToSocketAddrs, key: PublicKey) -> Result<Self, Error> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let connection =
+            Connection::new_async(stream, PacketType::Classic, PrivateKey::None, key.clone());
+        let mut bot = Self {
+            connection,
+            key,
+            timeout: Duration::from_secs(10),
+            player_id: 0,
+            position: Default::default(),
+            received: vec![],
+        };
+        bot.expect("ServerHello", |p| {
+            matches!(p, Packet::ServerHello(_)).then_some(())
+        })
+        .await?;
+        bot.send(&Packet::EncryptionRequest(login::EncryptionRequestPacket {
+            rsa_data: new_session_key().into(),
+        }))
+        .await?;
+        bot.expect("EncryptionResponse", |p| {
+            matches!(p, Packet::EncryptionResponse(_)).then_some(())
+        })
+        .await?;
+        Ok(bot)
+    }
+    /// Sets the maximum time to wait for an expected packet.
+    pub const fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+    /// Player ID assigned by the server after login.
+    pub const fn player_id(&self) -> u32 {
+        self.player_id
+    }
+    pub const fn position(&self) -> Position {
+        self.position
+    }
+    /// Returns packets received since the last call that weren't consumed by the bot.
+    pub fn take_received(&mut self) -> Vec<Packet> {
+        std::mem::take(&mut self.received)
+    }
+    pub async fn send(&mut self, packet: &Packet) -> Result<(), Error> {
+        self.connection.write_packet_async(packet).await?;
+        Ok(())
+    }
+    /// Reads the next packet from the server, pings are answered automatically.
+    pub async fn recv(&mut self) -> Result<Packet, Error> {
+        loop {
+            let packet =
+                match tokio::time::timeout(self.timeout, self.connection.read_packet_async()).await
+                {
+                    Ok(Ok(packet)) => packet,
+                    Ok(Err(ConnectionError::Io(e)))
+                        if e.kind() == std::io::ErrorKind::ConnectionAborted =>
+                    {
+                        return Err(Error::Disconnected)
+                    }
+                    Ok(Err(e)) => return Err(e.into()),
+                    Err(_) => return Err(Error::Timeout("packet")),
+                };
+            match packet {
+                Packet::ServerPing => self.send(&Packet::ServerPong).await?,
+                packet => return Ok(packet),
+            }
+        }
+    }
+    /// Reads packets until `f` returns a value, other packets are stored (see
+    /// [`Bot::take_received`]).
+    pub async fn expect<T>(
+        &mut self,
+        name: &'static str,
+        mut f: impl FnMut(&Packet) -> Option<T>,
+    ) -> Result<T, Error> {
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        loop {
+            let packet = tokio::time::timeout_at(deadline, self.recv())
+                .await
+                .map_err(|_| Error::Timeout(name))?
+                .map_err(|e| match e {
+                    Error::Timeout(_) => Error::Timeout(name),
+                    e => e,
+                })?;
+            if let Some(value) = f(&packet) {
+                return Ok(value);
+            }
+            self.received.push(packet);
+        }
+    }
+    /// Logs in with a Sega ID. New accounts get `username` as their nickname.
+    pub async fn login(&mut self, username: &str, password: &str) -> Result<(), Error> {
+        self.send(&Packet::SegaIDLogin(login::SegaIDLoginPacket {
+            username: username.to_string().into(),
+            password: password.to_string().into(),
+            text_lang: login::Language::English,
+            ..Default::default()
+        }))
+        .await?;
+        self.connection.change_packet_type(PacketType::NA);
+        loop {
+            let packet = self
+                .expect("LoginResponse", |p| match p {
+                    Packet::LoginResponse(_) | Packet::NicknameRequest(_) => Some(p.clone()),
+                    _ => None,
+                })
+                .await?;
+            match packet {
+                Packet::NicknameRequest(_) => {
+                    self.send(&Packet::NicknameResponse(NicknameResponsePacket {
+                        nickname: username.to_string().into(),
+                    }))
+                    .await?
+                }
+                Packet::LoginResponse(response) => return self.on_login_response(response),
+                _ => unreachable!(),
+            }
+        }
+    }
+    fn on_login_response(&mut self, response: login::LoginResponsePacket) -> Result<(), Error> {
+        if response.status == LoginStatus::Failure {
+            return Err(Error::LoginFailed(response.error));
+        }
+        self.player_id = response.player.id;
+        Ok(())
+    }
+    /// Moves to another block of the ship, the bot has to be in game.
+    pub async fn switch_block(mut self, block_id: u16) -> Result<Self, Error> {
+        self.send(&Packet::BlockSwitchRequest(
+            login::BlockSwitchRequestPacket {
+                block_id,
+                ..Default::default()
+            },
+        ))
+        .await?;
+        let response = self
+            .expect("BlockSwitchResponse", |p| match p {
+                Packet::BlockSwitchResponse(r) => Some(r.clone()),
+                _ => None,
+            })
+            .await?;
+        let ip = if response.ip == Ipv4Addr::UNSPECIFIED {
+            self.connection.get_ip()?
+        } else {
+            response.ip
+        };
+        let mut bot = Self::connect((ip, response.port), self.key.clone()).await?;
+        bot.timeout = self.timeout;
+        bot.block_login(&response).await?;
+        Ok(bot)
+    }
+    /// Logs in using a block switch challenge.
+    pub async fn block_login(&mut self, switch: &BlockSwitchResponsePacket) -> Result<(), Error> {
+        self.send(&Packet::BlockLogin(login::BlockLoginPacket {
+            player_id: switch.user_id as u64,
+            challenge: switch.challenge,
+            ..Default::default()
+        }))
+        .await?;
+        self.connection.change_packet_type(PacketType::NA);
+        let response = self
+            .expect("LoginResponse", |p| match p {
+                Packet::LoginResponse(r) => Some(r.clone()),
+                _ => None,
+            })
+            .await?;
+        self.on_login_response(response)
+    }
+    pub async fn characters(&mut self) -> Result<CharacterListPacket, Error> {
+        self.send(&Packet::CharacterListRequest).await?;
+        self.expect("CharacterListResponse", |p| match p {
+            Packet::CharacterListResponse(list) => Some(list.clone()),
+            _ => None,
+        })
+        .await
+    }
+    /// Creates a new character and returns its ID.
+    pub async fn create_character(&mut self, character: Character) -> Result<u32, Error> {
+        self.send(&Packet::CharacterCreate(login::CharacterCreatePacket {
+            character,
+        }))
+        .await?;
+        let response = self
+            .expect("CharacterCreateResponse", |p| match p {
+                Packet::CharacterCreateResponse(r) => Some(r.clone()),
+                _ => None,
+            })
+            .await?;
+        match response.status {
+            login::CharacterCreationStatus::Success => Ok(response.char_id),
+            status => Err(Error::CharacterCreationFailed(status)),
+        }
+    }
+    /// Enters the game with the character and waits until the lobby is loaded.
+    pub async fn start_game(&mut self, char_id: u32) -> Result<(), Error> {
+        self.send(&Packet::StartGame(login::StartGamePacket {
+            char_id,
+            ..Default::default()
+        }))
+        .await?;
+        self.expect("LoadingScreenTransition", |p| {
+            matches!(p, Packet::LoadingScreenTransition).then_some(())
+        })
+        .await?;
+        self.send(&Packet::InitialLoad).await?;
+        self.load_map().await
+    }
+    /// Waits for the server to load a map and confirms the loading.
+    pub async fn load_map(&mut self) -> Result<(), Error> {
+        let map_object = self
+            .expect("LoadLevel", |p| match p {
+                Packet::LoadLevel(l) => Some(l.map_object),
+                _ => None,
+            })
+            .await?;
+        self.send(&Packet::MapLoaded(MapLoadedPacket {
+            map_object,
+            ..Default::default()
+        }))
+        .await?;
+        self.expect("FinishLoading", |p| {
+            matches!(p, Packet::FinishLoading).then_some(())
+        })
+        .await
+    }
+    /// Walks to the given position.
+    pub async fn move_to(&mut self, x: f32, y: f32, z: f32) -> Result<(), Error> {
+        self.position.pos_x = f16::from_f32(x);
+        self.position.pos_y = f16::from_f32(y);
+        self.position.pos_z = f16::from_f32(z);
+        let pos = self.position;
+        self.send(&Packet::Movement(MovementPacket {
+            ent1_id: Some(self.player_id as u64),
+            ent1_type: Some(ObjectType::Player as u16),
+            rot_x: Some(pos.rot_x),
+            rot_y: Some(pos.rot_y),
+            rot_z: Some(pos.rot_z),
+            rot_w: Some(pos.rot_w),
+            cur_x: Some(pos.pos_x),
+            cur_y: Some(pos.pos_y),
+            cur_z: Some(pos.pos_z),
+            ..Default::default()
+        }))
+        .await
+    }
+    pub async fn send_chat(&mut self, channel: MessageChannel, message: &str) -> Result<(), Error> {
+        self.send(&Packet::ChatMessage(ChatMessage {
+            object: self.object_header(),
+            channel,
+            message: message.to_string(),
+            ..Default::default()
+        }))
+        .await
+    }
+    /// Pings the block, the reply is sent by the connection task of the player.
+    pub async fn ping(&mut self) -> Result<(), Error> {
+        self.send(&Packet::ClientPing(Default::default())).await?;
+        self.expect("ClientPong", |p| {
+            matches!(p, Packet::ClientPong(_)).then_some(())
+        })
+        .await
+    }
+    /// Sends a chat command and returns the first system message of the reply.
+    pub async fn command(&mut self, command: &str) -> Result<String, Error> {
+        self.send_chat(MessageChannel::Map, command).await?;
+        self.expect("SystemMessage", |p| match p {
+            Packet::SystemMessage(m) => Some(m.message.clone()),
+            _ => None,
+        })
+        .await
+    }
+    /// Invites a player to the party of the bot.
+    pub async fn invite(&mut self, player_id: u32) -> Result<(), Error> {
+        self.send(&Packet::PartyInviteRequest(
+            party::PartyInviteRequestPacket {
+                invitee: ObjectHeader {
+                    id: player_id,
+                    entity_type: ObjectType::Player,
+                    ..Default::default()
+                },
+            },
+        ))
+        .await
+    }
+    /// Waits for a party invite and joins the party.
+    pub async fn accept_invite(&mut self) -> Result<(), Error> {
+        let invite = self
+            .expect("NewInvite", |p| match p {
+                Packet::NewInvite(i) => Some(i.clone()),
+                _ => None,
+            })
+            .await?;
+        self.send(&Packet::AcceptInvite(party::AcceptInvitePacket {
+            party_object: invite.party_object,
+            inviter: invite.inviter,
+        }))
+        .await?;
+        self.expect("PartyInit", |p| {
+            matches!(p, Packet::PartyInit(_)).then_some(())
+        })
+        .await
+    }
+    /// Accepts a quest for the party of the bot.
+    pub async fn accept_quest(&mut self, quest_id: u32, diff: u16) -> Result<(), Error> {
+        self.send(&Packet::AcceptQuest(questlist::AcceptQuestPacket {
+            quest_obj: ObjectHeader {
+                id: quest_id,
+                entity_type: ObjectType::Quest,
+                ..Default::default()
+            },
+            diff,
+            ..Default::default()
+        }))
+        .await?;
+        self.wait_for_quest().await
+    }
+    /// Waits until the party has a quest set.
+    pub async fn wait_for_quest(&mut self) -> Result<(), Error> {
+        self.expect("SetPartyQuest", |p| {
+            matches!(p, Packet::SetPartyQuest(_)).then_some(())
+        })
+        .await
+    }
+    /// Moves to the campship of the party quest.
+    pub async fn to_campship(&mut self) -> Result<(), Error> {
+        self.send(&Packet::ToCampship(Default::default())).await?;
+        self.load_map().await
+    }
+    /// Goes down from the campship to the quest field.
+    pub async fn campship_down(&mut self) -> Result<(), Error> {
+        self.send(&Packet::CampshipDown(Default::default())).await?;
+        self.expect("MapTransfer", |p| {
+            matches!(p, Packet::MapTransfer(_)).then_some(())
+        })
+        .await
+    }
+    pub const fn object_header(&self) -> ObjectHeader {
+        ObjectHeader {
+            id: self.player_id,
+            unk: 0,
+            entity_type: ObjectType::Player,
+            map_id: 0,
+        }
+    }
+}
+
+/// Returns a minimal character that the server accepts (casts don't need costume data).
+pub fn new_character(name: &str) -> Character {
+    let mut character = Character {
+        name: name.to_string(),
+        ..Default::default()
+    };
+    character.look.race = Race::Cast;
+    character.classes.main_class = Class::Hunter;
+    character.classes.sub_class = Class::Ranger;
+    character
+}
+
+/// Creates encryption request data for the AES session: encrypted secret followed by the key.
+fn new_session_key() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut key = [0u8; 0x20];
+    rng.fill_bytes(&mut key);
+    let iv: [u8; 0x10] = std::array::from_fn(|i| i as u8);
+    let mut data = [0u8; 0x30];
+    rng.fill_bytes(&mut data[..0x20]);
+    let aes = cbc::Encryptor::<aes::Aes256>::new(&key.into(), &iv.into());
+    aes.encrypt_padded_mut::<Pkcs7>(&mut data, 0x20)
+        .expect("buffer has space for the padding");
+    let mut out = data.to_vec();
+    out.extend_from_slice(&key);
+    out
+}
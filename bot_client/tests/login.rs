@@ -0,0 +1,152 @@
+//! Runs against a master and ship started in-process on ephemeral ports.
+
+use bot_client::{load_ship_key, new_character, Bot};
+use pso2packetlib::protocol::{chat::MessageChannel, Packet};
+use pso2ship_server::settings::{BlockSettings, ChatLogSettings, Settings};
+use std::{net::TcpListener, path::Path, sync::OnceLock, time::Duration};
+
+struct Servers {
+    addr: String,
+    key: pso2packetlib::PublicKey,
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn hostkey_fingerprint(key: &[u8]) -> String {
+    use base64::Engine;
+    use sha2::Digest;
+
+    base64::engine::general_purpose::STANDARD.encode(sha2::Sha256::digest(key))
+}
+
+/// Starts the servers once for all tests on their own thread, they run until the process exits.
+fn servers() -> &'static Servers {
+    static SERVERS: OnceLock<Servers> = OnceLock::new();
+    SERVERS.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("bot_client_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = move |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let ports = [free_port(), free_port(), free_port()];
+        let key_file = path("keypair.pem");
+
+        let (started_send, started_recv) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async {
+                let data =
+                    data_compiler::compile(&Path::new(env!("CARGO_MANIFEST_DIR")).join("../data"))
+                        .expect("failed to compile server data");
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let master_addr = listener.local_addr().unwrap();
+                let signing_key = master_ship::load_key(path("master_key.bin")).await;
+                let fingerprint = hostkey_fingerprint(&signing_key.verifying_key().to_sec1_bytes());
+                std::fs::write(
+                    path("hostkeys.toml"),
+                    format!("[[keys]]\nip = \"127.0.0.1\"\nfingerprint = \"{fingerprint}\"\n"),
+                )
+                .unwrap();
+                let sql = master_ship::sql::Sql::new(&path("master_ship.db"), true)
+                    .await
+                    .unwrap();
+                tokio::spawn(master_ship::serve_ships(
+                    listener,
+                    sql,
+                    Some(data),
+                    signing_key,
+                ));
+
+                let settings = Settings {
+                    db_name: path("ship.db"),
+                    balance_port: ports[0],
+                    blocks: vec![
+                        BlockSettings {
+                            port: Some(ports[1]),
+                            ..Default::default()
+                        },
+                        BlockSettings {
+                            port: Some(ports[2]),
+                            name: "Block 2".to_string(),
+                            ..Default::default()
+                        },
+                    ],
+                    key_file: Some(path("keypair.pem")),
+                    hostkeys_file: path("hostkeys.toml"),
+                    master_ship: Some(master_addr.to_string()),
+                    console: false,
+                    chat_log: ChatLogSettings {
+                        enabled: false,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+                let ship = tokio::spawn(pso2ship_server::run_with(settings));
+                // the blocks are listening once the ship answers
+                let addr = format!("127.0.0.1:{}", ports[1]);
+                while tokio::net::TcpStream::connect(&addr).await.is_err() {
+                    assert!(!ship.is_finished(), "ship failed to start");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                started_send.send(addr).unwrap();
+                let _ = ship.await;
+            })
+        });
+
+        let addr = started_recv
+            .recv_timeout(Duration::from_secs(60))
+            .expect("servers didn't start");
+        Servers {
+            addr,
+            key: load_ship_key(key_file).expect("failed to load the ship key"),
+        }
+    })
+}
+
+#[tokio::test]
+async fn login_and_walk() {
+    let servers = servers();
+    let name = format!("bot{}", std::process::id());
+    let mut bot = Bot::connect(&servers.addr, servers.key.clone())
+        .await
+        .unwrap();
+    bot.login(&name, "password").await.unwrap();
+    assert_ne!(bot.player_id(), 0);
+
+    let char_id = bot.create_character(new_character(&name)).await.unwrap();
+    let list = bot.characters().await.unwrap();
+    assert!(list.characters.iter().any(|c| c.character_id == char_id));
+
+    bot.start_game(char_id).await.unwrap();
+    bot.move_to(1.0, 0.0, 1.0).await.unwrap();
+    bot.send_chat(MessageChannel::Map, "!get_pos")
+        .await
+        .unwrap();
+    bot.expect("SystemMessage", |p| {
+        matches!(p, Packet::SystemMessage(_)).then_some(())
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn switch_block() {
+    let servers = servers();
+    let name = format!("bot{}s", std::process::id());
+    let mut bot = Bot::connect(&servers.addr, servers.key.clone())
+        .await
+        .unwrap();
+    bot.login(&name, "password").await.unwrap();
+    let id = bot.player_id();
+    let char_id = bot.create_character(new_character(&name)).await.unwrap();
+    bot.start_game(char_id).await.unwrap();
+
+    let mut bot = bot.switch_block(2).await.unwrap();
+    assert_eq!(bot.player_id(), id);
+    bot.start_game(char_id).await.unwrap();
+}
@@ -0,0 +1,13 @@
+-- Entry teleporter 633 sends the player to the exit teleporter 624
+add_player(1)
+add_player(2)
+
+local events = interact(1, 633, "Transfer")
+local transfer = find_packet(events, "TeleportTransfer", 1)
+assert(transfer ~= nil, "the sender should be teleported")
+assert(transfer.source_tele.id == 633)
+assert(find_packet(events, "TeleportTransfer", 2) == nil, "only the sender should be teleported")
+
+local tag = find_packet(events, "SetTag", 2)
+assert(tag ~= nil and tag.attribute == "Forwarded", "other players should see the transfer")
+assert(find_event(events, "move") == nil, "teleporters don't change the zone")
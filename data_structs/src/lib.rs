@@ -6,6 +6,7 @@ pub mod inventory;
 pub mod map;
 #[cfg(feature = "ship")]
 pub mod master_ship;
+pub mod messages;
 pub mod quest;
 pub mod stats;
 
@@ -157,6 +158,7 @@ pub struct ServerData {
     pub enemy_stats: stats::AllEnemyStats,
     pub attack_stats: Vec<stats::AttackStats>,
     pub default_classes: DefaultClassesData,
+    pub messages: messages::MessageCatalog,
 }
 
 pub fn name_to_id(name: &str) -> u32 {
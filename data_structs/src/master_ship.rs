@@ -65,6 +65,18 @@ pub enum MasterShipAction {
         id: u32,
         uuid: u64,
     },
+    /// Change the permission level of an account
+    SetPermission {
+        id: u32,
+        permission: PermissionLevel,
+    },
+    /// Ban an account until the Unix timestamp (in seconds), `None` bans permanently
+    BanUser {
+        id: u32,
+        until: Option<u64>,
+    },
+    /// Lift the ban of an account. Parameter is the player id
+    UnbanUser(u32),
     /// Create a new block login challenge. Parameter is the player id
     NewBlockChallenge(u32),
     /// Result of a new block login challenge request.
@@ -90,6 +102,17 @@ pub enum MasterShipAction {
     },
     /// Delete ship from the list. Parameter is the id of the ship
     UnregisterShip(u32),
+    /// Request a portable copy of the account. Parameter is the player id
+    ExportAccount(u32),
+    ExportAccountResult(Box<AccountExport>),
+    /// Create a new account from the exported data
+    ImportAccount(Box<AccountExport>),
+    ImportAccountResult(ImportAccountResult),
+    /// Delete an account, used to undo a failed import. Parameter is the player id
+    DeleteAccount(u32),
+    /// Request the permission level of an account. Parameter is the player id
+    GetPermission(u32),
+    GetPermissionResult(PermissionLevel),
     SetFormat(SerializerFormat),
     ServerDataRequest,
     ServerDataResponse(ServerDataResult),
@@ -140,11 +163,84 @@ pub enum UserLoginResult {
         id: u32,
         nickname: String,
         accountflags: Flags,
-        isgm: bool,
+        permission: PermissionLevel,
         last_uuid: u64,
     },
     InvalidPassword(u32),
     NotFound,
+    /// Account is banned until the Unix timestamp (in seconds), `None` if the ban is permanent.
+    Banned {
+        until: Option<u64>,
+    },
+}
+
+/// Permission level of an account, each level includes the lower ones.
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum PermissionLevel {
+    #[default]
+    Player,
+    Moderator,
+    Gm,
+    Admin,
+}
+
+impl PermissionLevel {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Player => "player",
+            Self::Moderator => "moderator",
+            Self::Gm => "gm",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+impl std::fmt::Display for PermissionLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for PermissionLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "player" => Ok(Self::Player),
+            "moderator" => Ok(Self::Moderator),
+            "gm" => Ok(Self::Gm),
+            "admin" => Ok(Self::Admin),
+            _ => Err(format!("Unknown permission level {s}")),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ImportAccountResult {
+    /// Account was created. Parameter is the new player id
+    Success(u32),
+    /// Username or PSN username is already registered
+    AlreadyExists,
+}
+
+/// Master ship side of the account, used for moving players between servers.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AccountExport {
+    pub username: String,
+    /// Argon2 hash of the password in the PHC string format
+    pub password_hash: String,
+    pub psn_username: String,
+    pub nickname: String,
+    pub settings: String,
+    pub storage: AccountStorages,
+    pub info: UserInfoPacket,
+    pub flags: Flags,
+    pub permission: PermissionLevel,
+    pub last_uuid: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -388,10 +484,10 @@ impl ShipConnection {
             .map_err(|_| Error::HKDFError)?;
         Ok(output)
     }
-    pub fn set_format(&mut self, format: SerializerFormat) {
+    pub const fn set_format(&mut self, format: SerializerFormat) {
         self.format = format;
     }
-    pub fn set_deferred_fmt(&mut self, format: SerializerFormat) {
+    pub const fn set_deferred_fmt(&mut self, format: SerializerFormat) {
         self.deferred_fmt = Some(format);
     }
 }
@@ -445,6 +541,19 @@ impl std::fmt::Debug for UserCreds {
     }
 }
 
+impl std::fmt::Debug for AccountExport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccountExport")
+            .field("username", &self.username)
+            .field("password_hash", &"[REDACTED]")
+            .field("psn_username", &self.psn_username)
+            .field("nickname", &self.nickname)
+            .field("permission", &self.permission)
+            .field("last_uuid", &self.last_uuid)
+            .finish_non_exhaustive()
+    }
+}
+
 impl std::fmt::Debug for ServerDataResult {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut print = f.debug_tuple("ServerDataResult");
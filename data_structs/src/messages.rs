@@ -0,0 +1,97 @@
+use pso2packetlib::protocol::login::Language;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt::Display, fmt::Write};
+
+/// Message with a text for each game language.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LocalizedMessage {
+    pub en: String,
+    pub jp: String,
+}
+
+impl LocalizedMessage {
+    /// Returns the text in the requested language, falling back to the other one if empty.
+    pub fn get(&self, lang: Language) -> &str {
+        let (first, second) = match lang {
+            Language::English => (&self.en, &self.jp),
+            Language::Japanese => (&self.jp, &self.en),
+        };
+        if first.is_empty() {
+            second
+        } else {
+            first
+        }
+    }
+}
+
+/// Server messages keyed by message ID.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MessageCatalog {
+    messages: HashMap<String, LocalizedMessage>,
+}
+
+impl MessageCatalog {
+    pub fn get(&self, id: &str) -> Option<&LocalizedMessage> {
+        self.messages.get(id)
+    }
+    /// Returns the message in the requested language with `{name}` placeholders replaced by the
+    /// arguments. Unknown IDs are returned as is, so that missing entries are visible in game.
+    pub fn format(
+        &self,
+        id: &str,
+        lang: Language,
+        args: &[(&str, &(dyn Display + Sync))],
+    ) -> String {
+        let Some(message) = self.messages.get(id) else {
+            return id.to_string();
+        };
+        let mut text = message.get(lang);
+        let mut out = String::with_capacity(text.len());
+        while let Some(start) = text.find('{') {
+            out.push_str(&text[..start]);
+            text = &text[start..];
+            let arg = text.find('}').and_then(|end| {
+                let name = &text[1..end];
+                args.iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, value)| (end, value))
+            });
+            match arg {
+                Some((end, value)) => {
+                    let _ = write!(out, "{value}");
+                    text = &text[end + 1..];
+                }
+                None => {
+                    out.push('{');
+                    text = &text[1..];
+                }
+            }
+        }
+        out.push_str(text);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LocalizedMessage, MessageCatalog};
+    use pso2packetlib::protocol::login::Language;
+
+    #[test]
+    fn test_format() {
+        let mut catalog = MessageCatalog::default();
+        catalog.messages.insert(
+            "capture".into(),
+            LocalizedMessage {
+                en: "Capture of player {player} enabled {unknown}".into(),
+                jp: String::new(),
+            },
+        );
+        let msg = catalog.format("capture", Language::Japanese, &[("player", &10)]);
+        assert_eq!(msg, "Capture of player 10 enabled {unknown}");
+        let msg = catalog.format("missing", Language::English, &[]);
+        assert_eq!(msg, "missing");
+    }
+}
@@ -137,6 +137,31 @@ pub struct AttackStats {
     pub attack_type: AttackType,
     pub defense_type: AttackType,
     pub damage: DamageType,
+    /// Weapon class that can use this attack, `None` if any weapon can.
+    pub weapon: Option<WeaponClass>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WeaponClass {
+    Unarmed,
+    Sword,
+    WiredLance,
+    Partisan,
+    TwinDagger,
+    DoubleSaber,
+    Knuckle,
+    Gunslash,
+    Rifle,
+    Launcher,
+    TwinMachineGun,
+    Rod,
+    Talis,
+    Wand,
+    Katana,
+    CompoundBow,
+    JetBoots,
+    DualBlade,
+    Takt,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -159,6 +184,59 @@ pub enum DamageType {
     PA((u32, f32)),
 }
 
+impl WeaponClass {
+    /// Weapon class of an item category, `None` if the category isn't a weapon.
+    pub const fn from_category(category: u16) -> Option<Self> {
+        Some(match category {
+            1 => Self::Sword,
+            2 => Self::WiredLance,
+            3 => Self::Partisan,
+            4 => Self::TwinDagger,
+            5 => Self::DoubleSaber,
+            6 => Self::Knuckle,
+            7 => Self::Gunslash,
+            8 => Self::Rifle,
+            9 => Self::Launcher,
+            10 => Self::TwinMachineGun,
+            11 => Self::Rod,
+            12 => Self::Talis,
+            13 => Self::Wand,
+            14 => Self::Katana,
+            15 => Self::CompoundBow,
+            16 => Self::JetBoots,
+            17 => Self::DualBlade,
+            18 => Self::Takt,
+            _ => return None,
+        })
+    }
+    /// Weapon class from the prefix of an attack name (e.g. `Rifle:A1`).
+    pub fn from_attack_name(name: &str) -> Option<Self> {
+        let (prefix, _) = name.split_once(':')?;
+        Some(match prefix {
+            "Unarmed" => Self::Unarmed,
+            "Sword" => Self::Sword,
+            "WiredLance" => Self::WiredLance,
+            "Partisan" => Self::Partisan,
+            "TwinDagger" => Self::TwinDagger,
+            "DoubleSaber" => Self::DoubleSaber,
+            "Knuckle" => Self::Knuckle,
+            "Gunslash" => Self::Gunslash,
+            "Rifle" => Self::Rifle,
+            "Launcher" => Self::Launcher,
+            "TwinMachineGun" => Self::TwinMachineGun,
+            "Rod" => Self::Rod,
+            "Talis" => Self::Talis,
+            "Wand" => Self::Wand,
+            "Katana" => Self::Katana,
+            "CompoundBow" => Self::CompoundBow,
+            "JetBoots" => Self::JetBoots,
+            "DualBlade" => Self::DualBlade,
+            "Takt" => Self::Takt,
+            _ => return None,
+        })
+    }
+}
+
 impl Default for DamageTypeReadable {
     fn default() -> Self {
         Self::Generic { mul: 1.0 }
@@ -23,6 +23,7 @@ use serde::{Deserialize, Serialize};
 use std::{
     io,
     net::{IpAddr, Ipv4Addr},
+    path::Path,
     sync::{atomic::AtomicBool, Arc},
     time::Duration,
 };
@@ -40,6 +41,10 @@ struct Settings {
     file_log_level: log::LevelFilter,
     console_log_level: log::LevelFilter,
     data_path: Option<String>,
+    /// Login attempts older than this many days are deleted. `None` keeps the whole history
+    login_retention_days: Option<u64>,
+    /// How often the login history is pruned (in minutes)
+    login_prune_interval: u64,
 }
 
 #[derive(Parser, Debug)]
@@ -69,6 +74,9 @@ struct Args {
     /// Location of complied server data file
     #[arg(short, long)]
     data_path: Option<String>,
+    /// Number of days to keep the login history for
+    #[arg(long)]
+    login_retention_days: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -115,6 +123,7 @@ impl Settings {
         args_to_settings!(args.file_log_level => settings.file_log_level);
         args_to_settings!(args.console_log_level => settings.console_log_level);
         settings.data_path = args.data_path.or(settings.data_path);
+        settings.login_retention_days = args.login_retention_days.or(settings.login_retention_days);
         Ok(settings)
     }
 }
@@ -128,6 +137,8 @@ impl Default for Settings {
             file_log_level: log::LevelFilter::Info,
             console_log_level: log::LevelFilter::Debug,
             data_path: None,
+            login_retention_days: None,
+            login_prune_interval: 60,
         }
     }
 }
@@ -227,32 +238,76 @@ pub async fn run() -> Result<(), Error> {
         ships: servers,
         srv_data: server_data,
     });
+    let listener = TcpListener::bind(("0.0.0.0", 15000)).await?;
+    log::info!("Loading signing key...");
+    let signing_key = load_key("master_key.bin").await;
     start_discovery_loop(15000).await?;
+    if let Some(days) = settings.login_retention_days {
+        tokio::spawn(login_pruner(
+            ms_data.clone(),
+            Duration::from_secs(days * 24 * 3600),
+            Duration::from_secs(settings.login_prune_interval.max(1) * 60),
+        ));
+    }
     tokio::spawn(make_keys(ms_data.clone()));
     make_query(ms_data.clone()).await?;
     make_block_balance(ms_data.clone()).await?;
-    ship_receiver(ms_data).await?;
+    ship_receiver(listener, ms_data, signing_key).await?;
 
     Ok(())
 }
 
+/// Accepts ships on `listener` without the client facing listeners, discovery or logging setup.
+/// Used to run the master ship in-process, e.g. in tests.
+pub async fn serve_ships(
+    listener: TcpListener,
+    sql: sql::Sql,
+    srv_data: Option<ServerData>,
+    signing_key: SigningKey,
+) -> Result<(), Error> {
+    let ms_data = Arc::new(MSData {
+        sql,
+        ships: RwLock::new(vec![]),
+        srv_data,
+    });
+    ship_receiver(listener, ms_data, signing_key).await
+}
+
 pub async fn ctrl_c_handler() {
     tokio::signal::ctrl_c().await.expect("failed to listen");
     log::info!("Shutting down...");
     IS_RUNNING.swap(false, std::sync::atomic::Ordering::Relaxed);
 }
 
-pub async fn load_key() -> SigningKey {
-    let mut data = tokio::fs::read("master_key.bin").await.unwrap_or_default();
+async fn login_pruner(ms_data: Arc<MSData>, max_age: Duration, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        if !IS_RUNNING.load(std::sync::atomic::Ordering::Relaxed) {
+            return;
+        }
+        match ms_data.sql.prune_logins(max_age).await {
+            Ok(0) => {}
+            Ok(count) => log::info!("Pruned {count} old login attempts"),
+            Err(e) => log::warn!("Failed to prune login history: {e}"),
+        }
+    }
+}
+
+/// Loads the signing key from `path`, creating it if it doesn't exist.
+pub async fn load_key(path: impl AsRef<Path>) -> SigningKey {
+    let path = path.as_ref();
+    let mut data = tokio::fs::read(path).await.unwrap_or_default();
     data.resize_with(32, || OsRng.next_u32() as u8);
-    let _ = tokio::fs::write("master_key.bin", &data).await;
+    let _ = tokio::fs::write(path, &data).await;
     SigningKey::from_slice(&data).unwrap()
 }
 
-async fn ship_receiver(ms_data: Arc<MSData>) -> Result<(), Error> {
-    let listener = TcpListener::bind(("0.0.0.0", 15000)).await?;
-    log::info!("Loading signing key...");
-    let signing_key = load_key().await;
+async fn ship_receiver(
+    listener: TcpListener,
+    ms_data: Arc<MSData>,
+    signing_key: SigningKey,
+) -> Result<(), Error> {
     // this is 65 bytes
     let hostkey = signing_key.verifying_key().to_sec1_bytes().to_vec();
     log::info!("Started master server");
@@ -387,15 +442,7 @@ async fn run_action(ms_data: &MSData, action: MasterShipComm) -> Result<MasterSh
                 .get_sega_user(&data.username, &data.password, data.ip)
                 .await
             {
-                Ok(d) => {
-                    response.action = MasterShipAction::UserLoginResult(UserLoginResult::Success {
-                        id: d.id,
-                        nickname: d.nickname,
-                        accountflags: d.account_flags,
-                        isgm: d.isgm,
-                        last_uuid: d.last_uuid,
-                    })
-                }
+                Ok(d) => response.action = MasterShipAction::UserLoginResult(login_result(d)),
                 Err(ref e) if matches!(e, Error::NoUser) => {
                     response.action = MasterShipAction::UserLoginResult(UserLoginResult::NotFound)
                 }
@@ -408,29 +455,13 @@ async fn run_action(ms_data: &MSData, action: MasterShipComm) -> Result<MasterSh
         }
         MasterShipAction::UserRegister(data) => {
             match sql.create_sega_user(&data.username, &data.password).await {
-                Ok(d) => {
-                    response.action = MasterShipAction::UserLoginResult(UserLoginResult::Success {
-                        id: d.id,
-                        nickname: d.nickname,
-                        accountflags: d.account_flags,
-                        isgm: d.isgm,
-                        last_uuid: d.last_uuid,
-                    })
-                }
+                Ok(d) => response.action = MasterShipAction::UserLoginResult(login_result(d)),
                 Err(e) => response.action = MasterShipAction::Error(e.to_string()),
             }
         }
         MasterShipAction::UserLoginVita(data) => {
             match sql.get_psn_user(&data.username, data.ip).await {
-                Ok(d) => {
-                    response.action = MasterShipAction::UserLoginResult(UserLoginResult::Success {
-                        id: d.id,
-                        nickname: d.nickname,
-                        accountflags: d.account_flags,
-                        isgm: d.isgm,
-                        last_uuid: d.last_uuid,
-                    })
-                }
+                Ok(d) => response.action = MasterShipAction::UserLoginResult(login_result(d)),
                 Err(ref e) if matches!(e, Error::NoUser) => {
                     response.action = MasterShipAction::UserLoginResult(UserLoginResult::NotFound)
                 }
@@ -439,15 +470,7 @@ async fn run_action(ms_data: &MSData, action: MasterShipComm) -> Result<MasterSh
         }
         MasterShipAction::UserRegisterVita(data) => {
             match sql.create_psn_user(&data.username).await {
-                Ok(d) => {
-                    response.action = MasterShipAction::UserLoginResult(UserLoginResult::Success {
-                        id: d.id,
-                        nickname: d.nickname,
-                        accountflags: d.account_flags,
-                        isgm: d.isgm,
-                        last_uuid: d.last_uuid,
-                    })
-                }
+                Ok(d) => response.action = MasterShipAction::UserLoginResult(login_result(d)),
                 Err(e) => response.action = MasterShipAction::Error(e.to_string()),
             }
         }
@@ -488,15 +511,7 @@ async fn run_action(ms_data: &MSData, action: MasterShipComm) -> Result<MasterSh
             challenge,
             player_id,
         } => match sql.login_challenge(player_id, challenge).await {
-            Ok(d) => {
-                response.action = MasterShipAction::UserLoginResult(UserLoginResult::Success {
-                    id: d.id,
-                    nickname: d.nickname,
-                    accountflags: d.account_flags,
-                    isgm: d.isgm,
-                    last_uuid: d.last_uuid,
-                })
-            }
+            Ok(d) => response.action = MasterShipAction::UserLoginResult(login_result(d)),
             Err(ref e) if matches!(e, Error::NoUser) => {
                 response.action = MasterShipAction::UserLoginResult(UserLoginResult::NotFound)
             }
@@ -521,6 +536,22 @@ async fn run_action(ms_data: &MSData, action: MasterShipComm) -> Result<MasterSh
             Ok(_) => response.action = MasterShipAction::Ok,
             Err(e) => response.action = MasterShipAction::Error(e.to_string()),
         },
+        MasterShipAction::SetPermission { id, permission } => {
+            match sql.set_permission(id, permission).await {
+                Ok(_) => response.action = MasterShipAction::Ok,
+                Err(e) => response.action = MasterShipAction::Error(e.to_string()),
+            }
+        }
+        MasterShipAction::BanUser { id, until } => {
+            match sql.set_ban(id, Some(until.unwrap_or(u64::MAX))).await {
+                Ok(_) => response.action = MasterShipAction::Ok,
+                Err(e) => response.action = MasterShipAction::Error(e.to_string()),
+            }
+        }
+        MasterShipAction::UnbanUser(id) => match sql.set_ban(id, None).await {
+            Ok(_) => response.action = MasterShipAction::Ok,
+            Err(e) => response.action = MasterShipAction::Error(e.to_string()),
+        },
         MasterShipAction::ShipLogin { .. } => {
             response.action = MasterShipAction::Error(Error::InvalidAction.to_string())
         }
@@ -554,10 +585,49 @@ async fn run_action(ms_data: &MSData, action: MasterShipComm) -> Result<MasterSh
             }
         }
         MasterShipAction::ServerDataResponse(_) => {}
+        MasterShipAction::ExportAccount(id) => match sql.export_user(id).await {
+            Ok(d) => response.action = MasterShipAction::ExportAccountResult(Box::new(d)),
+            Err(e) => response.action = MasterShipAction::Error(e.to_string()),
+        },
+        MasterShipAction::ExportAccountResult(_) => {}
+        MasterShipAction::ImportAccount(data) => match sql.import_user(*data).await {
+            Ok(result) => response.action = MasterShipAction::ImportAccountResult(result),
+            Err(e) => response.action = MasterShipAction::Error(e.to_string()),
+        },
+        MasterShipAction::ImportAccountResult(_) => {}
+        MasterShipAction::DeleteAccount(id) => match sql.delete_user(id).await {
+            Ok(_) => response.action = MasterShipAction::Ok,
+            Err(e) => response.action = MasterShipAction::Error(e.to_string()),
+        },
+        MasterShipAction::GetPermission(id) => match sql.get_permission(id).await {
+            Ok(p) => response.action = MasterShipAction::GetPermissionResult(p),
+            Err(e) => response.action = MasterShipAction::Error(e.to_string()),
+        },
+        MasterShipAction::GetPermissionResult(_) => {}
     }
     Ok(response)
 }
 
+/// Login result of an existing account, banned accounts can't log in.
+fn login_result(user: sql::User) -> UserLoginResult {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    match user.banned_until {
+        Some(until) if until > now => UserLoginResult::Banned {
+            until: (until != u64::MAX).then_some(until),
+        },
+        _ => UserLoginResult::Success {
+            id: user.id,
+            nickname: user.nickname,
+            accountflags: user.account_flags,
+            permission: user.permission,
+            last_uuid: user.last_uuid,
+        },
+    }
+}
+
 async fn make_keys(servers: Arc<MSData>) -> io::Result<()> {
     let listener = TcpListener::bind(("0.0.0.0", 11000)).await?;
     loop {
@@ -738,7 +808,7 @@ async fn send_keys(mut stream: TcpStream, servers: Arc<MSData>) -> Result<(), Er
     Ok(())
 }
 
-async fn async_write<T>(mutex: &RwLock<T>) -> RwLockWriteGuard<T>
+async fn async_write<T>(mutex: &RwLock<T>) -> RwLockWriteGuard<'_, T>
 where
     T: Send + Sync,
 {
@@ -1,6 +1,10 @@
 use crate::Error;
 use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
-use data_structs::{flags::Flags, inventory::AccountStorages};
+use data_structs::{
+    flags::Flags,
+    inventory::AccountStorages,
+    master_ship::{AccountExport, ImportAccountResult, PermissionLevel},
+};
 use pso2packetlib::{
     protocol::login::{LoginAttempt, LoginResult, UserInfoPacket},
     AsciiString,
@@ -25,8 +29,10 @@ pub struct User {
 
     pub nickname: String,
     pub account_flags: Flags,
-    pub isgm: bool,
+    pub permission: PermissionLevel,
     pub last_uuid: u64,
+    /// Unix timestamp (in seconds) until the account is banned
+    pub banned_until: Option<u64>,
 }
 
 #[derive(Default, serde::Serialize, serde::Deserialize)]
@@ -37,8 +43,21 @@ struct UserData {
     storage: AccountStorages,
     info: UserInfoPacket,
     flags: Flags,
+    /// Old GM flag, such accounts are treated as GMs
     isgm: bool,
     last_uuid: u64,
+    permission: PermissionLevel,
+    banned_until: Option<u64>,
+}
+
+impl UserData {
+    fn permission(&self) -> PermissionLevel {
+        if self.isgm {
+            self.permission.max(PermissionLevel::Gm)
+        } else {
+            self.permission
+        }
+    }
 }
 
 impl Sql {
@@ -146,10 +165,11 @@ impl Sql {
                 let user_data: UserData = rmp_serde::from_slice(data.try_get("Data")?)?;
                 Ok(User {
                     id,
+                    permission: user_data.permission(),
                     nickname: user_data.nickname,
                     account_flags: user_data.flags,
-                    isgm: user_data.isgm,
                     last_uuid: user_data.last_uuid,
+                    banned_until: user_data.banned_until,
                 })
             }
             None => Err(Error::NoUser),
@@ -231,10 +251,11 @@ impl Sql {
             let user_data: UserData = rmp_serde::from_slice(row.try_get("Data")?)?;
             return Ok(User {
                 id: user_id,
+                permission: user_data.permission(),
                 nickname: user_data.nickname,
                 account_flags: user_data.flags,
-                isgm: user_data.isgm,
                 last_uuid: user_data.last_uuid,
+                banned_until: user_data.banned_until,
             });
         }
         Err(Error::NoUser)
@@ -254,10 +275,11 @@ impl Sql {
                 self.put_login(id, ip, LoginResult::Successful).await?;
                 Ok(User {
                     id,
+                    permission: user_data.permission(),
                     nickname: user_data.nickname,
                     account_flags: user_data.flags,
-                    isgm: user_data.isgm,
                     last_uuid: user_data.last_uuid,
+                    banned_until: user_data.banned_until,
                 })
             }
             None => Err(Error::NoUser),
@@ -284,10 +306,11 @@ impl Sql {
 
         Ok(User {
             id,
+            permission: user_data.permission(),
             nickname: user_data.nickname,
             account_flags: user_data.flags,
-            isgm: user_data.isgm,
             last_uuid: user_data.last_uuid,
+            banned_until: user_data.banned_until,
         })
     }
     pub async fn create_sega_user(&self, username: &str, password: &str) -> Result<User, Error> {
@@ -325,10 +348,11 @@ impl Sql {
 
         Ok(User {
             id,
+            permission: user_data.permission(),
             nickname: user_data.nickname,
             account_flags: user_data.flags,
-            isgm: user_data.isgm,
             last_uuid: user_data.last_uuid,
+            banned_until: user_data.banned_until,
         })
     }
     pub async fn get_logins(&self, id: u32) -> Result<Vec<LoginAttempt>, Error> {
@@ -348,6 +372,19 @@ impl Sql {
         }
         Ok(attempts)
     }
+    /// Deletes login attempts older than `max_age`. Returns the number of deleted entries.
+    pub async fn prune_logins(&self, max_age: Duration) -> Result<u64, Error> {
+        let until = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .saturating_sub(max_age)
+            .as_secs();
+        let result = sqlx::query("delete from Logins where Timestamp <= ?")
+            .bind(until as i64)
+            .execute(&self.connection)
+            .await?;
+        Ok(result.rows_affected())
+    }
     async fn put_login(&self, id: u32, ip: Ipv4Addr, status: LoginResult) -> Result<(), Error> {
         let timestamp_int = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -396,6 +433,45 @@ impl Sql {
         self.update_userdata(user_id, |user_data| user_data.last_uuid = uuid)
             .await
     }
+    pub async fn get_permission(&self, user_id: u32) -> Result<PermissionLevel, Error> {
+        let row = sqlx::query("select Data from Users where Id = ?")
+            .bind(user_id as i64)
+            .fetch_one(&self.connection)
+            .await?;
+        let user_data: UserData = rmp_serde::from_slice(row.try_get("Data")?)?;
+        Ok(user_data.permission())
+    }
+    pub async fn set_permission(
+        &self,
+        user_id: u32,
+        permission: PermissionLevel,
+    ) -> Result<(), Error> {
+        self.update_userdata(user_id, |user_data| {
+            user_data.isgm = false;
+            user_data.permission = permission;
+        })
+        .await
+    }
+    /// Bans the account until the Unix timestamp, `None` lifts the ban.
+    pub async fn set_ban(&self, user_id: u32, until: Option<u64>) -> Result<(), Error> {
+        self.update_userdata(user_id, |user_data| user_data.banned_until = until)
+            .await
+    }
+    pub async fn delete_user(&self, user_id: u32) -> Result<(), Error> {
+        let mut transaction = self.connection.begin().await?;
+        for table in ["Logins", "Challenges"] {
+            sqlx::query(&format!("delete from {table} where UserId = ?"))
+                .bind(user_id as i64)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        sqlx::query("delete from Users where Id = ?")
+            .bind(user_id as i64)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
 
     pub async fn get_ship_data(&self, psk: &[u8]) -> Result<bool, Error> {
         let count = sqlx::query("select count(*) from Ships where PSK = ?")
@@ -432,6 +508,83 @@ impl Sql {
         Ok(true)
     }
 
+    pub async fn export_user(&self, user_id: u32) -> Result<AccountExport, Error> {
+        let Some(row) = sqlx::query("select * from Users where Id = ?")
+            .bind(user_id as i64)
+            .fetch_optional(&self.connection)
+            .await?
+        else {
+            return Err(Error::NoUser);
+        };
+        let user_data: UserData = rmp_serde::from_slice(row.try_get("Data")?)?;
+        Ok(AccountExport {
+            username: from_utf8(row.try_get("Username")?)?.to_string(),
+            password_hash: from_utf8(row.try_get("Password")?)?.to_string(),
+            psn_username: from_utf8(row.try_get("PSNUsername")?)?.to_string(),
+            permission: user_data.permission(),
+            nickname: user_data.nickname,
+            settings: user_data.settings,
+            storage: user_data.storage,
+            info: user_data.info,
+            flags: user_data.flags,
+            last_uuid: user_data.last_uuid,
+        })
+    }
+    pub async fn import_user(&self, data: AccountExport) -> Result<ImportAccountResult, Error> {
+        let mut transaction = self.connection.begin().await?;
+        for (column, name) in [
+            ("Username", &data.username),
+            ("PSNUsername", &data.psn_username),
+        ] {
+            if name.is_empty() {
+                continue;
+            }
+            let count = sqlx::query(&format!("select count(*) from Users where {column} = ?"))
+                .bind(name.as_bytes())
+                .fetch_one(&mut *transaction)
+                .await?
+                .try_get::<i64, _>(0)?;
+            if count != 0 {
+                return Ok(ImportAccountResult::AlreadyExists);
+            }
+        }
+        let mut user_data = UserData {
+            nickname: data.nickname,
+            settings: data.settings,
+            storage: data.storage,
+            info: data.info,
+            flags: data.flags,
+            isgm: false,
+            last_uuid: data.last_uuid,
+            permission: data.permission,
+            banned_until: None,
+        };
+        // nickname will be requested again on the next login
+        let rows = sqlx::query("select Data from Users")
+            .fetch_all(&mut *transaction)
+            .await?;
+        for row in rows {
+            let other_data: UserData = rmp_serde::from_slice(row.try_get("Data")?)?;
+            if !user_data.nickname.is_empty() && other_data.nickname == user_data.nickname {
+                user_data.nickname.clear();
+                break;
+            }
+        }
+        let id = sqlx::query(
+            "insert into Users (Username, Password, PSNUsername, Data) values (?, ?, ?, ?) 
+            returning Id",
+        )
+        .bind(data.username.as_bytes())
+        .bind(data.password_hash.as_bytes())
+        .bind(data.psn_username.as_bytes())
+        .bind(rmp_serde::to_vec(&user_data)?)
+        .fetch_one(&mut *transaction)
+        .await?
+        .try_get::<i64, _>("Id")? as u32;
+        transaction.commit().await?;
+        Ok(ImportAccountResult::Success(id))
+    }
+
     async fn update_userdata<F>(&self, user_id: u32, f: F) -> Result<(), Error>
     where
         F: FnOnce(&mut UserData) + Send,
@@ -456,7 +609,10 @@ impl Sql {
 #[cfg(test)]
 mod tests {
     use crate::sql::Sql;
-    use data_structs::flags::Flags;
+    use data_structs::{
+        flags::Flags,
+        master_ship::{ImportAccountResult, PermissionLevel},
+    };
     use pso2packetlib::{
         protocol::{
             login::{LoginResult, UserInfoPacket},
@@ -510,6 +666,37 @@ mod tests {
             .expect("Failed to insert uuid");
         created_user.last_uuid = 199;
 
+        // old GM accounts are at least GMs until the level is set
+        db.update_userdata(created_user.id, |user_data| user_data.isgm = true)
+            .await
+            .expect("Failed to set GM flag");
+        let gm_user = db
+            .get_sega_user(segaid, pass, Ipv4Addr::UNSPECIFIED)
+            .await
+            .expect("SEGAID user login failed");
+        assert_eq!(gm_user.permission, PermissionLevel::Gm);
+        db.set_permission(created_user.id, PermissionLevel::Moderator)
+            .await
+            .expect("Failed to set permission");
+        created_user.permission = PermissionLevel::Moderator;
+        let permission = db
+            .get_permission(created_user.id)
+            .await
+            .expect("Failed to get permission");
+        assert_eq!(permission, PermissionLevel::Moderator);
+
+        db.set_ban(created_user.id, Some(u64::MAX))
+            .await
+            .expect("Failed to ban user");
+        let banned_user = db
+            .get_sega_user(segaid, pass, Ipv4Addr::UNSPECIFIED)
+            .await
+            .expect("SEGAID user login failed");
+        assert_eq!(banned_user.banned_until, Some(u64::MAX));
+        db.set_ban(created_user.id, None)
+            .await
+            .expect("Failed to unban user");
+
         let challenge = db
             .new_challenge(created_user.id)
             .await
@@ -543,6 +730,14 @@ mod tests {
         let login = &logins[0];
         assert_eq!(login.ip, Ipv4Addr::UNSPECIFIED);
         assert_eq!(login.status, LoginResult::Successful);
+        db.prune_logins(Duration::from_secs(3600))
+            .await
+            .expect("Failed to prune logins");
+        assert!(!db.get_logins(created_user.id).await.unwrap().is_empty());
+        db.prune_logins(Duration::ZERO)
+            .await
+            .expect("Failed to prune logins");
+        assert!(db.get_logins(created_user.id).await.unwrap().is_empty());
 
         let settings = AsciiString::from("a");
         db.save_settings(created_user.id, &settings)
@@ -554,6 +749,33 @@ mod tests {
             .expect("Failed to read settings");
         assert_eq!(read_settings, settings);
 
+        let export = db
+            .export_user(created_user.id)
+            .await
+            .expect("Failed to export user");
+        assert_eq!(export.username, segaid);
+        assert!(matches!(
+            db.import_user(export.clone()).await,
+            Ok(ImportAccountResult::AlreadyExists)
+        ));
+
+        let _ = std::fs::remove_file("test_import.db");
+        let import_db = Sql::new("sqlite:test_import.db", false)
+            .await
+            .expect("DB creation failed");
+        let Ok(ImportAccountResult::Success(new_id)) = import_db.import_user(export).await else {
+            panic!("User import failed");
+        };
+        let imported_user = import_db
+            .get_sega_user(segaid, pass, Ipv4Addr::UNSPECIFIED)
+            .await
+            .expect("Imported user login failed");
+        assert_eq!(imported_user.id, new_id);
+        assert_eq!(imported_user.account_flags, created_user.account_flags);
+        assert_eq!(imported_user.last_uuid, created_user.last_uuid);
+        assert_eq!(imported_user.permission, created_user.permission);
+
         let _ = std::fs::remove_file("test.db");
+        let _ = std::fs::remove_file("test_import.db");
     }
 }
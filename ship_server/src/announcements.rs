@@ -0,0 +1,194 @@
+use crate::{mutex::RwLock, BlockData, Error};
+pub use data_structs::messages::LocalizedMessage;
+use pso2packetlib::protocol::unk19::MessageType;
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use time::OffsetDateTime;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnnouncementSettings {
+    /// Cron-like schedule: "minute hour day-of-month month day-of-week" (UTC)
+    pub schedule: String,
+    /// Target block ID, every block if empty
+    pub block: Option<u32>,
+    #[serde(default)]
+    pub msg_type: MessageType,
+    pub message: LocalizedMessage,
+}
+
+struct ScheduledAnnouncement {
+    schedule: Schedule,
+    block: Option<u32>,
+    msg_type: MessageType,
+    message: LocalizedMessage,
+}
+
+/// Parsed cron expression, every field is a bitmask of the allowed values.
+#[derive(Debug, PartialEq)]
+struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    fn parse(expr: &str) -> Result<Self, Error> {
+        let error = || Error::InvalidSchedule(expr.to_string());
+        let fields: Vec<_> = expr.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(error());
+        };
+        let weekdays = parse_field(weekdays, 0, 7).ok_or_else(error)?;
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59).ok_or_else(error)?,
+            hours: parse_field(hours, 0, 23).ok_or_else(error)?,
+            days: parse_field(days, 1, 31).ok_or_else(error)?,
+            months: parse_field(months, 1, 12).ok_or_else(error)?,
+            // both 0 and 7 are sundays
+            weekdays: (weekdays | weekdays >> 7) & 0x7F,
+            any_day: days == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+    fn matches(&self, time: OffsetDateTime) -> bool {
+        let has = |mask: u64, value: u8| mask & (1 << value) != 0;
+        let day = has(self.days, time.day());
+        let weekday = has(self.weekdays, time.weekday().number_days_from_sunday());
+        // same as cron: if both day fields are restricted, either of them can match
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        has(self.minutes, time.minute())
+            && has(self.hours, time.hour())
+            && has(self.months, time.month() as u8)
+            && day_matches
+    }
+}
+
+/// Parses a comma separated list of `*`, `a`, `a-b` with an optional `/step`.
+fn parse_field(field: &str, min: u8, max: u8) -> Option<u64> {
+    let mut mask = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u8>().ok().filter(|&s| s != 0)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+                None => {
+                    let value = range.parse().ok()?;
+                    (value, if step > 1 { max } else { value })
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            return None;
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Some(mask)
+}
+
+/// Sends a message to every player in the selected block (or in all blocks) in their language.
+pub async fn announce(
+    blocks: &RwLock<Vec<Arc<BlockData>>>,
+    block_id: Option<u32>,
+    msg_type: MessageType,
+    message: &LocalizedMessage,
+) {
+    let blocks = blocks.read().await.clone();
+    for block in blocks
+        .iter()
+        .filter(|b| block_id.is_none_or(|id| id == b.block_id))
+    {
+        let clients: Vec<_> = block
+            .clients
+            .lock()
+            .await
+            .iter()
+            .map(|(_, c)| c.clone())
+            .collect();
+        for client in clients {
+            let mut client = client.lock().await;
+            let msg = message.get(client.user_data.lang);
+            let _ = client.send_message(msg, msg_type).await;
+        }
+    }
+}
+
+/// Starts the task that sends scheduled announcements.
+pub fn spawn_scheduler(
+    settings: Vec<AnnouncementSettings>,
+    blocks: Arc<RwLock<Vec<Arc<BlockData>>>>,
+) -> Result<(), Error> {
+    let announcements = settings
+        .into_iter()
+        .map(|a| {
+            Ok(ScheduledAnnouncement {
+                schedule: Schedule::parse(&a.schedule)?,
+                block: a.block,
+                msg_type: a.msg_type,
+                message: a.message,
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    if announcements.is_empty() {
+        return Ok(());
+    }
+    tokio::spawn(async move {
+        loop {
+            // wake up at the start of every minute
+            let now = OffsetDateTime::now_utc();
+            let delay = 60 - now.second() as u64;
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+            let now = OffsetDateTime::now_utc();
+            for announcement in announcements.iter().filter(|a| a.schedule.matches(now)) {
+                log::debug!(
+                    "Sending scheduled announcement: {}",
+                    announcement.message.en
+                );
+                announce(
+                    &blocks,
+                    announcement.block,
+                    announcement.msg_type,
+                    &announcement.message,
+                )
+                .await;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Schedule;
+    use time::macros::datetime;
+
+    #[test]
+    fn test_schedule() {
+        let schedule = Schedule::parse("*/15 8-10,20 * * *").unwrap();
+        assert!(schedule.matches(datetime!(2024-05-01 08:45 UTC)));
+        assert!(schedule.matches(datetime!(2024-05-01 20:00 UTC)));
+        assert!(!schedule.matches(datetime!(2024-05-01 08:50 UTC)));
+        assert!(!schedule.matches(datetime!(2024-05-01 11:00 UTC)));
+
+        // 2024-05-05 is a sunday
+        let schedule = Schedule::parse("0 12 * * 7").unwrap();
+        assert!(schedule.matches(datetime!(2024-05-05 12:00 UTC)));
+        assert!(!schedule.matches(datetime!(2024-05-06 12:00 UTC)));
+
+        assert!(Schedule::parse("0 12 * *").is_err());
+        assert!(Schedule::parse("60 * * * *").is_err());
+        assert!(Schedule::parse("*/0 * * * *").is_err());
+    }
+}
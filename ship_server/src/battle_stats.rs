@@ -67,7 +67,7 @@ impl PlayerStats {
         let Some(char) = &user.character else {
             unreachable!("User should be in state >= `PreInGame`")
         };
-        let server_data = &user.get_blockdata().server_data;
+        let server_data = user.get_blockdata().server_data.load_full();
 
         let char_data = &char.character;
         let class = char_data.classes.main_class as usize;
@@ -111,7 +111,8 @@ impl PlayerStats {
             unreachable!("User should be in state >= `PreInGame`")
         };
         let mut resulting_stats = Self::default();
-        let player_stats = &user.get_blockdata().server_data.player_stats;
+        let server_data = user.get_blockdata().server_data.load_full();
+        let player_stats = &server_data.player_stats;
 
         let stats = &player_stats.stats[class][level - 1];
 
@@ -270,8 +271,13 @@ impl EnemyStats {
             .get(name)
             .ok_or(Error::NoEnemyData(name.to_string()))?;
         resulting_stats.hitboxes.clone_from(&enemy_stats.hitboxes);
-        let base_level_stats = &base_stats.levels[level as usize - 1];
-        let level_stats = &enemy_stats.levels[level as usize - 1];
+        // maps without a quest (e.g. the lobby) have no enemy level
+        let index = level.max(1) as usize - 1;
+        let (Some(base_level_stats), Some(level_stats)) =
+            (base_stats.levels.get(index), enemy_stats.levels.get(index))
+        else {
+            return Err(Error::NoEnemyData(name.to_string()));
+        };
 
         resulting_stats.level = level_stats.level;
         resulting_stats.exp = (base_level_stats.exp * level_stats.exp).floor() as _;
@@ -296,6 +302,9 @@ impl EnemyStats {
 
         Ok(resulting_stats)
     }
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
     pub fn create_spawn_packet(&self, id: u32, map_id: u16) -> EnemySpawnPacket {
         EnemySpawnPacket {
             object: pso2packetlib::protocol::ObjectHeader {
@@ -1,31 +1,42 @@
 use crate::{
-    map,
+    lua_reload, map,
     mutex::{Mutex, RwLock},
+    plugins::{Event, Plugins},
+    rate_limit::{RateLimiter, Violation},
+    session,
+    settings::RateLimitAction,
     sql,
-    user::User,
+    user::{User, UserState},
     Action, BlockData, BlockInfo, Error,
 };
-use pso2packetlib::{connection::ConnectionError, PrivateKey};
+use pso2packetlib::{connection::ConnectionError, protocol::unk19::MessageType, PrivateKey};
 use std::{
     io,
     sync::{atomic::AtomicU32, Arc},
+    time::{Duration, Instant},
 };
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::mpsc,
+    sync::{mpsc, watch},
 };
 
+/// Remaining seconds at which the shutdown countdown is announced.
+const SHUTDOWN_ANNOUNCEMENTS: [u64; 7] = [60, 30, 10, 5, 3, 2, 1];
+
 pub async fn init_block(
     blocks: Arc<RwLock<Vec<BlockInfo>>>,
     this_block: BlockInfo,
     sql: Arc<sql::Sql>,
     key: PrivateKey,
+    mut shutdown: watch::Receiver<Option<Instant>>,
+    running_blocks: Arc<RwLock<Vec<Arc<BlockData>>>>,
 ) -> Result<(), Error> {
     let listener = TcpListener::bind(("0.0.0.0", this_block.port)).await?;
 
     let latest_mapid = AtomicU32::new(0);
 
-    let Some(lobby) = this_block.server_data.maps.get(&this_block.lobby_map) else {
+    let server_data = this_block.server_data.load_full();
+    let Some(lobby) = server_data.maps.get(&this_block.lobby_map) else {
         return Err(Error::NoMapFound(this_block.lobby_map.clone()));
     };
 
@@ -40,62 +51,169 @@ pub async fn init_block(
         blocks,
         block_id: this_block.id,
         block_name: this_block.name,
+        block_type: this_block.block_type,
         lobby,
         key,
         latest_mapid,
         latest_partyid: AtomicU32::new(0),
+        autosave_interval: this_block.autosave_interval,
+        reconnect_grace: this_block.reconnect_grace,
+        rate_limits: this_block.rate_limits,
+        validation: this_block.validation,
+        lua_limits: this_block.lua_limits,
+        lua_reloader: this_block.lua_reloader,
+        plugins: this_block.plugins,
+        capture_all: this_block.capture_all,
+        captures: this_block.captures,
+        mutes: this_block.mutes,
+        chat_log: this_block.chat_log,
         server_data: this_block.server_data,
         quests: this_block.quests,
         clients: Mutex::new(vec![]),
+        running_blocks: running_blocks.clone(),
+        suspended: this_block.suspended,
     });
     // we are the only owner of the map, so this never blocks
-    block_data
-        .lobby
-        .lock_blocking()
-        .set_block_data(block_data.clone());
+    {
+        let mut lobby = block_data.lobby.lock_blocking();
+        lobby.set_block_data(block_data.clone());
+        lobby.set_handle(&block_data.lobby);
+    }
+    if let Some(reloader) = &block_data.lua_reloader {
+        let source = lua_reload::ScriptSource::Map(this_block.lobby_map.clone());
+        reloader.register(source, &block_data.lobby).await;
+    }
+    running_blocks.write().await.push(block_data.clone());
 
     let mut conn_id = 0usize;
     let (send, mut recv) = mpsc::channel(10);
+    let (disconnect_send, disconnect_recv) = watch::channel(false);
+    let mut listener = Some(listener);
+    let mut shutdown_at = None;
+    let mut last_announcement = u64::MAX;
+    let mut countdown = tokio::time::interval(Duration::from_secs(1));
+    let mut session_expiry = tokio::time::interval(Duration::from_secs(5));
 
     loop {
         tokio::select! {
             // we opt out of random selection because the listener is rarely accepting
             biased;
-            result = listener.accept() => {
+            // the deadline can be moved closer if the shutdown is forced
+            Ok(_) = shutdown.changed() => {
+                let deadline = *shutdown.borrow_and_update();
+                if deadline.is_some() && shutdown_at.is_none() {
+                    log::info!("Block {} is shutting down", block_data.block_name);
+                    // stop accepting new connections
+                    listener = None;
+                }
+                shutdown_at = deadline.or(shutdown_at);
+            }
+            result = accept(&listener) => {
                 let (stream, _) = result?;
                 new_conn_handler(
                     stream,
                     &block_data,
                     send.clone(),
+                    disconnect_recv.clone(),
                     this_block.id,
                     &mut conn_id,
                 )
                 .await?;
             }
+            _ = countdown.tick(), if shutdown_at.is_some() => {
+                let remaining = shutdown_at
+                    .unwrap()
+                    .saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break;
+                }
+                let secs = remaining.as_secs_f32().ceil() as u64;
+                if last_announcement == u64::MAX
+                    || SHUTDOWN_ANNOUNCEMENTS
+                        .iter()
+                        .any(|&a| secs <= a && last_announcement > a)
+                {
+                    last_announcement = secs;
+                    broadcast_msg(
+                        &block_data,
+                        &format!("Server will shut down in {secs} seconds"),
+                        MessageType::AdminMessageInstant,
+                    )
+                    .await;
+                }
+            }
             Some((id, action)) = recv.recv() => {
                 match run_action(&block_data, id, action, &block_data).await {
                     Ok(_) => {}
                     Err(e) => log::warn!("Client error: {e}"),
                 };
             }
+            _ = session_expiry.tick(), if block_data.reconnect_grace.is_some() => {
+                session::expire(&block_data).await;
+            }
         };
     }
+
+    // save and disconnect every client
+    let _ = disconnect_send.send(true);
+    let timeout = tokio::time::sleep(Duration::from_secs(10));
+    tokio::pin!(timeout);
+    while !block_data.clients.lock().await.is_empty() {
+        tokio::select! {
+            Some((id, action)) = recv.recv() => {
+                if let Err(e) = run_action(&block_data, id, action, &block_data).await {
+                    log::warn!("Client error: {e}");
+                }
+            }
+            _ = &mut timeout => {
+                log::warn!("Block {}: not all clients disconnected in time", block_data.block_name);
+                break;
+            }
+        }
+    }
+    session::save_all(&block_data).await;
+    running_blocks
+        .write()
+        .await
+        .retain(|b| !Arc::ptr_eq(b, &block_data));
+    log::info!("Block {} stopped", block_data.block_name);
+    Ok(())
+}
+
+async fn accept(listener: &Option<TcpListener>) -> io::Result<(TcpStream, std::net::SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Sends a system message to every client in the block.
+pub async fn broadcast_msg(block_data: &BlockData, msg: &str, msg_type: MessageType) {
+    let clients: Vec<_> = block_data
+        .clients
+        .lock()
+        .await
+        .iter()
+        .map(|(_, c)| c.clone())
+        .collect();
+    for client in clients {
+        let _ = client.lock().await.send_message(msg, msg_type).await;
+    }
 }
 
 async fn new_conn_handler(
     s: TcpStream,
     block_data: &Arc<BlockData>,
     send: mpsc::Sender<(usize, Action)>,
+    mut disconnect: watch::Receiver<bool>,
     block_id: u32,
     conn_id_ref: &mut usize,
 ) -> Result<(), Error> {
     log::info!("Client connected");
 
+    // full blocks are rejected during login, so that the client gets an error message
     let mut lock = block_data.blocks.write().await;
     if let Some(block) = lock.iter_mut().find(|x| x.id == block_id) {
-        if block.players >= block.max_players {
-            return Ok(());
-        }
         block.players += 1;
     }
     drop(lock);
@@ -105,6 +223,8 @@ async fn new_conn_handler(
     let client = Arc::new(Mutex::new(client));
     let mut clients = block_data.clients.lock().await;
     clients.push((conn_id, client.clone()));
+    let mut rate_limiter = RateLimiter::new(&block_data.rate_limits);
+    let rate_limit_action = block_data.rate_limits.action;
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(std::time::Duration::from_millis(100));
         loop {
@@ -112,9 +232,14 @@ async fn new_conn_handler(
                 biased;
                 result = read.read_packet_async() => {
                     match result {
-                        Ok(a) => {
-                            let lock = client.lock().await;
-                            crate::user::packet_handler(lock, a).await
+                        Ok(a) => match rate_limiter.check(&a) {
+                            Ok(_) => {
+                                let lock = client.lock().await;
+                                crate::user::packet_handler(lock, a).await
+                            }
+                            Err(violation) => {
+                                on_rate_limit(&client, violation, rate_limit_action).await
+                            }
                         },
                         Err(ConnectionError::Io(e)) if matches!(e.kind(), io::ErrorKind::Interrupted) => Ok(Action::Nothing),
                         Err(ConnectionError::Io(e))
@@ -123,13 +248,12 @@ async fn new_conn_handler(
                                 io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset
                             ) =>
                         {
-                            send.send((conn_id, Action::Disconnect)).await.unwrap();
+                            send.send((conn_id, Action::ConnectionLost)).await.unwrap();
                             return;
                         }
                         Err(e) => {
-                            let error_msg = format!("Client error: {e}");
-                            let _ = client.lock().await.send_error(&error_msg).await;
-                            log::warn!("{}", error_msg);
+                            let _ = client.lock().await.send_error("client_error", &[("error", &e)]).await;
+                            log::warn!("Client error: {e}");
                             Ok(Action::Nothing)
                         }
                     }
@@ -137,11 +261,23 @@ async fn new_conn_handler(
                 _ = interval.tick() => {
                     User::tick(client.lock().await).await
                 }
+                _ = disconnect.changed() => {
+                    let mut user = client.lock().await;
+                    if let Err(e) = user.save().await {
+                        log::warn!("Failed to save user {}: {e}", user.get_user_id());
+                    }
+                    let _ = user
+                        .send_localized_msg("server_shutdown", &[], MessageType::AdminMessage)
+                        .await;
+                    drop(user);
+                    let _ = send.send((conn_id, Action::Disconnect)).await;
+                    return;
+                }
             };
             match result {
                 Ok(Action::Nothing) => {}
-                Ok(Action::Disconnect) => {
-                    send.send((conn_id, Action::Disconnect)).await.unwrap();
+                Ok(action @ (Action::Disconnect | Action::ConnectionLost)) => {
+                    send.send((conn_id, action)).await.unwrap();
                     return;
                 }
                 Err(Error::IOError(e)) if matches!(e.kind(), io::ErrorKind::Interrupted) => {}
@@ -151,13 +287,16 @@ async fn new_conn_handler(
                         io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset
                     ) =>
                 {
-                    send.send((conn_id, Action::Disconnect)).await.unwrap();
+                    send.send((conn_id, Action::ConnectionLost)).await.unwrap();
                     return;
                 }
                 Err(e) => {
-                    let error_msg = format!("Client error: {e}");
-                    let _ = client.lock().await.send_error(&error_msg).await;
-                    log::warn!("{}", error_msg);
+                    let _ = client
+                        .lock()
+                        .await
+                        .send_error("client_error", &[("error", &e)])
+                        .await;
+                    log::warn!("Client error: {e}");
                 }
             }
         }
@@ -167,6 +306,32 @@ async fn new_conn_handler(
     Ok(())
 }
 
+async fn on_rate_limit(
+    client: &Mutex<User>,
+    violation: Violation,
+    action: RateLimitAction,
+) -> Result<Action, Error> {
+    let mut user = client.lock().await;
+    // don't flood the log with every dropped packet
+    if violation.count == 1 || violation.count.is_multiple_of(100) {
+        log::warn!(
+            "User {} exceeded the {:?} packet rate limit ({} violations)",
+            user.get_user_id(),
+            violation.category,
+            violation.count
+        );
+    }
+    match action {
+        RateLimitAction::Drop => Ok(Action::Nothing),
+        RateLimitAction::Kick => {
+            let _ = user
+                .send_localized_msg("kicked_flooding", &[], MessageType::AdminMessage)
+                .await;
+            Ok(Action::Disconnect)
+        }
+    }
+}
+
 async fn run_action(
     block: &BlockData,
     conn_id: usize,
@@ -186,9 +351,20 @@ async fn run_action(
     };
     match action {
         Action::Nothing => {}
-        Action::Disconnect => {
+        Action::Disconnect | Action::ConnectionLost => {
             log::info!("Client disconnected");
-            clients.remove(pos);
+            let (_, user) = clients.remove(pos);
+            drop(clients);
+            let (player, in_game) = {
+                let user = user.lock().await;
+                (user.get_user_id(), user.state == UserState::InGame)
+            };
+            if in_game {
+                Plugins::dispatch(block_data, Event::Logout { player });
+            }
+            if matches!(action, Action::ConnectionLost) {
+                session::suspend(block, user).await;
+            }
 
             let mut lock = block_data.blocks.write().await;
             if let Some(block) = lock.iter_mut().find(|x| x.id == block_data.block_id) {
@@ -0,0 +1,228 @@
+use crate::{BlockData, Error};
+use pso2packetlib::{
+    ppac::{Direction, PPACWriter},
+    protocol::{Packet, PacketType},
+};
+use std::{
+    collections::HashSet,
+    fs::File,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// Maximum number of packets written by a single blocking task.
+const BATCH_SIZE: usize = 64;
+
+enum Record {
+    Packet(Duration, Direction, Box<Packet>),
+    PacketType(PacketType),
+}
+
+/// Recording of every packet of a single connection to a PPAC file.
+///
+/// Packets are written by a background task, so recording never blocks the connection.
+pub struct Capture {
+    sender: UnboundedSender<Record>,
+}
+
+impl Capture {
+    fn create(path: PathBuf, packet_type: PacketType) -> Self {
+        let (sender, receiver) = unbounded_channel();
+        tokio::spawn(write_records(path, packet_type, receiver));
+        Self { sender }
+    }
+    /// Records a packet, `Direction::ToServer` is used for packets sent by the client.
+    /// Passwords are removed from the recorded packets.
+    pub fn write(&mut self, direction: Direction, packet: &Packet) {
+        let mut packet = packet.clone();
+        redact(&mut packet);
+        let _ = self
+            .sender
+            .send(Record::Packet(now(), direction, Box::new(packet)));
+    }
+    pub fn change_packet_type(&mut self, packet_type: PacketType) {
+        let _ = self.sender.send(Record::PacketType(packet_type));
+    }
+}
+
+/// Clears the credentials sent by the client.
+fn redact(packet: &mut Packet) {
+    match packet {
+        Packet::SegaIDLogin(p) => p.password = Default::default(),
+        Packet::VitaLogin(p) => p.password = Default::default(),
+        Packet::SecondPwdOperationRequest(p) => p.password = Default::default(),
+        _ => {}
+    }
+}
+
+async fn write_records(
+    path: PathBuf,
+    packet_type: PacketType,
+    mut receiver: UnboundedReceiver<Record>,
+) {
+    let open_path = path.clone();
+    let writer = tokio::task::spawn_blocking(move || -> Result<_, Error> {
+        if let Some(dir) = open_path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        Ok(PPACWriter::new(
+            File::create(&open_path)?,
+            packet_type,
+            true,
+        )?)
+    });
+    let mut writer = match writer.await.map_err(Error::from).and_then(|w| w) {
+        Ok(w) => w,
+        Err(e) => {
+            log::warn!("Failed to start packet capture {}: {e}", path.display());
+            return;
+        }
+    };
+    log::info!("Started packet capture {}", path.display());
+    let mut records = Vec::with_capacity(BATCH_SIZE);
+    while receiver.recv_many(&mut records, BATCH_SIZE).await != 0 {
+        let batch = std::mem::take(&mut records);
+        let result = tokio::task::spawn_blocking(move || {
+            let mut result = Ok(());
+            for record in batch {
+                let write_result = match record {
+                    Record::Packet(time, direction, packet) => {
+                        writer.write_packet(time, direction, &*packet)
+                    }
+                    Record::PacketType(packet_type) => writer.change_packet_type(packet_type),
+                };
+                if let Err(e) = write_result {
+                    result = Err(e);
+                }
+            }
+            (writer, result)
+        })
+        .await;
+        match result {
+            Ok((w, result)) => {
+                writer = w;
+                if let Err(e) = result {
+                    log::warn!("Failed to write packet capture {}: {e}", path.display());
+                }
+            }
+            Err(e) => {
+                log::warn!("Failed to write packet capture {}: {e}", path.display());
+                return;
+            }
+        }
+    }
+    log::info!("Stopped packet capture {}", path.display());
+}
+
+/// Capture configuration shared by all blocks.
+pub struct Captures {
+    directory: PathBuf,
+    /// Accounts that are recorded on every login.
+    accounts: parking_lot::Mutex<HashSet<u32>>,
+}
+
+impl Captures {
+    pub fn new(directory: String, accounts: Vec<u32>) -> Self {
+        Self {
+            directory: directory.into(),
+            accounts: parking_lot::Mutex::new(accounts.into_iter().collect()),
+        }
+    }
+    pub fn is_captured(&self, player_id: u32) -> bool {
+        self.accounts.lock().contains(&player_id)
+    }
+    pub fn set_captured(&self, player_id: u32, enabled: bool) {
+        let mut accounts = self.accounts.lock();
+        if enabled {
+            accounts.insert(player_id);
+        } else {
+            accounts.remove(&player_id);
+        }
+    }
+    /// Starts a capture of a connection that hasn't logged in yet.
+    pub fn start_connection(&self, block_id: u32, conn_id: usize) -> Capture {
+        let name = format!("block{block_id}_conn{conn_id}_{}.ppac", now().as_secs());
+        self.start(name, PacketType::Classic)
+    }
+    /// Starts a capture of a logged in account.
+    pub fn start_account(&self, player_id: u32, packet_type: PacketType) -> Capture {
+        let name = format!("user{player_id}_{}.ppac", now().as_secs());
+        self.start(name, packet_type)
+    }
+    fn start(&self, name: String, packet_type: PacketType) -> Capture {
+        Capture::create(self.directory.join(name), packet_type)
+    }
+}
+
+/// Enables or disables the capture of an account, returns `false` if the player is not in the
+/// block. The account is also recorded on the next logins.
+pub async fn set_capture(block: &BlockData, player_id: u32, enabled: bool) -> bool {
+    block.captures.set_captured(player_id, enabled);
+    let clients: Vec<_> = block
+        .clients
+        .lock()
+        .await
+        .iter()
+        .map(|(_, c)| c.clone())
+        .collect();
+    for client in clients {
+        let mut client = client.lock().await;
+        if client.get_user_id() == player_id {
+            client.set_capture(enabled);
+            return true;
+        }
+    }
+    false
+}
+
+fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{redact, Captures};
+    use crate::map_harness::MapHarness;
+    use pso2packetlib::protocol::{login::SegaIDLoginPacket, Packet};
+    use std::{path::Path, sync::Arc, time::Duration};
+
+    #[test]
+    fn test_redact() {
+        let mut packet = Packet::SegaIDLogin(SegaIDLoginPacket {
+            username: String::from("user").into(),
+            password: String::from("secret").into(),
+            ..Default::default()
+        });
+        redact(&mut packet);
+        let Packet::SegaIDLogin(packet) = packet else {
+            unreachable!()
+        };
+        assert_eq!(packet.username.to_string(), "user");
+        assert!(packet.password.to_string().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_capture_file() {
+        let dir = std::env::temp_dir().join(format!("capture_{}", std::process::id()));
+        let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("../data/maps/lobby");
+        let data = MapHarness::load_map_dir(data).unwrap();
+        let captures = Arc::new(Captures::new(dir.display().to_string(), vec![]));
+        let mut harness = MapHarness::with_block(data, |b| {
+            b.capture_all = true;
+            b.captures = captures;
+        })
+        .await
+        .unwrap();
+        harness.add_player(1).await.unwrap();
+        drop(harness);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let sizes: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|f| f.unwrap().metadata().unwrap().len())
+            .collect();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(sizes.len(), 1);
+        assert!(sizes[0] > 0);
+    }
+}
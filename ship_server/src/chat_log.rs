@@ -0,0 +1,191 @@
+use crate::settings::ChatLogSettings;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+use time::{macros::format_description, OffsetDateTime};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// Maximum number of lines written by a single blocking task.
+const BATCH_SIZE: usize = 64;
+
+/// Log of every chat message of the ship. The file is rotated after reaching the size limit,
+/// `chat.log` becomes `chat.log.1` and so on.
+///
+/// Lines are written by a background task, so logging never blocks the chat.
+#[derive(Default)]
+pub struct ChatLog {
+    /// `None` if the log is disabled.
+    sender: Option<UnboundedSender<String>>,
+}
+
+/// A single chat message.
+pub struct ChatEntry<'a> {
+    pub block_id: u32,
+    /// Name of the channel (e.g. `map`, `whisper`).
+    pub channel: &'a str,
+    pub sender_id: u32,
+    pub sender_name: &'a str,
+    /// Player ID of the receiver of a whisper.
+    pub receiver_id: Option<u32>,
+    pub message: &'a str,
+}
+
+impl ChatLog {
+    pub fn new(settings: ChatLogSettings) -> Self {
+        let sender = settings.enabled.then(|| {
+            let (sender, receiver) = unbounded_channel();
+            let file = LogFile {
+                path: settings.file.into(),
+                max_size: settings.max_size,
+                max_files: settings.max_files,
+                file: None,
+            };
+            tokio::spawn(write_lines(file, receiver));
+            sender
+        });
+        Self { sender }
+    }
+    pub fn write(&self, entry: ChatEntry) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let format = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+        let timestamp = OffsetDateTime::now_utc().format(format).unwrap_or_default();
+        let ChatEntry {
+            block_id,
+            channel,
+            sender_id,
+            sender_name,
+            receiver_id,
+            message,
+        } = entry;
+        let receiver = receiver_id
+            .map(|id| format!(" -> {id}"))
+            .unwrap_or_default();
+        // messages can't add fake lines
+        let message = message.replace(['\r', '\n'], " ");
+        let line = format!(
+            "[{timestamp}] [block {block_id}] [{channel}] {sender_id} ({sender_name}){receiver}: \
+             {message}\n"
+        );
+        let _ = sender.send(line);
+    }
+}
+
+/// Currently open log file.
+struct LogFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: u32,
+    file: Option<(File, u64)>,
+}
+
+impl LogFile {
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        if let Some((_, size)) = self.file.as_ref() {
+            if self.max_size != 0 && size + line.len() as u64 > self.max_size {
+                self.file = None;
+                self.rotate()?;
+            }
+        }
+        let (file, size) = match self.file.as_mut() {
+            Some(file) => file,
+            None => {
+                if let Some(dir) = self.path.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                let new_file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?;
+                let size = new_file.metadata()?.len();
+                self.file.insert((new_file, size))
+            }
+        };
+        file.write_all(line.as_bytes())?;
+        *size += line.len() as u64;
+        Ok(())
+    }
+    fn rotate(&self) -> std::io::Result<()> {
+        let path = &self.path;
+        let numbered = |n: u32| {
+            let mut name = path.clone().into_os_string();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        if self.max_files == 0 {
+            return std::fs::remove_file(path);
+        }
+        let _ = std::fs::remove_file(numbered(self.max_files));
+        for n in (1..self.max_files).rev() {
+            let from = numbered(n);
+            if from.exists() {
+                std::fs::rename(from, numbered(n + 1))?;
+            }
+        }
+        std::fs::rename(path, numbered(1))
+    }
+}
+
+async fn write_lines(mut file: LogFile, mut receiver: UnboundedReceiver<String>) {
+    let mut lines = Vec::with_capacity(BATCH_SIZE);
+    while receiver.recv_many(&mut lines, BATCH_SIZE).await != 0 {
+        let batch = std::mem::take(&mut lines);
+        let result = tokio::task::spawn_blocking(move || {
+            for line in batch {
+                if let Err(e) = file.write_line(&line) {
+                    log::warn!("Failed to write the chat log {}: {e}", file.path.display());
+                }
+            }
+            file
+        })
+        .await;
+        match result {
+            Ok(f) => file = f,
+            Err(e) => {
+                log::warn!("Failed to write the chat log: {e}");
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChatEntry, ChatLog};
+    use crate::settings::ChatLogSettings;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("chat_log_{}", std::process::id()));
+        let log = ChatLog::new(ChatLogSettings {
+            enabled: true,
+            file: dir.join("chat.log").to_string_lossy().into(),
+            max_size: 100,
+            max_files: 2,
+        });
+        for i in 0..10 {
+            log.write(ChatEntry {
+                block_id: 1,
+                channel: "map",
+                sender_id: i,
+                sender_name: "Name",
+                receiver_id: None,
+                message: "hello\nworld",
+            });
+        }
+        // the writer stops once every line is written
+        drop(log);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let current = std::fs::read_to_string(dir.join("chat.log")).unwrap();
+        let rotated = std::fs::read_to_string(dir.join("chat.log.2")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(current.ends_with("[block 1] [map] 9 (Name): hello world\n"));
+        assert_eq!(current.lines().count(), 1);
+        assert!(rotated.contains("] 7 (Name)"));
+        assert!(rotated.len() <= 100);
+    }
+}
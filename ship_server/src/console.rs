@@ -0,0 +1,345 @@
+use crate::{
+    announcements::{announce, LocalizedMessage},
+    moderation,
+    mutex::{Mutex, RwLock},
+    user::User,
+    BlockData, Error, SharedData,
+};
+use data_structs::master_ship::PermissionLevel;
+use indicatif::HumanBytes;
+use memory_stats::memory_stats;
+use pso2packetlib::protocol::unk19::MessageType;
+use std::{fmt::Write, sync::Arc};
+
+const HELP: &str = "\
+help                        - show this message
+blocks                      - list blocks
+players [block id]          - list players
+kick <player id>            - save and disconnect a player
+broadcast <message>         - send a system message to every player
+announce <block id> <msg>   - send a system message to every player in a block
+lobby <player id>           - move a player to the lobby
+permission <id> <level>     - set the level of a player (player, moderator, gm, admin)
+reload                      - reload server data (lobby changes need a restart)
+mem                         - show memory usage
+dump <player id> [path]     - dump character data as JSON";
+
+/// Operator console, shared by the interactive prompt and the control socket.
+pub struct Console {
+    blocks: Arc<RwLock<Vec<Arc<BlockData>>>>,
+    shared_data: Arc<SharedData>,
+    sql: Arc<crate::sql::Sql>,
+}
+
+impl Console {
+    pub const fn new(
+        blocks: Arc<RwLock<Vec<Arc<BlockData>>>>,
+        shared_data: Arc<SharedData>,
+        sql: Arc<crate::sql::Sql>,
+    ) -> Self {
+        Self {
+            blocks,
+            shared_data,
+            sql,
+        }
+    }
+    /// Runs a single command line and returns its output.
+    pub async fn execute(&self, line: &str) -> String {
+        let mut args = line.split_whitespace();
+        let Some(cmd) = args.next() else {
+            return String::new();
+        };
+        let result = match cmd {
+            "help" => Ok(HELP.to_string()),
+            "blocks" => Ok(self.list_blocks().await),
+            "players" => {
+                let block_id = args.next().and_then(|a| a.parse().ok());
+                Ok(self.list_players(block_id).await)
+            }
+            "kick" => match parse_id(args.next()) {
+                Ok(id) => self.kick(id).await,
+                Err(e) => Err(e),
+            },
+            "broadcast" => Ok(self.announce(None, args).await),
+            "announce" => match parse_id(args.next()) {
+                Ok(id) => Ok(self.announce(Some(id), args).await),
+                Err(e) => Err(e),
+            },
+            "lobby" => match parse_id(args.next()) {
+                Ok(id) => self.move_to_lobby(id).await,
+                Err(e) => Err(e),
+            },
+            "permission" => match (parse_id(args.next()), args.next().map(str::parse)) {
+                (Ok(id), Some(Ok(level))) => self.set_permission(id, level).await,
+                (Ok(_), Some(Err(e))) => Ok(e),
+                _ => Err(Error::InvalidInput("console")),
+            },
+            "reload" => self
+                .shared_data
+                .reload(&self.sql)
+                .await
+                .map(|_| "Server data reloaded, lobbies need a restart".to_string()),
+            "mem" => Ok(memory_usage()),
+            "dump" => match parse_id(args.next()) {
+                Ok(id) => self.dump(id, args.next()).await,
+                Err(e) => Err(e),
+            },
+            _ => Ok("Unknown command, type 'help' for the list of commands".to_string()),
+        };
+        result.unwrap_or_else(|e| format!("Error: {e}"))
+    }
+    async fn list_blocks(&self) -> String {
+        let mut output = String::new();
+        for block in self.blocks.read().await.iter() {
+            let players = block.clients.lock().await.len();
+            let _ = writeln!(
+                output,
+                "{:>3} {} ({:?}) - {players} players",
+                block.block_id, block.block_name, block.block_type
+            );
+        }
+        output.trim_end().to_string()
+    }
+    async fn list_players(&self, block_id: Option<u32>) -> String {
+        let mut output = String::new();
+        for block in self.blocks.read().await.iter() {
+            if block_id.is_some_and(|id| id != block.block_id) {
+                continue;
+            }
+            for user in block_users(block).await {
+                let user = user.lock().await;
+                let char_name = user
+                    .character
+                    .as_ref()
+                    .map(|c| c.character.name.to_string())
+                    .unwrap_or_default();
+                let _ = writeln!(
+                    output,
+                    "[{}] {:>8} {} ({char_name}) zone {}",
+                    block.block_name,
+                    user.get_user_id(),
+                    user.user_data.nickname,
+                    user.get_zone_id()
+                );
+            }
+        }
+        if output.is_empty() {
+            return "No players online".to_string();
+        }
+        output.trim_end().to_string()
+    }
+    async fn announce<'a>(
+        &self,
+        block_id: Option<u32>,
+        msg: impl Iterator<Item = &'a str> + Send,
+    ) -> String {
+        let msg = msg.collect::<Vec<_>>().join(" ");
+        if msg.is_empty() {
+            return "No message provided".to_string();
+        }
+        let message = LocalizedMessage {
+            en: msg.clone(),
+            jp: msg,
+        };
+        announce(&self.blocks, block_id, MessageType::SystemMessage, &message).await;
+        "Message sent".to_string()
+    }
+    async fn find_player(&self, id: u32) -> Result<Arc<Mutex<User>>, Error> {
+        for block in self.blocks.read().await.iter() {
+            for user in block_users(block).await {
+                if user.lock().await.get_user_id() == id {
+                    return Ok(user);
+                }
+            }
+        }
+        Err(Error::NoUser)
+    }
+    async fn kick(&self, id: u32) -> Result<String, Error> {
+        let user = self.find_player(id).await?;
+        moderation::kick(&mut *user.lock().await).await;
+        Ok(format!("Player {id} kicked"))
+    }
+    async fn move_to_lobby(&self, id: u32) -> Result<String, Error> {
+        let user = self.find_player(id).await?;
+        let map = user.lock().await.get_current_map();
+        let Some(map) = map else {
+            return Ok(format!("Player {id} is not in a map"));
+        };
+        map.lock().await.move_to_lobby(id).await?;
+        Ok(format!("Player {id} moved to the lobby"))
+    }
+    async fn set_permission(&self, id: u32, level: PermissionLevel) -> Result<String, Error> {
+        self.sql.set_permission(id, level).await?;
+        if let Ok(user) = self.find_player(id).await {
+            user.lock().await.user_data.permission = level;
+        }
+        log::info!("Permission level of player {id} set to {level}");
+        Ok(format!("Player {id} is now {level}"))
+    }
+    async fn dump(&self, id: u32, path: Option<&str>) -> Result<String, Error> {
+        let user = self.find_player(id).await?;
+        let json = match &user.lock().await.character {
+            Some(char) => serde_json::to_string_pretty(char)?,
+            None => return Ok(format!("Player {id} has no character loaded")),
+        };
+        match path {
+            Some(path) => {
+                tokio::fs::write(path, json).await?;
+                Ok(format!("Character of player {id} dumped to {path}"))
+            }
+            None => Ok(json),
+        }
+    }
+}
+
+async fn block_users(block: &BlockData) -> Vec<Arc<Mutex<User>>> {
+    block
+        .clients
+        .lock()
+        .await
+        .iter()
+        .map(|(_, c)| c.clone())
+        .collect()
+}
+
+fn parse_id(arg: Option<&str>) -> Result<u32, Error> {
+    arg.and_then(|a| a.parse().ok())
+        .ok_or(Error::InvalidInput("console"))
+}
+
+pub fn memory_usage() -> String {
+    if let Some(mem) = memory_stats() {
+        format!(
+            "Physical memory: {}\nVirtual memory: {}",
+            HumanBytes(mem.physical_mem as u64),
+            HumanBytes(mem.virtual_mem as u64),
+        )
+    } else {
+        "Couldn't gather memory info".into()
+    }
+}
+
+/// Reads commands from the terminal until stdin is closed.
+pub fn spawn_prompt(console: Arc<Console>) {
+    let handle = tokio::runtime::Handle::current();
+    std::thread::spawn(move || loop {
+        let line = dialoguer::Input::<String>::new()
+            .with_prompt("ship")
+            .allow_empty(true)
+            .interact_text();
+        match line {
+            Ok(line) if line.trim().is_empty() => {}
+            Ok(line) => println!("{}", handle.block_on(console.execute(&line))),
+            // ctrl-c is delivered as a signal
+            Err(dialoguer::Error::IO(e)) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(_) => return,
+        }
+    });
+}
+
+/// Accepts commands over a local socket, one command per line.
+#[cfg(unix)]
+pub fn spawn_socket(console: Arc<Console>, path: &str) -> Result<(), Error> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let _ = std::fs::remove_file(path);
+    let listener = tokio::net::UnixListener::bind(path)?;
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((s, _)) => s,
+                Err(e) => {
+                    log::warn!("Failed to accept console connection: {e}");
+                    return;
+                }
+            };
+            let console = console.clone();
+            tokio::spawn(async move {
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let mut output = console.execute(&line).await;
+                    output.push('\n');
+                    if write.write_all(output.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn spawn_socket(_: Arc<Console>, _: &str) -> Result<(), Error> {
+    log::warn!("Console socket is only supported on unix");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Console, HELP};
+    use crate::{master_conn::MasterConnection, mutex::RwLock, sql::Sql, SharedData};
+    use data_structs::master_ship::{MasterShipAction as MAS, PermissionLevel, ServerDataResult};
+    use std::sync::Arc;
+
+    async fn console() -> Console {
+        let master = MasterConnection::fake(|action| match action {
+            MAS::ServerDataRequest => MAS::ServerDataResponse(ServerDataResult::Ok(Box::default())),
+            MAS::SetPermission {
+                id: 1,
+                permission: PermissionLevel::Gm,
+            } => MAS::Ok,
+            _ => MAS::Error("unexpected".into()),
+        });
+        let sql = Arc::new(Sql::in_memory(master).await.unwrap());
+        let shared_data = SharedData::load(None, vec![], &sql).await.unwrap();
+        Console::new(Arc::new(RwLock::new(vec![])), Arc::new(shared_data), sql)
+    }
+
+    #[tokio::test]
+    async fn test_console_commands() {
+        let console = console().await;
+        assert_eq!(console.execute("").await, "");
+        assert_eq!(console.execute("  help  ").await, HELP);
+        assert!(console.execute("fly").await.starts_with("Unknown command"));
+        assert_eq!(console.execute("blocks").await, "");
+        assert_eq!(console.execute("players").await, "No players online");
+        assert_eq!(console.execute("players 2").await, "No players online");
+        assert_eq!(console.execute("broadcast").await, "No message provided");
+        assert_eq!(console.execute("announce 1").await, "No message provided");
+        assert_eq!(
+            console.execute("broadcast hello  world").await,
+            "Message sent"
+        );
+        assert_eq!(
+            console.execute("reload").await,
+            "Server data reloaded, lobbies need a restart"
+        );
+        assert!(!console.execute("mem").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_console_arguments() {
+        let console = console().await;
+        let invalid = "Error: Invalid input in fn console";
+        for line in ["kick", "kick abc", "lobby -1", "dump", "announce x hi"] {
+            assert_eq!(console.execute(line).await, invalid, "{line}");
+        }
+        assert_eq!(console.execute("kick 5").await, "Error: No user found");
+        assert_eq!(
+            console.execute("dump 5 out.json").await,
+            "Error: No user found"
+        );
+        assert_eq!(console.execute("permission").await, invalid);
+        assert_eq!(console.execute("permission 1").await, invalid);
+        assert_eq!(
+            console.execute("permission 1 wizard").await,
+            "Unknown permission level wizard"
+        );
+        assert_eq!(
+            console.execute("permission 1 gm").await,
+            "Player 1 is now gm"
+        );
+    }
+}
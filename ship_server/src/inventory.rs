@@ -524,6 +524,12 @@ impl Inventory {
         }));
         packets
     }
+    pub const fn add_meseta(&mut self, amount: u64) -> Packet {
+        self.inventory.meseta = self.inventory.meseta.saturating_add(amount);
+        Packet::InventoryMeseta(InventoryMesetaPacket {
+            meseta: self.inventory.meseta,
+        })
+    }
     pub fn add_item(&mut self, item: Item) -> Packet {
         let packet = Packet::AddedItem(AddedItemPacket {
             item: item.clone(),
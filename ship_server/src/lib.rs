@@ -4,20 +4,34 @@
 #![allow(clippy::await_holding_lock)]
 #![allow(dead_code)]
 
+mod announcements;
 mod battle_stats;
 mod block;
+mod capture;
+mod chat_log;
+mod console;
 mod inventory;
 mod invites;
+mod lua_reload;
 mod map;
+#[cfg(test)]
+mod map_harness;
 mod master_conn;
+mod moderation;
 mod mutex;
 mod palette;
 mod party;
+mod plugins;
 mod quests;
-mod settings;
+mod rate_limit;
+mod script_store;
+mod session;
+pub mod settings;
 mod sql;
 mod user;
+mod validation;
 
+use arc_swap::ArcSwap;
 use data_structs::{
     master_ship::{self, ShipInfo},
     SerDeFile, ServerData,
@@ -29,13 +43,15 @@ use pso2packetlib::{
     Connection, PrivateKey, PublicKey,
 };
 use quests::Quests;
-use rand::Rng;
 use rsa::traits::PublicKeyParts;
-use settings::Settings;
+use settings::{
+    AccountTransfer, BlockType, LuaLimitSettings, RateLimitSettings, Settings, ValidationSettings,
+};
 use std::{
     io,
     net::Ipv4Addr,
     sync::{atomic::AtomicU32, Arc},
+    time::Duration,
 };
 use thiserror::Error;
 use user::*;
@@ -44,6 +60,8 @@ use user::*;
 pub enum Error {
     #[error("Invalid input in fn {0}")]
     InvalidInput(&'static str),
+    #[error("Script store is full")]
+    ScriptStoreFull,
     #[error("Invalid password")]
     InvalidPassword,
     #[error("No user found")]
@@ -60,6 +78,8 @@ pub enum Error {
     MSInvalidPSK,
     #[error("Master server didn't respond")]
     MSNoResponse,
+    #[error("Account is banned")]
+    AccountBanned,
     #[error("User sent unexpected packet while being in state: {0}")]
     UserInvalidState(UserState),
     #[error("Map with name {0} doesn't exist")]
@@ -76,6 +96,8 @@ pub enum Error {
     NoHitboxInfo(String, u32),
     #[error("No ship data available")]
     NoShipData,
+    #[error("User already exists")]
+    UserExists,
 
     // passthrough errors
     #[error("SQL error: {0}")]
@@ -104,8 +126,14 @@ pub enum Error {
     ConnError(#[from] pso2packetlib::connection::ConnectionError),
     #[error("Packet error: {0}")]
     PacketError(#[from] pso2packetlib::protocol::PacketError),
+    #[error("PPAC error: {0}")]
+    PPACError(#[from] pso2packetlib::ppac::PPACError),
+    #[error("Invalid schedule: {0}")]
+    InvalidSchedule(String),
     #[error("Task join error: {0}")]
     JoinError(#[from] tokio::task::JoinError),
+    #[error("File watcher error: {0}")]
+    NotifyError(#[from] notify::Error),
 }
 
 #[derive(Clone)]
@@ -117,22 +145,113 @@ struct BlockInfo {
     max_players: u32,
     players: u32,
     lobby_map: String,
-    server_data: Arc<ServerData>,
-    quests: Arc<Quests>,
+    balance_weight: f32,
+    preferred: bool,
+    block_type: BlockType,
+    autosave_interval: Option<Duration>,
+    reconnect_grace: Option<Duration>,
+    rate_limits: Arc<RateLimitSettings>,
+    validation: Arc<ValidationSettings>,
+    lua_limits: Arc<LuaLimitSettings>,
+    lua_reloader: Option<Arc<lua_reload::LuaReloader>>,
+    plugins: Arc<plugins::Plugins>,
+    capture_all: bool,
+    captures: Arc<capture::Captures>,
+    mutes: Arc<moderation::Mutes>,
+    chat_log: Arc<chat_log::ChatLog>,
+    server_data: Arc<ArcSwap<ServerData>>,
+    quests: Arc<ArcSwap<Quests>>,
+    suspended: Arc<Mutex<Vec<session::SuspendedSession>>>,
 }
 
 struct BlockData {
     sql: Arc<sql::Sql>,
     block_id: u32,
     block_name: String,
+    block_type: BlockType,
     blocks: Arc<RwLock<Vec<BlockInfo>>>,
     lobby: Arc<Mutex<map::Map>>,
     key: PrivateKey,
     latest_mapid: AtomicU32,
     latest_partyid: AtomicU32,
-    server_data: Arc<ServerData>,
-    quests: Arc<Quests>,
+    autosave_interval: Option<Duration>,
+    reconnect_grace: Option<Duration>,
+    rate_limits: Arc<RateLimitSettings>,
+    validation: Arc<ValidationSettings>,
+    lua_limits: Arc<LuaLimitSettings>,
+    /// Registry of running maps in developer mode.
+    lua_reloader: Option<Arc<lua_reload::LuaReloader>>,
+    /// Server-wide Lua plugins.
+    plugins: Arc<plugins::Plugins>,
+    /// Record every connection to this block.
+    capture_all: bool,
+    captures: Arc<capture::Captures>,
+    /// Muted accounts of all blocks.
+    mutes: Arc<moderation::Mutes>,
+    /// Chat messages of all blocks.
+    chat_log: Arc<chat_log::ChatLog>,
+    server_data: Arc<ArcSwap<ServerData>>,
+    quests: Arc<ArcSwap<Quests>>,
     clients: Mutex<Vec<(usize, Arc<Mutex<User>>)>>,
+    /// Every running block of the ship.
+    running_blocks: Arc<RwLock<Vec<Arc<BlockData>>>>,
+    /// Sessions of disconnected players of all blocks waiting for a reconnect, the balancer can
+    /// send the player to another block.
+    suspended: Arc<Mutex<Vec<session::SuspendedSession>>>,
+}
+
+/// Server data shared by all blocks. New maps and lookups always use the latest data.
+struct SharedData {
+    data_file: Option<String>,
+    lobby_maps: Vec<String>,
+    server_data: Arc<ArcSwap<ServerData>>,
+    quests: Arc<ArcSwap<Quests>>,
+}
+
+impl SharedData {
+    async fn load(
+        data_file: Option<String>,
+        lobby_maps: Vec<String>,
+        sql: &sql::Sql,
+    ) -> Result<Self, Error> {
+        let (server_data, quests) = Self::load_data(data_file.as_deref(), &lobby_maps, sql).await?;
+        Ok(Self {
+            data_file,
+            lobby_maps,
+            server_data: Arc::new(ArcSwap::from_pointee(server_data)),
+            quests: Arc::new(ArcSwap::from_pointee(quests)),
+        })
+    }
+    /// Loads and validates fresh data, then swaps it in. Running instances keep the old data,
+    /// including the lobby of each block, so lobby changes need a restart.
+    async fn reload(&self, sql: &sql::Sql) -> Result<(), Error> {
+        let (server_data, quests) =
+            Self::load_data(self.data_file.as_deref(), &self.lobby_maps, sql).await?;
+        self.server_data.store(Arc::new(server_data));
+        self.quests.store(Arc::new(quests));
+        Ok(())
+    }
+    async fn load_data(
+        data_file: Option<&str>,
+        lobby_maps: &[String],
+        sql: &sql::Sql,
+    ) -> Result<(ServerData, Quests), Error> {
+        let mut server_data = match data_file {
+            Some(path) => {
+                let path = path.to_string();
+                tokio::task::spawn_blocking(move || ServerData::load_from_mp_comp(path)).await??
+            }
+            None => sql.get_server_data().await?,
+        };
+        if let Some(map) = lobby_maps
+            .iter()
+            .find(|m| !server_data.maps.contains_key(*m))
+        {
+            return Err(Error::NoMapFound(map.clone()));
+        }
+        let quests = Quests::load(std::mem::take(&mut server_data.quests));
+        Ok((server_data, quests))
+    }
 }
 
 #[derive(Default, Clone)]
@@ -140,6 +259,8 @@ enum Action {
     #[default]
     Nothing,
     Disconnect,
+    /// Connection was lost without logging out, the session can be resumed.
+    ConnectionLost,
 }
 
 // feel free to suggest log level changes
@@ -148,15 +269,16 @@ pub async fn run() -> Result<(), Error> {
     // setup logging
     {
         let _ = std::fs::create_dir_all(&settings.log_dir);
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
         let mut path = std::path::PathBuf::from(&settings.log_dir);
-        path.push(format!(
-            "ship_{}.log",
-            std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs()
-        ));
+        path.push(format!("ship_{timestamp}.log"));
         let log_file = std::fs::File::create(path)?;
+        let mut path = std::path::PathBuf::from(&settings.log_dir);
+        path.push(format!("suspicious_{timestamp}.log"));
+        let suspicious_file = std::fs::File::create(path)?;
 
         use simplelog::*;
         CombinedLogger::init(vec![
@@ -166,11 +288,30 @@ pub async fn run() -> Result<(), Error> {
                 TerminalMode::Mixed,
                 ColorChoice::Auto,
             ),
-            WriteLogger::new(settings.file_log_level, Config::default(), log_file),
+            WriteLogger::new(
+                settings.file_log_level,
+                ConfigBuilder::new()
+                    .add_filter_ignore_str(validation::LOG_TARGET)
+                    .build(),
+                log_file,
+            ),
+            // suspicious client behaviour is kept in a separate file
+            WriteLogger::new(
+                LevelFilter::Info,
+                ConfigBuilder::new()
+                    .add_filter_allow_str(validation::LOG_TARGET)
+                    .build(),
+                suspicious_file,
+            ),
         ])
         .unwrap();
     }
 
+    run_with(settings).await
+}
+
+/// Runs the ship with already loaded settings, without setting up logging.
+pub async fn run_with(mut settings: Settings) -> Result<(), Error> {
     log::info!("Starting server...");
     let key = settings.load_key()?;
     let server_statuses = Arc::new(RwLock::new(Vec::<BlockInfo>::new()));
@@ -192,6 +333,10 @@ pub async fn run() -> Result<(), Error> {
     )
     .await?;
     log::info!("Connected to master ship");
+    if let Some(transfer) = settings.account_transfer.take() {
+        let sql = sql::Sql::new(&settings.db_name, master_conn).await?;
+        return transfer_account(&sql, transfer).await;
+    }
     let total_max_players = settings.blocks.iter().map(|b| b.max_players).sum();
     log::info!("Registering ship");
     for id in settings.min_ship_id..=settings.max_ship_id {
@@ -225,38 +370,65 @@ pub async fn run() -> Result<(), Error> {
     }
     log::info!("Registed ship");
 
-    let mut server_data = Arc::new(if let Some(data_path) = settings.data_file {
-        log::info!("Loading server data...");
-        ServerData::load_from_mp_comp(data_path)?
-    } else {
+    let sql = Arc::new(sql::Sql::new(&settings.db_name, master_conn).await?);
+    if settings.data_file.is_none() {
         log::warn!("No server data file provided, receiving from master ship...");
-        match master_conn
-            .run_action(master_ship::MasterShipAction::ServerDataRequest)
-            .await?
-        {
-            master_ship::MasterShipAction::ServerDataResponse(server_data_result) => {
-                match server_data_result {
-                    master_ship::ServerDataResult::Ok(server_data) => *server_data,
-                    master_ship::ServerDataResult::NotAvailable => {
-                        log::error!("No data available from master ship!");
-                        return Err(Error::NoShipData);
-                    }
-                }
-            }
-            master_ship::MasterShipAction::Error(e) => return Err(Error::MSError(e)),
-            _ => return Err(Error::MSUnexpected),
-        }
-    });
+    }
+    let shared_data = Arc::new(
+        SharedData::load(
+            settings.data_file.clone(),
+            settings
+                .blocks
+                .iter()
+                .map(|b| b.lobby_map.clone())
+                .collect(),
+            &sql,
+        )
+        .await?,
+    );
     log::info!("Loaded server data");
-    let quests = Arc::new(Quests::load(std::mem::take(
-        &mut Arc::get_mut(&mut server_data).unwrap().quests,
-    )));
 
-    let sql = Arc::new(sql::Sql::new(&settings.db_name, master_conn).await?);
     make_block_balance(server_statuses.clone(), settings.balance_port).await?;
     let mut blocks = vec![];
     let mut ports = 13001;
     let mut blockstatus_lock = server_statuses.write().await;
+    let (shutdown_send, shutdown_recv) = tokio::sync::watch::channel(None);
+    let running_blocks = Arc::new(RwLock::new(vec![]));
+    let autosave_interval = match settings.autosave_interval {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let reconnect_grace = match settings.reconnect_grace {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let rate_limits = Arc::new(settings.rate_limits);
+    let validation = Arc::new(settings.validation);
+    let lua_limits = Arc::new(settings.lua_limits);
+    let lua_reloader = match &settings.dev_data_dir {
+        Some(dir) => {
+            let reloader = Arc::new(lua_reload::LuaReloader::new(
+                dir,
+                shared_data.server_data.clone(),
+                shared_data.quests.clone(),
+            ));
+            reloader.reload().await?;
+            reloader.clone().spawn_watcher()?;
+            Some(reloader)
+        }
+        None => None,
+    };
+    let plugins = Arc::new(match &settings.plugin_dir {
+        Some(dir) => plugins::Plugins::load(dir, lua_limits.clone())?,
+        None => Default::default(),
+    });
+    let mutes = Arc::new(moderation::Mutes::default());
+    let suspended = Arc::new(Mutex::new(vec![]));
+    let chat_log = Arc::new(chat_log::ChatLog::new(settings.chat_log));
+    let captures = Arc::new(capture::Captures::new(
+        settings.capture.directory,
+        settings.capture.accounts,
+    ));
     log::info!("Starting blocks...");
     for (i, block) in settings.blocks.into_iter().enumerate() {
         let port = block.port.unwrap_or(ports);
@@ -269,16 +441,42 @@ pub async fn run() -> Result<(), Error> {
             max_players: block.max_players,
             players: 0,
             lobby_map: block.lobby_map,
-            server_data: server_data.clone(),
-            quests: quests.clone(),
+            balance_weight: block.balance_weight,
+            preferred: block.preferred,
+            block_type: block.block_type,
+            autosave_interval,
+            reconnect_grace,
+            rate_limits: rate_limits.clone(),
+            validation: validation.clone(),
+            lua_limits: lua_limits.clone(),
+            lua_reloader: lua_reloader.clone(),
+            plugins: plugins.clone(),
+            capture_all: block.capture,
+            captures: captures.clone(),
+            mutes: mutes.clone(),
+            chat_log: chat_log.clone(),
+            server_data: shared_data.server_data.clone(),
+            quests: shared_data.quests.clone(),
+            suspended: suspended.clone(),
         };
         blockstatus_lock.push(new_block.clone());
         let server_statuses = server_statuses.clone();
         let sql = sql.clone();
         let key = PrivateKey::Key(key.clone());
+        let shutdown_recv = shutdown_recv.clone();
+        let running_blocks = running_blocks.clone();
         log::debug!("Started block {}", block.name);
         blocks.push(tokio::spawn(async move {
-            match block::init_block(server_statuses, new_block, sql, key).await {
+            match block::init_block(
+                server_statuses,
+                new_block,
+                sql,
+                key,
+                shutdown_recv,
+                running_blocks,
+            )
+            .await
+            {
                 Ok(_) => {}
                 Err(e) => log::error!("Block \"{}\" failed: {e}", block.name),
             }
@@ -286,9 +484,90 @@ pub async fn run() -> Result<(), Error> {
     }
     drop(blockstatus_lock);
 
+    announcements::spawn_scheduler(settings.announcements, running_blocks.clone())?;
+    let console = Arc::new(console::Console::new(
+        running_blocks,
+        shared_data.clone(),
+        sql.clone(),
+    ));
+    if let Some(path) = &settings.console_socket {
+        console::spawn_socket(console.clone(), path)?;
+    }
     log::info!("Server started.");
-    tokio::signal::ctrl_c().await?;
+    if settings.console {
+        console::spawn_prompt(console);
+    }
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    loop {
+        #[cfg(unix)]
+        let reload = hangup.recv();
+        #[cfg(not(unix))]
+        let reload = std::future::pending::<Option<()>>();
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                result?;
+                break;
+            }
+            _ = reload => {
+                log::info!("Reloading server data...");
+                match shared_data.reload(&sql).await {
+                    Ok(_) => log::info!("Server data reloaded"),
+                    Err(e) => log::error!("Failed to reload server data: {e}"),
+                }
+            }
+        }
+    }
 
+    let countdown = Duration::from_secs(settings.shutdown_countdown);
+    log::info!(
+        "Shutting down in {} seconds (press Ctrl-C again to shut down immediately)...",
+        countdown.as_secs()
+    );
+    let _ = shutdown_send.send(Some(std::time::Instant::now() + countdown));
+    let wait_blocks = async {
+        for block in blocks.iter_mut() {
+            let _ = block.await;
+        }
+    };
+    tokio::select! {
+        _ = wait_blocks => {}
+        _ = tokio::signal::ctrl_c() => {
+            log::info!("Skipping shutdown countdown...");
+            let _ = shutdown_send.send(Some(std::time::Instant::now()));
+            for block in blocks {
+                let _ = block.await;
+            }
+        }
+    }
+
+    log::info!("Unregistering ship...");
+    if let Err(e) = sql.unregister_ship().await {
+        log::warn!("Failed to unregister ship: {e}");
+    }
+    if let Some(path) = &settings.console_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    log::info!("Server stopped.");
+
+    Ok(())
+}
+
+async fn transfer_account(sql: &sql::Sql, transfer: AccountTransfer) -> Result<(), Error> {
+    match transfer {
+        AccountTransfer::Export { id, path } => {
+            log::info!("Exporting account {id}...");
+            let archive = sql.export_account(id).await?;
+            archive.save_to_mp_comp(&path)?;
+            log::info!("Account {id} exported to {path}");
+        }
+        AccountTransfer::Import { path } => {
+            log::info!("Importing account from {path}...");
+            let archive = sql::AccountArchive::load_from_mp_comp(&path)?;
+            let id = sql.import_account(archive).await?;
+            log::info!("Account imported with id {id}");
+        }
+    }
     Ok(())
 }
 
@@ -328,7 +607,6 @@ async fn send_block_balance(
         PublicKey::None,
     );
     let mut blocks = blocks.write().await;
-    let server_count = blocks.len() as u32;
     for block in blocks.iter_mut() {
         if block.ip == Ipv4Addr::UNSPECIFIED {
             if let std::net::IpAddr::V4(addr) = local_addr {
@@ -336,14 +614,154 @@ async fn send_block_balance(
             }
         }
     }
-    let block = &mut blocks[rand::thread_rng().gen_range(0..server_count) as usize];
+    let Some(block) = least_loaded_block(&blocks) else {
+        // the client language isn't known before login
+        let error = blocks.first().map_or_else(String::new, |b| {
+            b.server_data
+                .load()
+                .messages
+                .format("server_full", login::Language::English, &[])
+        });
+        drop(blocks);
+        log::debug!("All blocks are full");
+        con.write_packet_async(&Packet::LoginResponse(login::LoginResponsePacket {
+            status: login::LoginStatus::Failure,
+            error,
+            ..Default::default()
+        }))
+        .await?;
+        return Ok(());
+    };
     let packet = login::BlockBalancePacket {
         ip: block.ip,
         port: block.port,
-        blockname: block.name.clone().into(),
+        blockname: block.block_type.display_name(&block.name).into(),
         ..Default::default()
     };
     con.write_packet_async(&Packet::BlockBalance(packet))
         .await?;
     Ok(())
 }
+
+/// Selects a block with free slots and the lowest weighted load, preferred blocks first.
+/// GM-only blocks are never selected.
+fn least_loaded_block(blocks: &[BlockInfo]) -> Option<&BlockInfo> {
+    let load = |b: &BlockInfo| b.players as f32 / (b.max_players as f32 * b.balance_weight);
+    let candidates = blocks
+        .iter()
+        .filter(|b| b.players < b.max_players && b.balance_weight > 0.0)
+        .filter(|b| b.block_type != BlockType::GmOnly);
+    let has_preferred = candidates.clone().any(|b| b.preferred);
+    candidates
+        .filter(|b| !has_preferred || b.preferred)
+        .min_by(|a, b| load(a).total_cmp(&load(b)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        capture, least_loaded_block, master_conn::MasterConnection, mutex::Mutex, quests::Quests,
+        settings::BlockType, sql::Sql, BlockInfo, Error, SharedData,
+    };
+    use arc_swap::ArcSwap;
+    use data_structs::{
+        master_ship::{MasterShipAction as MAS, ServerDataResult},
+        ServerData,
+    };
+    use std::{
+        net::Ipv4Addr,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+
+    fn block(id: u32, players: u32, max_players: u32) -> BlockInfo {
+        BlockInfo {
+            id,
+            name: format!("Block {id}"),
+            ip: Ipv4Addr::UNSPECIFIED,
+            port: 0,
+            max_players,
+            players,
+            lobby_map: String::new(),
+            balance_weight: 1.0,
+            preferred: false,
+            block_type: BlockType::Normal,
+            autosave_interval: None,
+            reconnect_grace: None,
+            rate_limits: Default::default(),
+            validation: Default::default(),
+            lua_limits: Default::default(),
+            lua_reloader: None,
+            plugins: Default::default(),
+            capture_all: false,
+            captures: Arc::new(capture::Captures::new(String::new(), vec![])),
+            mutes: Default::default(),
+            chat_log: Default::default(),
+            server_data: Default::default(),
+            quests: Arc::new(ArcSwap::from_pointee(Quests::load(vec![]))),
+            suspended: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    #[test]
+    fn test_block_balance() {
+        let mut blocks = vec![block(1, 10, 32), block(2, 2, 32), block(3, 4, 8)];
+        assert_eq!(least_loaded_block(&blocks).unwrap().id, 2);
+
+        blocks[0].balance_weight = 10.0;
+        assert_eq!(least_loaded_block(&blocks).unwrap().id, 1);
+
+        blocks[2].preferred = true;
+        assert_eq!(least_loaded_block(&blocks).unwrap().id, 3);
+
+        blocks[2].players = 8;
+        assert_eq!(least_loaded_block(&blocks).unwrap().id, 1);
+
+        for block in blocks.iter_mut() {
+            block.players = block.max_players;
+        }
+        assert!(least_loaded_block(&blocks).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_data_reload() {
+        let has_lobby = Arc::new(AtomicBool::new(true));
+        let master_lobby = has_lobby.clone();
+        let master = MasterConnection::fake(move |action| match action {
+            MAS::ServerDataRequest => {
+                let mut data = ServerData::default();
+                data.maps.insert("other".into(), Default::default());
+                if master_lobby.load(Ordering::Relaxed) {
+                    data.maps.insert("lobby".into(), Default::default());
+                }
+                MAS::ServerDataResponse(ServerDataResult::Ok(Box::new(data)))
+            }
+            _ => MAS::Error("unexpected".into()),
+        });
+        let sql = Sql::in_memory(master).await.unwrap();
+
+        let shared = SharedData::load(None, vec!["lobby".into()], &sql)
+            .await
+            .expect("Initial load failed");
+        let old = shared.server_data.load_full();
+        shared.reload(&sql).await.expect("Reload failed");
+        assert!(!Arc::ptr_eq(&old, &shared.server_data.load_full()));
+
+        // data without the lobby map is rejected and the current data is kept
+        has_lobby.store(false, Ordering::Relaxed);
+        let current = shared.server_data.load_full();
+        let result = shared.reload(&sql).await;
+        assert!(matches!(result, Err(Error::NoMapFound(map)) if map == "lobby"));
+        assert!(Arc::ptr_eq(&current, &shared.server_data.load_full()));
+
+        // unreadable data files are rejected as well
+        let shared = SharedData {
+            data_file: Some("missing/server_data.mp".into()),
+            ..shared
+        };
+        assert!(shared.reload(&sql).await.is_err());
+        assert!(Arc::ptr_eq(&current, &shared.server_data.load_full()));
+    }
+}
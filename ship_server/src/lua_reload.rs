@@ -0,0 +1,289 @@
+use crate::{map::Map, mutex::Mutex, quests::Quests, Error};
+use arc_swap::ArcSwap;
+use data_structs::{quest::QuestData, SerDeFile as _, ServerData};
+use notify::{RecursiveMode, Watcher};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+/// Editors often write a file in several steps, so changes are collected for a while.
+const RELOAD_DELAY: Duration = Duration::from_millis(200);
+
+type Luas = HashMap<String, String>;
+
+/// Where the scripts of a map come from in the data directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptSource {
+    /// Map in the `maps` folder
+    Map(String),
+    /// Map of the quest with this name ID
+    Quest(u32),
+}
+
+#[derive(Default)]
+struct Scripts {
+    maps: HashMap<String, Luas>,
+    quests: HashMap<u32, Luas>,
+}
+
+/// Developer mode reloader of the Lua scripts in the uncompiled data directory.
+pub struct LuaReloader {
+    data_dir: PathBuf,
+    server_data: Arc<ArcSwap<ServerData>>,
+    quests: Arc<ArcSwap<Quests>>,
+    maps: Mutex<Vec<(ScriptSource, Weak<Mutex<Map>>)>>,
+}
+
+impl LuaReloader {
+    pub fn new(
+        data_dir: impl Into<PathBuf>,
+        server_data: Arc<ArcSwap<ServerData>>,
+        quests: Arc<ArcSwap<Quests>>,
+    ) -> Self {
+        Self {
+            data_dir: data_dir.into(),
+            server_data,
+            quests,
+            maps: Mutex::new(vec![]),
+        }
+    }
+    /// Adds a running map whose scripts should be reloaded.
+    pub async fn register(&self, source: ScriptSource, map: &Arc<Mutex<Map>>) {
+        let mut maps = self.maps.lock().await;
+        maps.retain(|(_, m)| m.strong_count() != 0);
+        maps.push((source, Arc::downgrade(map)));
+    }
+    /// Starts watching the `luas` folders of the data directory.
+    pub fn spawn_watcher(self: Arc<Self>) -> Result<(), Error> {
+        let (send, mut recv) = tokio::sync::mpsc::unbounded_channel();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+                Ok(event) if is_change(&event) && event.paths.iter().any(|p| is_script(p)) => {
+                    let _ = send.send(());
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("Lua watcher error: {e}"),
+            })?;
+        watcher.watch(&self.data_dir, RecursiveMode::Recursive)?;
+        log::info!("Watching Lua scripts in {}", self.data_dir.display());
+        tokio::spawn(async move {
+            // the watcher stops when dropped
+            let _watcher = watcher;
+            while recv.recv().await.is_some() {
+                tokio::time::sleep(RELOAD_DELAY).await;
+                while recv.try_recv().is_ok() {}
+                match self.reload().await {
+                    Ok(count) => log::info!("Reloaded Lua scripts of {count} running maps"),
+                    Err(e) => log::error!("Failed to reload Lua scripts: {e}"),
+                }
+            }
+        });
+        Ok(())
+    }
+    /// Re-reads every script into the server data and the running maps. Returns the number of
+    /// updated maps.
+    pub async fn reload(&self) -> Result<usize, Error> {
+        let data_dir = self.data_dir.clone();
+        let scripts = tokio::task::spawn_blocking(move || collect_scripts(&data_dir)).await??;
+
+        // new maps
+        let mut server_data = ServerData::clone(&self.server_data.load());
+        for (name, map) in server_data.maps.iter_mut() {
+            if let Some(luas) = scripts.maps.get(name) {
+                map.luas = luas.clone();
+            }
+        }
+        self.server_data.store(Arc::new(server_data));
+        let mut quests = Quests::clone(&self.quests.load());
+        for (&name_id, luas) in scripts.quests.iter() {
+            quests.set_luas(name_id, luas.clone());
+        }
+        self.quests.store(Arc::new(quests));
+
+        // running maps
+        let maps: Vec<_> = {
+            let mut maps = self.maps.lock().await;
+            maps.retain(|(_, m)| m.strong_count() != 0);
+            maps.iter()
+                .filter_map(|(source, map)| Some((source.clone(), map.upgrade()?)))
+                .collect()
+        };
+        let mut count = 0;
+        for (source, map) in maps {
+            let luas = match &source {
+                ScriptSource::Map(name) => scripts.maps.get(name),
+                ScriptSource::Quest(name_id) => scripts.quests.get(name_id),
+            };
+            if let Some(luas) = luas {
+                map.lock().await.reload_luas(luas.clone())?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// Reading the scripts also creates events, so only changes are accepted.
+fn is_change(event: &notify::Event) -> bool {
+    event.kind.is_create() || event.kind.is_modify() || event.kind.is_remove()
+}
+
+fn is_script(path: &Path) -> bool {
+    path.components().any(|c| c.as_os_str() == "luas")
+}
+
+/// Reads the scripts of every map and quest the same way as `data_compiler`.
+fn collect_scripts(data_dir: &Path) -> Result<Scripts, Error> {
+    let mut scripts = Scripts::default();
+    find_data_dirs(&data_dir.join("maps"), &mut |path, _| {
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        scripts.maps.insert(name, read_luas(&path.join("luas"))?);
+        Ok(())
+    })?;
+    find_data_dirs(&data_dir.join("quests"), &mut |path, data_file| {
+        let quest = QuestData::load_file(data_file)?;
+        let luas = read_luas(&path.join("map").join("luas"))?;
+        scripts.quests.insert(quest.definition.name_id, luas);
+        Ok(())
+    })?;
+    Ok(scripts)
+}
+
+fn find_data_dirs<F>(path: &Path, callback: &mut F) -> Result<(), Error>
+where
+    F: FnMut(&Path, &Path) -> Result<(), Error>,
+{
+    if !path.is_dir() {
+        return Ok(());
+    }
+    for ext in ["json", "toml"] {
+        let data_file = path.join("data").with_extension(ext);
+        if data_file.is_file() {
+            return callback(path, &data_file);
+        }
+    }
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?.path();
+        if entry.is_dir() {
+            find_data_dirs(&entry, callback)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn read_luas(path: &Path) -> Result<Luas, Error> {
+    let mut luas = Luas::new();
+    if !path.is_dir() {
+        return Ok(luas);
+    }
+    let mut dirs = vec![path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?.path();
+            if entry.is_dir() {
+                dirs.push(entry);
+            } else if entry.extension().is_some_and(|e| e == "lua") {
+                let name = entry
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string();
+                luas.insert(name, std::fs::read_to_string(&entry)?);
+            }
+        }
+    }
+    Ok(luas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{collect_scripts, is_script, read_luas, LuaReloader};
+    use crate::quests::Quests;
+    use arc_swap::ArcSwap;
+    use data_structs::{map::MapData, quest::QuestData, ServerData};
+    use std::{fs, path::Path, sync::Arc};
+
+    fn write(path: &Path, data: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+
+    /// Creates a data directory with a map and a quest.
+    fn data_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("lua_reload_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let map = dir.join("maps/test_map");
+        write(&map.join("data.json"), "{}");
+        write(&map.join("luas/on_player_load.lua"), "map_load()");
+        write(&map.join("luas/objects/switch.lua"), "switch()");
+        write(&map.join("luas/notes.txt"), "not a script");
+
+        let mut quest = QuestData::default();
+        quest.definition.name_id = 1234;
+        let quest_dir = dir.join("quests/category/test_quest");
+        write(
+            &quest_dir.join("data.json"),
+            &serde_json::to_string(&quest).unwrap(),
+        );
+        write(&quest_dir.join("map/luas/on_questwork.lua"), "questwork()");
+        dir
+    }
+
+    #[test]
+    fn test_read_luas() {
+        let dir = data_dir("read");
+        let luas = read_luas(&dir.join("maps/test_map/luas")).unwrap();
+        assert_eq!(luas.len(), 2);
+        assert_eq!(luas["on_player_load"], "map_load()");
+        assert_eq!(luas["switch"], "switch()");
+        assert!(read_luas(&dir.join("missing")).unwrap().is_empty());
+
+        assert!(is_script(&dir.join("maps/test_map/luas/switch.lua")));
+        assert!(!is_script(&dir.join("maps/test_map/data.json")));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_collect_scripts() {
+        let dir = data_dir("collect");
+        let scripts = collect_scripts(&dir).unwrap();
+        assert_eq!(scripts.maps.len(), 1);
+        assert_eq!(scripts.maps["test_map"]["on_player_load"], "map_load()");
+        assert_eq!(scripts.quests.len(), 1);
+        assert_eq!(scripts.quests[&1234]["on_questwork"], "questwork()");
+
+        // a missing data directory has no scripts
+        let scripts = collect_scripts(&dir.join("missing")).unwrap();
+        assert!(scripts.maps.is_empty() && scripts.quests.is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let dir = data_dir("reload");
+        let mut server_data = ServerData::default();
+        server_data
+            .maps
+            .insert("test_map".into(), MapData::default());
+        let server_data = Arc::new(ArcSwap::from_pointee(server_data));
+        let mut quest = QuestData::default();
+        quest.definition.name_id = 1234;
+        let quests = Arc::new(ArcSwap::from_pointee(Quests::load(vec![quest])));
+
+        let reloader = LuaReloader::new(&dir, server_data.clone(), quests.clone());
+        // no maps are running
+        assert_eq!(reloader.reload().await.unwrap(), 0);
+        assert_eq!(server_data.load().maps["test_map"].luas.len(), 2);
+        let quests = quests.load();
+        let quest = quests.get_quest_by_nameid(1234).unwrap();
+        assert_eq!(quest.map.luas["on_questwork"], "questwork()");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
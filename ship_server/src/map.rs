@@ -1,10 +1,13 @@
 use crate::{
     battle_stats::{BattleResult, EnemyStats},
     mutex::{Mutex, MutexGuard},
+    plugins::{Event, Plugins},
+    script_store::{self, ScriptValue},
+    settings::LuaLimitSettings,
     BlockData, Error, User,
 };
 use data_structs::map::MapData;
-use mlua::{Lua, LuaSerdeExt, StdLib};
+use mlua::{HookTriggers, Lua, LuaSerdeExt, StdLib, VmState};
 use pso2packetlib::protocol::{
     self,
     flag::{CutsceneEndPacket, SkitItemAddRequestPacket},
@@ -18,16 +21,29 @@ use pso2packetlib::protocol::{
 };
 use rand::{prelude::Distribution, seq::IteratorRandom};
 use std::{
+    cell::RefCell,
+    collections::HashMap,
     sync::{
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicU32, AtomicU64, Ordering},
         Arc, Weak,
     },
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 type ZoneId = u32;
 type PlayerId = u32;
 
+/// Number of instructions between the checks of the instruction budget.
+const LUA_HOOK_INTERVAL: u32 = 1000;
+/// Maximum number of active Lua timers in a single map.
+const MAX_LUA_TIMERS: usize = 64;
+/// Shortest delay of a Lua timer.
+const MIN_TIMER_DELAY: Duration = Duration::from_millis(100);
+/// Longest delay of a Lua timer.
+const MAX_TIMER_DELAY: Duration = Duration::from_secs(24 * 3600);
+/// Registry key of the table with the state of the map instance.
+const MAP_STORE: &str = "map_store";
+
 #[derive(Clone)]
 struct MapPlayer {
     player_id: PlayerId,
@@ -44,6 +60,55 @@ struct OwnedMapPlayer {
     user: Arc<Mutex<User>>,
 }
 
+/// Code executed by `run_lua`.
+enum LuaCode<'a> {
+    /// Source of a script.
+    Chunk(&'a str),
+    /// Callback of a timer.
+    Function(mlua::Function),
+}
+
+/// Timer created by `set_timeout` or `set_interval`.
+struct LuaTimer {
+    id: u32,
+    /// Script that created the timer.
+    script: String,
+    sender_id: PlayerId,
+    zone_id: ZoneId,
+    repeat: bool,
+    callback: mlua::RegistryKey,
+    task: tokio::task::AbortHandle,
+}
+
+impl Drop for LuaTimer {
+    fn drop(&mut self) {
+        // a running timer removing itself ends after the callback
+        if tokio::task::try_id() != Some(self.task.id()) {
+            self.task.abort();
+        }
+    }
+}
+
+enum TimerRequest {
+    Add {
+        id: u32,
+        delay: Duration,
+        repeat: bool,
+        callback: mlua::RegistryKey,
+    },
+    Clear(u32),
+}
+
+/// Changes requested by a script, applied after it finishes.
+#[derive(Default)]
+struct LuaRequests {
+    moves: Vec<(PlayerId, String)>,
+    lobby_moves: Vec<PlayerId>,
+    timers: Vec<TimerRequest>,
+    spawns: Vec<(u32, ZoneId, EnemyStats)>,
+    despawns: Vec<u32>,
+}
+
 pub enum MapType {
     Lobby,
     QuestMap,
@@ -53,12 +118,19 @@ pub struct Map {
     // lua is not `Send` so i've put it in a mutex
     // this mutex shouldn't block, because `Map` is under a mutex itself.
     lua: parking_lot::Mutex<Lua>,
+    lua_limits: Arc<LuaLimitSettings>,
+    timers: Vec<LuaTimer>,
+    latest_timer_id: AtomicU32,
+    /// Handle of this map, used by the timers.
+    this: Weak<Mutex<Map>>,
     map_objs: Vec<(ZoneId, ObjectHeader)>,
     data: MapData,
     players: Vec<MapPlayer>,
     // fighting with async recursion
     to_move: Vec<(PlayerId, String)>,
     to_lobby_move: Vec<PlayerId>,
+    to_spawn: Vec<(u32, ZoneId, EnemyStats)>,
+    to_despawn: Vec<u32>,
     max_id: u32,
     block_data: Option<Arc<BlockData>>,
     enemies: Vec<(u32, ZoneId, EnemyStats)>,
@@ -72,11 +144,17 @@ impl Map {
         let lua_libs = StdLib::NONE;
         let mut map = Self {
             lua: Lua::new_with(lua_libs, mlua::LuaOptions::default())?.into(),
+            lua_limits: Default::default(),
+            timers: vec![],
+            latest_timer_id: AtomicU32::new(0),
+            this: Weak::new(),
             map_objs: vec![],
             data,
             players: vec![],
             to_move: vec![],
             to_lobby_move: vec![],
+            to_spawn: vec![],
+            to_despawn: vec![],
             max_id: 0,
             block_data: None,
             enemies: vec![],
@@ -103,17 +181,41 @@ impl Map {
             ))
         }
         map.init_lua()?;
+        {
+            let lua = map.lua.get_mut();
+            lua.set_named_registry_value(MAP_STORE, lua.create_table()?)?;
+        }
         map.find_max_id();
         log::trace!("Map {} created", map_obj.id);
         Ok(map)
     }
-    pub fn set_map_type(&mut self, map_type: MapType) {
+    pub const fn set_map_type(&mut self, map_type: MapType) {
         self.map_type = map_type;
     }
     pub fn set_block_data(&mut self, data: Arc<BlockData>) {
+        let memory = data.lua_limits.memory;
+        if memory != 0 {
+            if let Err(e) = self.lua.get_mut().set_memory_limit(memory) {
+                log::warn!(
+                    "Couldn't set Lua memory limit of map {}: {e}",
+                    self.data.map_data.map_object.id
+                );
+            }
+        }
+        self.lua_limits = data.lua_limits.clone();
         self.block_data = Some(data);
     }
-    pub fn set_enemy_level(&mut self, level: u32) {
+    /// Sets the handle of this map, required for Lua timers.
+    pub fn set_handle(&mut self, this: &Arc<Mutex<Self>>) {
+        self.this = Arc::downgrade(this);
+    }
+    /// Replaces the Lua scripts of the map, the next events run the new code. Running timers
+    /// keep their callbacks.
+    pub fn reload_luas(&mut self, luas: HashMap<String, String>) -> Result<(), Error> {
+        self.data.luas = luas;
+        self.init_lua()
+    }
+    pub const fn set_enemy_level(&mut self, level: u32) {
         self.enemy_level = level;
     }
     fn find_max_id(&mut self) {
@@ -204,7 +306,39 @@ impl Map {
             .send_packet(&Packet::LoadLevel(self.data.map_data.clone()))
             .await?;
         drop(np_lock);
-        self.add_player(new_player, self.data.init_map).await
+        self.add_player(new_player, self.data.init_map, None).await
+    }
+    /// Replaces the player of a resumed session, keeping the zone and the position. Also adds
+    /// players coming from other maps at the given place.
+    pub async fn reattach_player(
+        &mut self,
+        player: Arc<Mutex<User>>,
+        zone_id: ZoneId,
+        position: Position,
+    ) -> Result<(), Error> {
+        let mut lock = player.lock().await;
+        self.remove_player(lock.get_user_id()).await;
+        lock.send_packet(&Packet::LoadLevel(self.data.map_data.clone()))
+            .await?;
+        // the level is loaded in the initial zone, so the player has to be moved back
+        let zone = self.data.zones.iter().find(|z| z.zone_id == zone_id);
+        let map_obj = self.map_objs.iter().find(|(m, _)| *m == zone_id);
+        let (zone_id, position) = match (zone, map_obj) {
+            _ if zone_id == self.data.init_map => (zone_id, Some(position)),
+            (Some(zone), Some((_, map_obj))) => {
+                let target = lock.create_object_header();
+                lock.send_packet(&Packet::MapTransfer(MapTransferPacket {
+                    map: *map_obj,
+                    target,
+                    settings: zone.settings.clone(),
+                }))
+                .await?;
+                (zone_id, Some(position))
+            }
+            _ => (self.data.init_map, None),
+        };
+        drop(lock);
+        self.add_player(player, zone_id, position).await
     }
     pub async fn move_player_named(&mut self, id: PlayerId, name: &str) -> Result<(), Error> {
         let Some(zone) = self.data.zones.iter().find(|z| z.name == name) else {
@@ -213,6 +347,15 @@ impl Map {
         self.move_player(id, zone.zone_id).await
     }
     pub async fn move_player(&mut self, id: PlayerId, zone_id: ZoneId) -> Result<(), Error> {
+        self.move_player_to(id, zone_id, None).await
+    }
+    /// Moves the player to the position in the zone (or to the default location).
+    pub async fn move_player_to(
+        &mut self,
+        id: PlayerId,
+        zone_id: ZoneId,
+        position: Option<Position>,
+    ) -> Result<(), Error> {
         let Some(player) = self.remove_player(id).await else {
             return Err(Error::NoUserInMap(id, self.data.map_data.unk7.to_string()));
         };
@@ -241,7 +384,7 @@ impl Map {
         }))
         .await?;
         drop(lock);
-        self.add_player(player, map.zone_id).await
+        self.add_player(player, map.zone_id, position).await
     }
     pub async fn move_to_lobby(&mut self, id: PlayerId) -> Result<(), Error> {
         if matches!(self.map_type, MapType::Lobby) {
@@ -260,6 +403,7 @@ impl Map {
         &mut self,
         new_player: Arc<Mutex<User>>,
         zone_id: ZoneId,
+        position: Option<Position>,
     ) -> Result<(), Error> {
         let mut other_equipment = Vec::with_capacity(self.players.len() * 2);
         let mut other_characters = Vec::with_capacity(self.players.len());
@@ -277,7 +421,7 @@ impl Map {
             other_equipment.push(char_data.palette.send_change_palette(pid));
             other_equipment.push(char_data.palette.send_cur_weapon(pid, &char_data.inventory));
             other_equipment.push(char_data.inventory.send_equiped(pid));
-            other_characters.push((char_data.character.clone(), p.position, p.user_data.isgm));
+            other_characters.push((char_data.character.clone(), p.position, p.user_data.is_gm()));
         }
         let mut np_lock = new_player.lock().await;
         np_lock.zone_id = zone_id;
@@ -294,15 +438,16 @@ impl Map {
                 ..Default::default()
             }))
             .await?;
-        let pos = self
-            .data
-            .zones
-            .iter()
-            .find(|z| z.zone_id == zone_id)
-            .map(|z| z.default_location)
-            .unwrap_or_default();
+        let pos = position.unwrap_or_else(|| {
+            self.data
+                .zones
+                .iter()
+                .find(|z| z.zone_id == zone_id)
+                .map(|z| z.default_location)
+                .unwrap_or_default()
+        });
         np_lock.position = pos;
-        let np_gm = np_lock.user_data.isgm as u32;
+        let np_gm = np_lock.user_data.is_gm() as u32;
         np_lock
             .spawn_character(CharacterSpawnPacket {
                 position: pos,
@@ -317,7 +462,13 @@ impl Map {
                 ..Default::default()
             })
             .await?;
-        Self::load_objects(&self.lua, &self.data, zone_id, &mut np_lock)?;
+        Self::load_objects(
+            &self.lua,
+            &self.lua_limits,
+            &self.data,
+            zone_id,
+            &mut np_lock,
+        )?;
         for (character, position, isgm) in other_characters {
             let player_id = character.player_id;
             np_lock
@@ -392,7 +543,9 @@ impl Map {
         let Some(lua) = self.data.luas.get("on_player_load").cloned() else {
             return Ok(());
         };
-        self.run_lua(np_id, zone_id, &Packet::None, "on_player_load", &lua)
+        let call_type = "on_player_load";
+        let code = LuaCode::Chunk(&lua);
+        self.run_lua(np_id, zone_id, &Packet::None, call_type, call_type, code)
             .await?;
         Ok(())
     }
@@ -582,14 +735,12 @@ impl Map {
         };
         let id = self.max_id + 1;
         self.max_id += 1;
-        let data = EnemyStats::build(name, self.enemy_level, pos, &block_data.server_data)?;
-        let map_id = self
-            .data
-            .zones
-            .iter()
-            .find(|z| z.zone_id == zone_id)
-            .map(|z| z.settings.map_id)
-            .unwrap();
+        let data = EnemyStats::build(name, self.enemy_level, pos, &block_data.server_data.load())?;
+        self.add_enemy(id, zone_id, data).await;
+        Ok(())
+    }
+    async fn add_enemy(&mut self, id: u32, zone_id: ZoneId, data: EnemyStats) {
+        let map_id = self.zone_map_id(zone_id);
         let (packet, mut packet2) = Self::prepare_enemy_packets(id, map_id, &data);
         self.enemies.push((id, zone_id, data));
 
@@ -602,8 +753,35 @@ impl Map {
             }
         })
         .await;
-
-        Ok(())
+    }
+    /// Removes the enemy from the map without killing it.
+    async fn despawn_enemy(&mut self, id: u32) {
+        let Some(pos) = self.enemies.iter().position(|(e, _, _)| *e == id) else {
+            return;
+        };
+        let (_, zone_id, _) = self.enemies.remove(pos);
+        let enemy = ObjectHeader {
+            id,
+            entity_type: ObjectType::Object,
+            map_id: self.zone_map_id(zone_id) as _,
+            ..Default::default()
+        };
+        exec_users(&self.players, zone_id, |_, mut player| {
+            let packet = Packet::DespawnObject(protocol::objects::DespawnObjectPacket {
+                player: player.create_object_header(),
+                item: enemy,
+            });
+            let _ = player.try_send_packet(&packet);
+        })
+        .await;
+    }
+    fn zone_map_id(&self, zone_id: ZoneId) -> u32 {
+        self.data
+            .zones
+            .iter()
+            .find(|z| z.zone_id == zone_id)
+            .map(|z| z.settings.map_id)
+            .unwrap()
     }
     fn prepare_enemy_packets(enemy_id: u32, map_id: u32, enemy: &EnemyStats) -> (Packet, Packet) {
         let packet = enemy.create_spawn_packet(enemy_id, map_id as _);
@@ -621,8 +799,36 @@ impl Map {
             return Err(Error::InvalidInput("deal_damage"));
         };
         let (inflicter, target) = (dmg.inflicter, dmg.target);
+        let is_pvp = inflicter.entity_type == ObjectType::Player
+            && target.entity_type == ObjectType::Player
+            && inflicter.id != target.id;
+        if is_pvp && !block_data.block_type.is_pvp_allowed() {
+            return Ok(());
+        }
+        if is_pvp {
+            // PvP damage isn't applied yet, but modified clients are still reported
+            let Some(target_zone) = self
+                .players
+                .iter()
+                .find(|p| p.player_id == target.id)
+                .map(|p| p.zone_id)
+            else {
+                return Ok(());
+            };
+            let Some(inflicter) = self
+                .players
+                .iter()
+                .find(|u| u.player_id == inflicter.id)
+                .and_then(|p| p.user.upgrade())
+            else {
+                return Err(Error::InvalidInput("deal_damage"));
+            };
+            inflicter.lock().await.validate_damage(&dmg, target_zone);
+            return Ok(());
+        }
         if inflicter.entity_type == ObjectType::Player && target.entity_type == ObjectType::Object {
-            let Some((pos, (_, _, target))) = self
+            let killer_id = inflicter.id;
+            let Some((pos, (_, target_zone, target))) = self
                 .enemies
                 .iter_mut()
                 .enumerate()
@@ -639,10 +845,13 @@ impl Map {
                 return Err(Error::InvalidInput("deal_damage"));
             };
             let mut lock = inflicter.lock().await;
+            if !lock.validate_damage(&dmg, *target_zone) {
+                return Ok(());
+            }
             let zone_id = lock.get_zone_id();
-            let result = lock
-                .get_stats_mut()
-                .damage_enemy(target, &block_data.server_data, dmg)?;
+            let result =
+                lock.get_stats_mut()
+                    .damage_enemy(target, &block_data.server_data.load(), dmg)?;
             drop(lock);
             match result {
                 BattleResult::Damaged { dmg_packet } => {
@@ -673,7 +882,11 @@ impl Map {
                         exp_packets.push(player.add_exp(exp_amount))
                     })
                     .await;
-                    let exp_packets = exp_packets.into_iter().collect::<Result<Vec<_>, _>>()?;
+                    let (exp_packets, level_ups): (Vec<_>, Vec<_>) = exp_packets
+                        .into_iter()
+                        .collect::<Result<Vec<_>, _>>()?
+                        .into_iter()
+                        .unzip();
                     let mut exp_packet = Packet::GainedEXP(GainedEXPPacket {
                         receivers: exp_packets,
                         ..Default::default()
@@ -697,7 +910,15 @@ impl Map {
                         }
                     })
                     .await;
-                    self.enemies.remove(pos);
+                    let (_, _, enemy) = self.enemies.remove(pos);
+                    let event = Event::EnemyKill {
+                        player: killer_id,
+                        enemy: enemy.get_name().to_string(),
+                    };
+                    Plugins::dispatch(&block_data, event);
+                    for event in level_ups.into_iter().flatten() {
+                        Plugins::dispatch(&block_data, event);
+                    }
                 }
             }
         } else if inflicter.entity_type == ObjectType::Object
@@ -720,8 +941,11 @@ impl Map {
             };
             let mut lock = target.lock().await;
             let zone_id = lock.get_zone_id();
-            let result =
-                inflicter.damage_player(lock.get_stats_mut(), &block_data.server_data, dmg)?;
+            let result = inflicter.damage_player(
+                lock.get_stats_mut(),
+                &block_data.server_data.load(),
+                dmg,
+            )?;
             drop(lock);
 
             match result {
@@ -753,6 +977,7 @@ impl Map {
     }
     fn load_objects(
         lua: &parking_lot::Mutex<Lua>,
+        limits: &LuaLimitSettings,
         map_data: &MapData,
         zone_id: ZoneId,
         user: &mut User,
@@ -765,18 +990,19 @@ impl Map {
             .cloned()
         {
             if user.user_data.packet_type == PacketType::Vita {
-                let lua_code = map_data
-                    .luas
-                    .get(obj.data.name.as_str())
-                    .map(|s| s.as_str())
-                    .unwrap_or("");
+                let script = obj.data.name.as_str();
+                let lua_code = map_data.luas.get(script).map(|s| s.as_str()).unwrap_or("");
                 let globals = lua.globals();
                 globals.set("data", obj.data.data.as_slice())?;
                 globals.set("call_type", "to_vita")?;
                 globals.set("size", obj.data.data.len())?;
-                let chunk = lua.load(lua_code);
-                chunk.exec()?;
-                obj.data.data = globals.get::<Vec<u32>>("data")?.into();
+                match exec_limited(&lua, limits, || lua.load(lua_code).set_name(script).exec()) {
+                    Ok(()) => obj.data.data = globals.get::<Vec<u32>>("data")?.into(),
+                    Err(e) if is_limit_violation(&e) => {
+                        log_limit_violation(&lua, script, "to_vita", &e)
+                    }
+                    Err(e) => return Err(e.into()),
+                }
                 globals.raw_remove("data")?;
                 globals.raw_remove("call_type")?;
                 globals.raw_remove("size")?;
@@ -923,7 +1149,9 @@ impl Map {
                 }
                 data_structs::map::EnemySpawnType::Manual => {
                     if let Some(lua) = self.data.luas.get("spawn_enemy").cloned() {
-                        self.run_lua(user.player_id, zone_id, &packet, "spawn_enemy", &lua)
+                        let call_type = "spawn_enemy";
+                        let code = LuaCode::Chunk(&lua);
+                        self.run_lua(user.player_id, zone_id, &packet, call_type, call_type, code)
                             .await?;
                     };
                 }
@@ -931,16 +1159,11 @@ impl Map {
         }
 
         if let Some(lua) = self.data.luas.get("on_minimap_reveal").cloned() {
-            self.run_lua(user.player_id, zone_id, &packet, "on_minimap_reveal", &lua)
+            let call_type = "on_minimap_reveal";
+            let code = LuaCode::Chunk(&lua);
+            self.run_lua(user.player_id, zone_id, &packet, call_type, call_type, code)
                 .await?;
-            let to_move: Vec<_> = self.to_move.drain(..).collect();
-            for (player, zone) in to_move {
-                self.move_player_named(player, &zone).await?;
-            }
-            let to_move: Vec<_> = self.to_lobby_move.drain(..).collect();
-            for player in to_move {
-                self.move_to_lobby(player).await?;
-            }
+            self.apply_moves().await?;
         };
         Ok(())
     }
@@ -957,7 +1180,7 @@ impl Map {
             ));
         };
         let zone_id = user.zone_id;
-        let Some(lua_data) = self
+        let Some((name, lua_data)) = self
             .data
             .objects
             .iter()
@@ -971,13 +1194,15 @@ impl Map {
                     .map(|x| (x.data.object.id, &x.data.name)),
             )
             .find(|(id, _)| *id == packet.object1.id)
-            .and_then(|(_, name)| self.data.luas.get(name.as_str()))
+            .and_then(|(_, name)| Some((name, self.data.luas.get(name.as_str())?)))
         else {
             return Ok(());
         };
-        let lua_data = lua_data.clone();
-        self.run_lua(sender_id, zone_id, &packet, "interaction", &lua_data)
+        let (name, lua_data) = (name.clone(), lua_data.clone());
+        let code = LuaCode::Chunk(&lua_data);
+        self.run_lua(sender_id, zone_id, &packet, "interaction", &name, code)
             .await?;
+        self.apply_moves().await?;
         Ok(())
     }
     pub async fn on_questwork(
@@ -992,15 +1217,14 @@ impl Map {
             ));
         };
         let zone_id = user.zone_id;
-        let Some(lua) = self.data.luas.get("on_questwork").cloned() else {
+        let call_type = "on_questwork";
+        let Some(lua) = self.data.luas.get(call_type).cloned() else {
             return Ok(());
         };
-        self.run_lua(player, zone_id, &packet, "on_questwork", &lua)
+        let code = LuaCode::Chunk(&lua);
+        self.run_lua(player, zone_id, &packet, call_type, call_type, code)
             .await?;
-        let to_move: Vec<_> = self.to_move.drain(..).collect();
-        for (player, zone) in to_move {
-            self.move_player_named(player, &zone).await?;
-        }
+        self.apply_moves().await?;
         Ok(())
     }
     pub async fn on_cutscene_end(
@@ -1015,19 +1239,14 @@ impl Map {
             ));
         };
         let zone_id = user.zone_id;
-        let Some(lua) = self.data.luas.get("on_cutscene_end").cloned() else {
+        let call_type = "on_cutscene_end";
+        let Some(lua) = self.data.luas.get(call_type).cloned() else {
             return Ok(());
         };
-        self.run_lua(player, zone_id, &packet, "on_cutscene_end", &lua)
+        let code = LuaCode::Chunk(&lua);
+        self.run_lua(player, zone_id, &packet, call_type, call_type, code)
             .await?;
-        let to_move: Vec<_> = self.to_move.drain(..).collect();
-        for (player, zone) in to_move {
-            self.move_player_named(player, &zone).await?;
-        }
-        let to_move: Vec<_> = self.to_lobby_move.drain(..).collect();
-        for player in to_move {
-            self.move_to_lobby(player).await?;
-        }
+        self.apply_moves().await?;
         Ok(())
     }
 
@@ -1039,11 +1258,18 @@ impl Map {
             ));
         };
         let zone_id = user.zone_id;
-        let Some(lua) = self.data.luas.get("on_map_loaded").cloned() else {
+        let call_type = "on_map_loaded";
+        let Some(lua) = self.data.luas.get(call_type).cloned() else {
             return Ok(());
         };
-        self.run_lua(player, zone_id, &Packet::None, "on_map_loaded", &lua)
+        let code = LuaCode::Chunk(&lua);
+        self.run_lua(player, zone_id, &Packet::None, call_type, call_type, code)
             .await?;
+        self.apply_moves().await?;
+        Ok(())
+    }
+    /// Moves the players requested by the last script.
+    async fn apply_moves(&mut self) -> Result<(), Error> {
         let to_move: Vec<_> = self.to_move.drain(..).collect();
         for (player, zone) in to_move {
             self.move_player_named(player, &zone).await?;
@@ -1054,6 +1280,18 @@ impl Map {
         }
         Ok(())
     }
+    pub fn get_zone_name(&self, zone_id: ZoneId) -> Option<&str> {
+        self.data
+            .zones
+            .iter()
+            .find(|z| z.zone_id == zone_id)
+            .map(|z| z.name.as_str())
+    }
+    /// Returns the zone name of a map object, e.g. of the target of a `MapTransfer` packet.
+    pub fn get_object_zone_name(&self, map_obj_id: u32) -> Option<&str> {
+        let (zone_id, _) = self.map_objs.iter().find(|(_, o)| o.id == map_obj_id)?;
+        self.get_zone_name(*zone_id)
+    }
     pub fn get_close_objects<F>(&self, zone_id: ZoneId, pred: F) -> Vec<ObjectSpawnPacket>
     where
         F: Fn(&Position) -> bool,
@@ -1068,16 +1306,27 @@ impl Map {
         obj
     }
 
+    /// Returns `false` if the script was aborted for exceeding the limits.
     async fn run_lua<S: serde::Serialize + Sync>(
         &mut self,
         sender_id: PlayerId,
         zone_id: ZoneId,
         packet: &S,
         call_type: &str,
-        lua_data: &str,
-    ) -> Result<(), Error> {
-        spawn_blocking(|| self.run_lua_blocking(sender_id, zone_id, packet, call_type, lua_data))
-            .await?
+        script: &str,
+        code: LuaCode<'_>,
+    ) -> Result<bool, Error> {
+        let completed = spawn_blocking(|| {
+            self.run_lua_blocking(sender_id, zone_id, packet, call_type, script, code)
+        })
+        .await??;
+        for (id, zone_id, enemy) in std::mem::take(&mut self.to_spawn) {
+            self.add_enemy(id, zone_id, enemy).await;
+        }
+        for id in std::mem::take(&mut self.to_despawn) {
+            self.despawn_enemy(id).await;
+        }
+        Ok(completed)
     }
     fn run_lua_blocking<S: serde::Serialize + Sync>(
         &mut self,
@@ -1085,25 +1334,21 @@ impl Map {
         zone_id: ZoneId,
         packet: &S,
         call_type: &str,
-        lua_data: &str,
-    ) -> Result<(), Error> {
-        let mut scheduled_move = vec![];
-        let mut lobby_moves = vec![];
+        script: &str,
+        code: LuaCode<'_>,
+    ) -> Result<bool, Error> {
+        let requests = RefCell::new(LuaRequests::default());
 
-        let Some(caller) = self
+        // the sender of a timer might have already left the map
+        let caller = self
             .players
             .iter()
             .find(|p| p.player_id == sender_id)
-            .and_then(|p| p.user.upgrade())
-        else {
-            unreachable!("Sender should exist in the current map");
-        };
-        let caller_lock = caller.lock_blocking();
+            .and_then(|p| p.user.upgrade());
         let Some(zone) = self.data.zones.iter().find(|z| z.zone_id == zone_id) else {
             return Err(Error::InvalidInput("run_lua, zone"));
         };
-        drop(caller_lock);
-        {
+        let result = {
             let lua = self.lua.lock();
             let globals = lua.globals();
             let player_ids: Vec<_> = self.players.iter().map(|p| p.player_id).collect();
@@ -1112,29 +1357,31 @@ impl Map {
             globals.set("sender", sender_id)?;
             globals.set("players", player_ids)?;
             globals.set("call_type", call_type)?;
-            lua.scope(|scope| {
-                self.setup_scope(
-                    &globals,
-                    scope,
-                    zone_id,
-                    &mut scheduled_move,
-                    &mut lobby_moves,
-                )?;
+            let result = lua.scope(|scope| {
+                self.setup_scope(&globals, scope, zone_id, &requests)?;
 
                 /* LUA FUNCTIONS */
 
+                let get_caller = || {
+                    caller
+                        .as_ref()
+                        .ok_or(mlua::Error::runtime("Sender has left the map"))
+                };
                 // get account flag
                 globals.set(
                     "get_account_flag",
-                    scope.create_function_mut(|_, flag: u32| -> Result<u8, _> {
-                        Ok(caller.lock_blocking().get_account_flags().get(flag as _))
+                    scope.create_function_mut(move |_, flag: u32| -> Result<u8, _> {
+                        Ok(get_caller()?
+                            .lock_blocking()
+                            .get_account_flags()
+                            .get(flag as _))
                     })?,
                 )?;
                 // get character flag
                 globals.set(
                     "get_character_flag",
-                    scope.create_function_mut(|_, flag: u32| -> Result<u8, _> {
-                        if let Some(f) = caller.lock_blocking().get_char_flags() {
+                    scope.create_function_mut(move |_, flag: u32| -> Result<u8, _> {
+                        if let Some(f) = get_caller()?.lock_blocking().get_char_flags() {
                             Ok(f.get(flag as _))
                         } else {
                             unreachable!("Users in maps should have loaded characters")
@@ -1144,23 +1391,57 @@ impl Map {
 
                 /* LUA FUNCTIONS END */
 
-                let chunk = lua.load(lua_data);
-                chunk.exec()?;
-                Ok(())
-            })?;
+                match code {
+                    LuaCode::Chunk(source) => exec_limited(&lua, &self.lua_limits, || {
+                        lua.load(source).set_name(script).exec()
+                    }),
+                    LuaCode::Function(f) => exec_limited(&lua, &self.lua_limits, || f.call(())),
+                }
+            });
             globals.raw_remove("packet")?;
             globals.raw_remove("sender")?;
             globals.raw_remove("players")?;
             globals.raw_remove("call_type")?;
             globals.raw_remove("zone")?;
+            if let Err(e) = &result {
+                if is_limit_violation(e) {
+                    log_limit_violation(&lua, script, call_type, e);
+                }
+            }
+            result
+        };
+        match result {
+            Ok(()) => {}
+            // the script is aborted, but the map keeps running
+            Err(e) if is_limit_violation(&e) => return Ok(false),
+            Err(e) => return Err(e.into()),
         }
-        for (receiver, mapid) in scheduled_move {
-            self.to_move.push((receiver, mapid));
-        }
-        for receiver in lobby_moves {
-            self.to_lobby_move.push(receiver);
+        let requests = requests.into_inner();
+        for request in requests.timers {
+            match request {
+                TimerRequest::Add {
+                    id,
+                    delay,
+                    repeat,
+                    callback,
+                } => self.timers.push(LuaTimer {
+                    id,
+                    script: script.to_string(),
+                    sender_id,
+                    zone_id,
+                    repeat,
+                    callback,
+                    task: self.start_timer(id, delay),
+                }),
+                TimerRequest::Clear(id) => self.timers.retain(|t| t.id != id),
+            }
         }
-        Ok(())
+        self.to_move.extend(requests.moves);
+        self.to_lobby_move.extend(requests.lobby_moves);
+        self.max_id += requests.spawns.len() as u32;
+        self.to_spawn.extend(requests.spawns);
+        self.to_despawn.extend(requests.despawns);
+        Ok(true)
     }
 
     fn setup_scope<'s>(
@@ -1168,8 +1449,7 @@ impl Map {
         globals: &mlua::Table,
         scope: &'s mlua::Scope<'s, '_>,
         zone_id: ZoneId,
-        scheduled_move: &'s mut Vec<(PlayerId, String)>,
-        lobby_moves: &'s mut Vec<PlayerId>,
+        requests: &'s RefCell<LuaRequests>,
     ) -> Result<(), mlua::Error> {
         /* LUA FUNCTIONS */
 
@@ -1241,7 +1521,7 @@ impl Map {
         globals.set(
             "move_player",
             scope.create_function_mut(|_, (receiver, zone): (u32, String)| {
-                scheduled_move.push((receiver, zone));
+                requests.borrow_mut().moves.push((receiver, zone));
                 Ok(())
             })?,
         )?;
@@ -1249,7 +1529,7 @@ impl Map {
         globals.set(
             "move_lobby",
             scope.create_function_mut(|_, receiver: u32| {
-                lobby_moves.push(receiver);
+                requests.borrow_mut().lobby_moves.push(receiver);
                 Ok(())
             })?,
         )?;
@@ -1365,9 +1645,347 @@ impl Map {
             )?,
         )?;
 
+        // schedule a function call after a delay (in milliseconds), returns the timer id
+        let add_timer = move |lua: &Lua, delay: u64, callback: mlua::Function, repeat: bool| {
+            let mut requests = requests.borrow_mut();
+            let pending = requests
+                .timers
+                .iter()
+                .filter(|r| matches!(r, TimerRequest::Add { .. }))
+                .count();
+            if self.timers.len() + pending >= MAX_LUA_TIMERS {
+                return Err(mlua::Error::runtime("Too many timers"));
+            }
+            if self.this.strong_count() == 0 {
+                return Err(mlua::Error::runtime("Timers are not available in this map"));
+            }
+            let id = self.latest_timer_id.fetch_add(1, Ordering::Relaxed) + 1;
+            requests.timers.push(TimerRequest::Add {
+                id,
+                delay: Duration::from_millis(delay).clamp(MIN_TIMER_DELAY, MAX_TIMER_DELAY),
+                repeat,
+                callback: lua.create_registry_value(callback)?,
+            });
+            Ok(id)
+        };
+        globals.set(
+            "set_timeout",
+            scope.create_function(move |lua, (delay, callback): (u64, mlua::Function)| {
+                add_timer(lua, delay, callback, false)
+            })?,
+        )?;
+        // schedule a repeating function call (interval in milliseconds), returns the timer id
+        globals.set(
+            "set_interval",
+            scope.create_function(move |lua, (delay, callback): (u64, mlua::Function)| {
+                add_timer(lua, delay, callback, true)
+            })?,
+        )?;
+        // cancel a timer
+        globals.set(
+            "clear_timer",
+            scope.create_function(|_, id: u32| {
+                requests.borrow_mut().timers.push(TimerRequest::Clear(id));
+                Ok(())
+            })?,
+        )?;
+
+        let get_player = move |id: u32| {
+            self.players
+                .iter()
+                .find(|p| p.player_id == id)
+                .and_then(|p| p.user.upgrade())
+                .ok_or_else(|| mlua::Error::runtime(format!("Player {id} is not in the map")))
+        };
+        // zone id by name, defaults to the zone of the script
+        let find_zone = move |zone: Option<String>| match zone {
+            Some(name) => self
+                .data
+                .zones
+                .iter()
+                .find(|z| z.name == name)
+                .map(|z| z.zone_id)
+                .ok_or_else(|| mlua::Error::runtime(format!("Unknown zone {name}"))),
+            None => Ok(zone_id),
+        };
+        // spawn_enemy(name: string, position: Position, zone: string?) -> enemy id
+        globals.set(
+            "spawn_enemy",
+            scope.create_function(
+                move |lua, (name, pos, zone): (String, mlua::Value, Option<String>)| {
+                    let zone_id = find_zone(zone)?;
+                    let pos: Position = lua.from_value(pos)?;
+                    let Some(block_data) = &self.block_data else {
+                        return Err(mlua::Error::runtime(
+                            "Enemies are not available in this map",
+                        ));
+                    };
+                    let enemy = EnemyStats::build(
+                        &name,
+                        self.enemy_level,
+                        pos,
+                        &block_data.server_data.load(),
+                    )
+                    .map_err(mlua::Error::external)?;
+                    let mut requests = requests.borrow_mut();
+                    let id = self.max_id + requests.spawns.len() as u32 + 1;
+                    requests.spawns.push((id, zone_id, enemy));
+                    Ok(id)
+                },
+            )?,
+        )?;
+        // despawn_enemy(id: integer)
+        globals.set(
+            "despawn_enemy",
+            scope.create_function(|_, id: u32| {
+                let mut requests = requests.borrow_mut();
+                let exists = self.enemies.iter().any(|(e, _, _)| *e == id)
+                    || requests.spawns.iter().any(|(e, _, _)| *e == id);
+                if !exists {
+                    return Err(mlua::Error::runtime(format!("Enemy {id} doesn't exist")));
+                }
+                requests.despawns.push(id);
+                Ok(())
+            })?,
+        )?;
+        // give_item(player: integer, item_type: integer, id: integer, subid: integer)
+        globals.set(
+            "give_item",
+            scope.create_function(
+                move |_, (receiver, item_type, id, subid): (u32, u16, u16, u16)| {
+                    let item_id = protocol::items::ItemId {
+                        item_type,
+                        id,
+                        subid,
+                        ..Default::default()
+                    };
+                    get_player(receiver)?
+                        .lock_blocking()
+                        .add_item_block(item_id)
+                        .map_err(mlua::Error::external)
+                },
+            )?,
+        )?;
+        // give_meseta(player: integer, amount: integer)
+        globals.set(
+            "give_meseta",
+            scope.create_function(move |_, (receiver, amount): (u32, u64)| {
+                get_player(receiver)?
+                    .lock_blocking()
+                    .add_meseta_block(amount)
+                    .map_err(mlua::Error::external)
+            })?,
+        )?;
+        // give_exp(player: integer, amount: integer)
+        globals.set(
+            "give_exp",
+            scope.create_function(move |_, (receiver, amount): (u32, u32)| {
+                let level_ups = get_player(receiver)?
+                    .lock_blocking()
+                    .add_exp_block(amount)
+                    .map_err(mlua::Error::external)?;
+                if let Some(block_data) = &self.block_data {
+                    for event in level_ups {
+                        Plugins::dispatch(block_data, event);
+                    }
+                }
+                Ok(())
+            })?,
+        )?;
+        // send_system_message(player: integer, message: string)
+        globals.set(
+            "send_system_message",
+            scope.create_function(move |_, (receiver, message): (u32, String)| {
+                let packet = Packet::SystemMessage(protocol::unk19::SystemMessagePacket {
+                    message,
+                    msg_type: protocol::unk19::MessageType::SystemMessage,
+                    ..Default::default()
+                });
+                get_player(receiver)?
+                    .lock_blocking()
+                    .send_packet_block(&packet)
+                    .map_err(mlua::Error::external)
+            })?,
+        )?;
+        // get_player_position(player: integer) -> Position, x: number, y: number, z: number
+        // (the Position table is in the same format as object positions)
+        globals.set(
+            "get_player_position",
+            scope.create_function(move |lua, receiver: u32| {
+                let position = get_player(receiver)?.lock_blocking().position;
+                Ok((
+                    lua.to_value(&position)?,
+                    position.pos_x.to_f32(),
+                    position.pos_y.to_f32(),
+                    position.pos_z.to_f32(),
+                ))
+            })?,
+        )?;
+        // get_player_class(player: integer) -> main class: string, subclass: string
+        globals.set(
+            "get_player_class",
+            scope.create_function(move |lua, receiver: u32| {
+                let player = get_player(receiver)?;
+                let lock = player.lock_blocking();
+                let Some(char) = &lock.character else {
+                    return Err(mlua::Error::runtime("Character isn't loaded"));
+                };
+                let classes = &char.character.classes;
+                Ok((
+                    lua.to_value(&classes.main_class)?,
+                    lua.to_value(&classes.sub_class)?,
+                ))
+            })?,
+        )?;
+        // get_player_level(player: integer) -> main class level: integer, subclass level: integer
+        globals.set(
+            "get_player_level",
+            scope.create_function(move |_, receiver: u32| {
+                let player = get_player(receiver)?;
+                let lock = player.lock_blocking();
+                let Some(char) = &lock.character else {
+                    return Err(mlua::Error::runtime("Character isn't loaded"));
+                };
+                Ok((
+                    char.character.get_level().level1,
+                    char.character.get_sublevel().level1,
+                ))
+            })?,
+        )?;
+        // get_zone_players(zone: string?) -> player ids
+        globals.set(
+            "get_zone_players",
+            scope.create_function(move |_, zone: Option<String>| {
+                let zone_id = find_zone(zone)?;
+                Ok(self
+                    .players
+                    .iter()
+                    .filter(|p| p.zone_id == zone_id)
+                    .map(|p| p.player_id)
+                    .collect::<Vec<_>>())
+            })?,
+        )?;
+
+        // map_get(key: string) -> value stored in this map instance
+        globals.set(
+            "map_get",
+            scope.create_function(|lua, key: String| {
+                lua.named_registry_value::<mlua::Table>(MAP_STORE)?
+                    .get::<mlua::Value>(key)
+            })?,
+        )?;
+        // map_set(key: string, value: any), nil removes the value
+        globals.set(
+            "map_set",
+            scope.create_function(|lua, (key, value): (String, mlua::Value)| {
+                lua.named_registry_value::<mlua::Table>(MAP_STORE)?
+                    .set(key, value)
+            })?,
+        )?;
+        // the store is cloned out of the party, so that the party lock isn't taken here
+        let get_party_store = move |id: u32| {
+            get_player(id)?
+                .lock_blocking()
+                .get_party_store()
+                .ok_or_else(|| mlua::Error::runtime(format!("Player {id} is not in a party")))
+        };
+        // party_get(player: integer, key: string) -> value stored in the party of the player
+        globals.set(
+            "party_get",
+            scope.create_function(move |lua, (player, key): (u32, String)| {
+                let value = get_party_store(player)?.lock().get(&key).cloned();
+                value.map(|value| lua.to_value(&value)).transpose()
+            })?,
+        )?;
+        // party_set(player: integer, key: string, value: any), nil removes the value
+        globals.set(
+            "party_set",
+            scope.create_function(
+                move |lua, (player, key, value): (u32, String, mlua::Value)| {
+                    let value: Option<ScriptValue> = lua.from_value(value)?;
+                    let store = get_party_store(player)?;
+                    let result = script_store::set_value(&mut store.lock(), key, value);
+                    result.map_err(mlua::Error::external)
+                },
+            )?,
+        )?;
+        // character_get(player: integer, key: string) -> value saved in the character
+        globals.set(
+            "character_get",
+            scope.create_function(move |lua, (player, key): (u32, String)| {
+                let value = get_player(player)?.lock_blocking().get_char_value(&key);
+                value.map(|value| lua.to_value(&value)).transpose()
+            })?,
+        )?;
+        // character_set(player: integer, key: string, value: any), nil removes the value
+        globals.set(
+            "character_set",
+            scope.create_function(
+                move |lua, (player, key, value): (u32, String, mlua::Value)| {
+                    let value: Option<ScriptValue> = lua.from_value(value)?;
+                    get_player(player)?
+                        .lock_blocking()
+                        .set_char_value(key, value)
+                        .map_err(mlua::Error::external)
+                },
+            )?,
+        )?;
+
         /* LUA FUNCTIONS END */
         Ok(())
     }
+
+    /// Spawns the task that calls the timer until it is cleared or the map is dropped.
+    fn start_timer(&self, id: u32, delay: Duration) -> tokio::task::AbortHandle {
+        let map = self.this.clone();
+        let task = tokio::spawn(async move {
+            let start = tokio::time::Instant::now() + delay;
+            let mut interval = tokio::time::interval_at(start, delay);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let Some(map) = map.upgrade() else {
+                    return;
+                };
+                let mut lock = map.lock().await;
+                match lock.run_timer(id).await {
+                    Ok(true) => {}
+                    Ok(false) => return,
+                    Err(e) => {
+                        log::warn!("Lua timer {id} failed: {e}");
+                        return;
+                    }
+                }
+            }
+        });
+        task.abort_handle()
+    }
+    /// Calls the timer callback. Returns `false` if the timer no longer exists, e.g. after a
+    /// single run or when it was cancelled.
+    async fn run_timer(&mut self, id: u32) -> Result<bool, Error> {
+        let Some(timer) = self.timers.iter().find(|t| t.id == id) else {
+            return Ok(false);
+        };
+        let callback = self.lua.lock().registry_value(&timer.callback)?;
+        let (sender_id, zone_id, script) = (timer.sender_id, timer.zone_id, timer.script.clone());
+        if !timer.repeat {
+            self.timers.retain(|t| t.id != id);
+        }
+        let code = LuaCode::Function(callback);
+        let result = match self
+            .run_lua(sender_id, zone_id, &Packet::None, "timer", &script, code)
+            .await
+        {
+            Ok(completed) => self.apply_moves().await.map(|()| completed),
+            Err(e) => Err(e),
+        };
+        if !matches!(result, Ok(true)) {
+            // don't let a failing or runaway callback run again every tick
+            log::warn!("Lua timer {id} cancelled");
+            self.timers.retain(|t| t.id != id);
+        }
+        Ok(result? && self.timers.iter().any(|t| t.id == id))
+    }
 }
 
 impl Drop for Map {
@@ -1376,6 +1994,53 @@ impl Drop for Map {
     }
 }
 
+/// Runs Lua code with the instruction budget of a single script call.
+pub fn exec_limited<R>(
+    lua: &Lua,
+    limits: &LuaLimitSettings,
+    f: impl FnOnce() -> mlua::Result<R>,
+) -> mlua::Result<R> {
+    let budget = limits.instructions;
+    if budget != 0 {
+        let executed = AtomicU64::new(0);
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(LUA_HOOK_INTERVAL),
+            move |_, _| {
+                let step = LUA_HOOK_INTERVAL as u64;
+                if executed.fetch_add(step, Ordering::Relaxed) + step > budget {
+                    Err(mlua::Error::external(InstructionLimitExceeded))
+                } else {
+                    Ok(VmState::Continue)
+                }
+            },
+        );
+    }
+    let result = f();
+    if budget != 0 {
+        lua.remove_hook();
+    }
+    result
+}
+
+/// Returned by the instruction hook when a script call runs out of its budget.
+#[derive(Debug, thiserror::Error)]
+#[error("Instruction limit exceeded")]
+struct InstructionLimitExceeded;
+
+/// Checks if the script was aborted because it exceeded the instruction or memory limit.
+pub fn is_limit_violation(error: &mlua::Error) -> bool {
+    error.chain().any(|e| {
+        e.is::<InstructionLimitExceeded>()
+            || matches!(e.downcast_ref(), Some(mlua::Error::MemoryError(_)))
+    })
+}
+
+fn log_limit_violation(lua: &Lua, script: &str, call_type: &str, error: &mlua::Error) {
+    log::warn!("Lua script {script} (call_type: {call_type}) aborted: {error}");
+    // free whatever the script left behind, so that the next call has memory to run
+    let _ = lua.gc_collect();
+}
+
 async fn exec_users<F>(users: &[MapPlayer], zone_id: ZoneId, mut f: F)
 where
     F: FnMut(OwnedMapPlayer, MutexGuard<User>) + Send,
@@ -1404,7 +2069,7 @@ where
     }
 }
 
-async fn spawn_blocking<F, R>(func: F) -> Result<R, Error>
+pub(crate) async fn spawn_blocking<F, R>(func: F) -> Result<R, Error>
 where
     F: FnOnce() -> R + Send,
     R: Send + 'static,
@@ -1414,3 +2079,143 @@ where
     let func: Box<dyn FnOnce() -> R + Send + 'static> = unsafe { std::mem::transmute(val) };
     Ok(tokio::task::spawn_blocking(func).await?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{exec_limited, is_limit_violation};
+    use crate::{
+        map_harness::{MapHarness, ScriptEvent},
+        settings::LuaLimitSettings,
+    };
+    use arc_swap::ArcSwap;
+    use data_structs::map::{MapData, ZoneData};
+    use mlua::{Lua, LuaOptions, StdLib};
+    use pso2packetlib::protocol::Packet;
+    use std::{path::Path, sync::Arc, time::Duration};
+
+    #[test]
+    fn test_lua_limits() {
+        let limits = LuaLimitSettings {
+            instructions: 100_000,
+            memory: 1024 * 1024,
+        };
+        let lua = Lua::new_with(StdLib::NONE, LuaOptions::default()).unwrap();
+        lua.set_memory_limit(limits.memory).unwrap();
+
+        let exec = |code| exec_limited(&lua, &limits, || lua.load(code).exec());
+        let e = exec("while true do end").unwrap_err();
+        assert!(is_limit_violation(&e));
+        let e = exec("local t = {} while true do t[#t + 1] = 1 end").unwrap_err();
+        assert!(is_limit_violation(&e));
+        let e = exec("x = 1 + nil").unwrap_err();
+        assert!(!is_limit_violation(&e));
+        exec("for i = 1, 1000 do x = i end").unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lua_timers() {
+        let data = MapData {
+            zones: vec![ZoneData {
+                name: "test".into(),
+                ..Default::default()
+            }],
+            luas: [(
+                "on_player_load".to_string(),
+                "set_interval(100, function() while true do end end)
+                set_interval(100, function() error('failed') end)
+                set_timeout(9223372036854775807, function() end)"
+                    .to_string(),
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let mut harness = MapHarness::new(data).await.unwrap();
+        harness.add_player(1).await.unwrap();
+        assert_eq!(harness.get_map().lock().await.timers.len(), 3);
+
+        // the runaway and failing intervals are cancelled, the long timeout is clamped and stays
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let map = harness.get_map();
+        let mut map = map.lock().await;
+        assert_eq!(map.timers.len(), 1);
+        assert!(!map.timers[0].repeat);
+
+        // removed timers stop their task
+        let task = map.timers[0].task.clone();
+        map.timers.clear();
+        drop(map);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(task.is_finished());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lua_functions() {
+        let server_data =
+            data_compiler::compile(&Path::new(env!("CARGO_MANIFEST_DIR")).join("../data")).unwrap();
+        let enemy = server_data
+            .enemy_stats
+            .enemies
+            .keys()
+            .next()
+            .unwrap()
+            .clone();
+        let script = format!(
+            "local level, sublevel = get_player_level(sender)
+            local class = get_player_class(sender)
+            local pos, x = get_player_position(sender)
+            local players = get_zone_players()
+            send_system_message(sender, level .. ' ' .. sublevel .. ' ' .. class .. ' '
+                .. #players .. ' ' .. x)
+            despawn_enemy(spawn_enemy('{enemy}', pos))
+            give_item(sender, 3, 1, 1)
+            give_meseta(sender, 100)
+            give_exp(sender, 4294967295)
+            give_exp(sender, 4294967295)"
+        );
+        let data = MapData {
+            zones: vec![ZoneData {
+                name: "test".into(),
+                ..Default::default()
+            }],
+            luas: [("on_player_load".to_string(), script)].into(),
+            ..Default::default()
+        };
+        let mut harness = MapHarness::with_block(data, |b| {
+            b.server_data = Arc::new(ArcSwap::from_pointee(server_data));
+        })
+        .await
+        .unwrap();
+        let events = harness.add_player(1).await.unwrap();
+        let packets: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                ScriptEvent::Packet { player: 1, packet } => Some(&**packet),
+                _ => None,
+            })
+            .collect();
+
+        assert!(packets
+            .iter()
+            .any(|p| matches!(p, Packet::SystemMessage(m) if m.message == "1 1 Hunter 1 0")));
+        assert!(packets
+            .iter()
+            .any(|p| matches!(p, Packet::DespawnObject(_))));
+        assert!(packets.iter().any(|p| matches!(p, Packet::AddedItem(_))));
+        assert!(packets
+            .iter()
+            .any(|p| matches!(p, Packet::InventoryMeseta(m) if m.meseta == 100)));
+        assert_eq!(
+            packets
+                .iter()
+                .filter(|p| matches!(p, Packet::GainedEXP(_)))
+                .count(),
+            2
+        );
+
+        // the EXP saturates instead of overflowing
+        let user = harness.get_user(1).unwrap();
+        let user = user.lock().await;
+        let level = user.character.as_ref().unwrap().character.get_level();
+        assert_eq!(level.exp, u32::MAX);
+    }
+}
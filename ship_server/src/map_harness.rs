@@ -0,0 +1,645 @@
+//! Test harness of map Lua scripts.
+//!
+//! [`MapHarness`] runs a [`Map`] with fake players connected over loopback sockets. Events are fed
+//! into the map the same way as by the packet handlers and everything the players receive is
+//! returned as a list of [`ScriptEvent`]s. Scripts can also be tested from Lua with
+//! [`run_lua_test`], every `tests/*.lua` file next to the map data is run by `cargo test`.
+
+use crate::{
+    capture,
+    lua_reload::read_luas,
+    map::{Map, MapType},
+    master_conn::MasterConnection,
+    mutex::{Mutex, RwLock},
+    party::Party,
+    quests::Quests,
+    settings::BlockType,
+    sql::{self, CharData},
+    user::{User, UserState},
+    BlockData, Error,
+};
+use arc_swap::ArcSwap;
+use data_structs::{
+    map::{MapData, ZoneData},
+    SerDeFile as _,
+};
+use mlua::LuaSerdeExt;
+use pso2packetlib::{
+    connection::{Connection, PrivateKey, PublicKey},
+    protocol::{
+        flag::{CutsceneEndPacket, FlagType, SkitItemAddRequestPacket},
+        models::character::Character,
+        objects::InteractPacket,
+        ObjectHeader, ObjectType, Packet, PacketType,
+    },
+};
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::net::{TcpListener, TcpStream};
+
+/// How long the players wait for more packets after an event.
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+static DB_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Something a fake player observed during an event.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ScriptEvent {
+    /// Any other packet received by the player.
+    Packet { player: u32, packet: Box<Packet> },
+    /// The player was moved to another zone (`move_player`).
+    Move { player: u32, zone: String },
+    /// The player was moved to the lobby (`move_lobby`).
+    Lobby { player: u32 },
+    /// An account or character flag of the player was changed.
+    Flag {
+        player: u32,
+        flag_type: FlagType,
+        id: u32,
+        value: u32,
+    },
+}
+
+struct FakePlayer {
+    id: u32,
+    user: Arc<Mutex<User>>,
+    client: Connection<Packet>,
+}
+
+/// Map with fake players, see the module documentation.
+pub struct MapHarness {
+    block: Arc<BlockData>,
+    map: Arc<Mutex<Map>>,
+    lobby_obj_id: u32,
+    listener: TcpListener,
+    players: Vec<FakePlayer>,
+    db_path: PathBuf,
+}
+
+impl MapHarness {
+    pub async fn new(data: MapData) -> Result<Self, Error> {
+        Self::with_block(data, |_| {}).await
+    }
+    /// Creates the harness with changed block settings, e.g. to test block features.
+    pub async fn with_block<F>(data: MapData, configure: F) -> Result<Self, Error>
+    where
+        F: FnOnce(&mut BlockData),
+    {
+        let db_path = std::env::temp_dir().join(format!(
+            "map_harness_{}_{}.db",
+            std::process::id(),
+            DB_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let sql =
+            sql::Sql::new(&db_path.to_string_lossy(), MasterConnection::disconnected()).await?;
+        let latest_mapid = AtomicU32::new(0);
+        let lobby_obj_id = latest_mapid.load(Ordering::Relaxed);
+        let lobby = Map::new_from_data(lobby_data(), &latest_mapid)?;
+        let mut map = Map::new_from_data(data, &latest_mapid)?;
+        map.set_enemy_level(1);
+        let mut block = BlockData {
+            sql: Arc::new(sql),
+            block_id: 1,
+            block_name: "Test block".into(),
+            block_type: BlockType::Normal,
+            blocks: Arc::new(RwLock::new(vec![])),
+            lobby: Arc::new(Mutex::new(lobby)),
+            key: PrivateKey::None,
+            latest_mapid,
+            latest_partyid: AtomicU32::new(0),
+            autosave_interval: None,
+            reconnect_grace: None,
+            rate_limits: Default::default(),
+            validation: Default::default(),
+            lua_limits: Default::default(),
+            lua_reloader: None,
+            plugins: Default::default(),
+            capture_all: false,
+            captures: Arc::new(capture::Captures::new(String::new(), vec![])),
+            mutes: Default::default(),
+            chat_log: Default::default(),
+            server_data: Default::default(),
+            quests: Arc::new(ArcSwap::from_pointee(Quests::load(vec![]))),
+            clients: Mutex::new(vec![]),
+            running_blocks: Arc::new(RwLock::new(vec![])),
+            suspended: Arc::new(Mutex::new(vec![])),
+        };
+        configure(&mut block);
+        let block = Arc::new(block);
+        let map = Arc::new(Mutex::new(map));
+        for map in [&block.lobby, &map] {
+            let mut lock = map.lock().await;
+            lock.set_block_data(block.clone());
+            lock.set_handle(map);
+        }
+        block.lobby.lock().await.set_map_type(MapType::Lobby);
+        block.running_blocks.write().await.push(block.clone());
+        Ok(Self {
+            block,
+            map,
+            lobby_obj_id,
+            listener: TcpListener::bind("127.0.0.1:0").await?,
+            players: vec![],
+            db_path,
+        })
+    }
+    /// Loads an uncompiled map directory (e.g. `data/maps/lobby` or the `map` folder of a quest).
+    pub fn load_map_dir(path: impl AsRef<Path>) -> Result<MapData, Error> {
+        load_map_dir(path.as_ref())
+    }
+    pub fn get_map(&self) -> Arc<Mutex<Map>> {
+        self.map.clone()
+    }
+    pub fn get_block(&self) -> Arc<BlockData> {
+        self.block.clone()
+    }
+    /// Connects a new player with an empty character and adds them to the initial zone.
+    pub async fn add_player(&mut self, id: u32) -> Result<Vec<ScriptEvent>, Error> {
+        let client = TcpStream::connect(self.listener.local_addr()?).await?;
+        let (stream, _) = self.listener.accept().await?;
+        let conn_id = self.players.len();
+        let (mut user, _) = User::new(stream, self.block.clone(), conn_id)?;
+        // the hello may still be buffered and the harness doesn't tick the users
+        user.flush_blocking()?;
+        user.user_data.id = id;
+        user.character = Some(CharData {
+            character: Character {
+                player_id: id,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        user.set_map(self.map.clone());
+        let user = Arc::new(Mutex::new(user));
+        self.block
+            .clients
+            .lock()
+            .await
+            .push((conn_id, user.clone()));
+        let mut client = Connection::new_async(
+            client,
+            PacketType::Classic,
+            PrivateKey::None,
+            PublicKey::None,
+        );
+        // server hello
+        client.read_packet_async().await?;
+        self.players.push(FakePlayer {
+            id,
+            user: user.clone(),
+            client,
+        });
+
+        Party::init_player(user.clone(), id).await?;
+        self.map.lock().await.init_add_player(user.clone()).await?;
+        user.lock().await.state = UserState::InGame;
+        self.collect_events().await
+    }
+    /// Moves a player to a zone, e.g. to set up a test.
+    pub async fn move_player(
+        &mut self,
+        player: u32,
+        zone: &str,
+    ) -> Result<Vec<ScriptEvent>, Error> {
+        self.map
+            .lock()
+            .await
+            .move_player_named(player, zone)
+            .await?;
+        self.collect_events().await
+    }
+    /// Interacts with an object or an NPC.
+    pub async fn interact(
+        &mut self,
+        player: u32,
+        object_id: u32,
+        action: &str,
+    ) -> Result<Vec<ScriptEvent>, Error> {
+        let packet = InteractPacket {
+            object1: ObjectHeader {
+                id: object_id,
+                entity_type: ObjectType::Object,
+                ..Default::default()
+            },
+            object3: ObjectHeader {
+                id: player,
+                entity_type: ObjectType::Player,
+                ..Default::default()
+            },
+            action: action.into(),
+            ..Default::default()
+        };
+        self.map.lock().await.interaction(packet, player).await?;
+        self.collect_events().await
+    }
+    /// Sends a questwork (skit) request.
+    pub async fn questwork(
+        &mut self,
+        player: u32,
+        skit_name: &str,
+    ) -> Result<Vec<ScriptEvent>, Error> {
+        let packet = SkitItemAddRequestPacket {
+            skit_name: skit_name.to_string().into(),
+            ..Default::default()
+        };
+        self.map.lock().await.on_questwork(player, packet).await?;
+        self.collect_events().await
+    }
+    /// Ends a cutscene.
+    pub async fn cutscene_end(
+        &mut self,
+        player: u32,
+        skit_name: &str,
+    ) -> Result<Vec<ScriptEvent>, Error> {
+        let packet = CutsceneEndPacket {
+            skit_name: skit_name.to_string().into(),
+            ..Default::default()
+        };
+        self.map
+            .lock()
+            .await
+            .on_cutscene_end(player, packet)
+            .await?;
+        self.collect_events().await
+    }
+    /// Reports that the player has finished loading the zone.
+    pub async fn map_loaded(&mut self, player: u32) -> Result<Vec<ScriptEvent>, Error> {
+        self.map.lock().await.on_map_loaded(player).await?;
+        self.collect_events().await
+    }
+    /// Returns the zone name of a player, `None` if the player has left the map.
+    pub async fn get_zone(&self, player: u32) -> Option<String> {
+        let user = self.get_user(player).ok()?;
+        let user = user.lock().await;
+        let map = user.get_current_map()?;
+        if !Arc::ptr_eq(&map, &self.map) {
+            return None;
+        }
+        let zone_id = user.get_zone_id();
+        let map = map.lock().await;
+        map.get_zone_name(zone_id).map(|z| z.to_string())
+    }
+    pub async fn get_account_flag(&self, player: u32, flag: u32) -> Result<u8, Error> {
+        let user = self.get_user(player)?;
+        let flags = user.lock().await.get_account_flags();
+        Ok(flags.get(flag as _))
+    }
+    pub async fn get_character_flag(&self, player: u32, flag: u32) -> Result<u8, Error> {
+        let user = self.get_user(player)?;
+        let flags = user.lock().await.get_char_flags().unwrap_or_default();
+        Ok(flags.get(flag as _))
+    }
+    /// Sets a flag without notifying the player, e.g. to set up a test.
+    pub async fn set_account_flag(&self, player: u32, flag: u32, value: u8) -> Result<(), Error> {
+        let user = self.get_user(player)?;
+        let mut user = user.lock().await;
+        user.user_data.accountflags.set(flag as _, value);
+        Ok(())
+    }
+    /// Sets a flag without notifying the player, e.g. to set up a test.
+    pub async fn set_character_flag(&self, player: u32, flag: u32, value: u8) -> Result<(), Error> {
+        let user = self.get_user(player)?;
+        let mut user = user.lock().await;
+        if let Some(character) = user.character.as_mut() {
+            character.flags.set(flag as _, value);
+        }
+        Ok(())
+    }
+
+    pub fn get_user(&self, player: u32) -> Result<Arc<Mutex<User>>, Error> {
+        self.players
+            .iter()
+            .find(|p| p.id == player)
+            .map(|p| p.user.clone())
+            .ok_or(Error::InvalidInput("map_harness, player"))
+    }
+    /// Reads everything the players have received since the last event, e.g. from a background
+    /// task.
+    pub async fn collect_events(&mut self) -> Result<Vec<ScriptEvent>, Error> {
+        let mut events = vec![];
+        for player in self.players.iter_mut() {
+            while let Ok(packet) =
+                tokio::time::timeout(READ_TIMEOUT, player.client.read_packet_async()).await
+            {
+                let packet = packet?;
+                let id = player.id;
+                let event = match packet {
+                    Packet::ServerSetFlag(flag) => ScriptEvent::Flag {
+                        player: id,
+                        flag_type: flag.flag_type,
+                        id: flag.id,
+                        value: flag.value,
+                    },
+                    Packet::MapTransfer(transfer) => ScriptEvent::Move {
+                        player: id,
+                        zone: self
+                            .map
+                            .lock()
+                            .await
+                            .get_object_zone_name(transfer.map.id)
+                            .unwrap_or_default()
+                            .to_string(),
+                    },
+                    Packet::LoadLevel(level) if level.map_object.id == self.lobby_obj_id => {
+                        ScriptEvent::Lobby { player: id }
+                    }
+                    packet => ScriptEvent::Packet {
+                        player: id,
+                        packet: Box::new(packet),
+                    },
+                };
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+}
+
+impl Drop for MapHarness {
+    fn drop(&mut self) {
+        // the block is kept alive by its list of running blocks
+        self.block
+            .running_blocks
+            .write_blocking()
+            .retain(|b| !Arc::ptr_eq(b, &self.block));
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = self.db_path.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Runs a Lua test script against a map. Failed `assert`s and other errors are returned.
+///
+/// Besides the standard library the script can call:
+/// - `add_player(id)`, `move_player(id, zone)`, `interact(id, object_id, action)`,
+///   `questwork(id, skit_name)`, `cutscene_end(id, skit_name)`, `map_loaded(id)` - feed events
+///   into the map and return the list of events (see [`ScriptEvent`])
+/// - `get_zone(id)`, `get_account_flag(id, flag)`, `get_character_flag(id, flag)`
+/// - `set_account_flag(id, flag, value)`, `set_character_flag(id, flag, value)`
+/// - `find_event(events, type, player?)` - returns the first event of a type
+/// - `find_packet(events, name, player?)` - returns the data of the first packet with this name
+pub async fn run_lua_test(data: MapData, name: &str, code: &str) -> Result<(), Error> {
+    let harness = Arc::new(Mutex::new(MapHarness::new(data).await?));
+    let lua = mlua::Lua::new();
+    let globals = lua.globals();
+
+    macro_rules! event_fn {
+        ($name:literal, |$h:ident, $args:ident: $ty:ty| $body:expr) => {{
+            let harness = harness.clone();
+            globals.set(
+                $name,
+                lua.create_async_function(move |lua, $args: $ty| {
+                    let harness = harness.clone();
+                    async move {
+                        let mut $h = harness.lock().await;
+                        let events = $body.await.map_err(mlua::Error::external)?;
+                        lua.to_value(&events)
+                    }
+                })?,
+            )?;
+        }};
+    }
+    event_fn!("add_player", |h, id: u32| h.add_player(id));
+    event_fn!("move_player", |h, args: (u32, String)| h
+        .move_player(args.0, &args.1));
+    event_fn!("interact", |h, args: (u32, u32, String)| h
+        .interact(args.0, args.1, &args.2));
+    event_fn!("questwork", |h, args: (u32, String)| h
+        .questwork(args.0, &args.1));
+    event_fn!("cutscene_end", |h, args: (u32, String)| h
+        .cutscene_end(args.0, &args.1));
+    event_fn!("map_loaded", |h, id: u32| h.map_loaded(id));
+
+    let h = harness.clone();
+    globals.set(
+        "get_zone",
+        lua.create_async_function(move |_, id: u32| {
+            let h = h.clone();
+            async move { Ok(h.lock().await.get_zone(id).await) }
+        })?,
+    )?;
+    let h = harness.clone();
+    globals.set(
+        "get_account_flag",
+        lua.create_async_function(move |_, (id, flag): (u32, u32)| {
+            let h = h.clone();
+            async move {
+                let lock = h.lock().await;
+                lock.get_account_flag(id, flag)
+                    .await
+                    .map_err(mlua::Error::external)
+            }
+        })?,
+    )?;
+    let h = harness.clone();
+    globals.set(
+        "get_character_flag",
+        lua.create_async_function(move |_, (id, flag): (u32, u32)| {
+            let h = h.clone();
+            async move {
+                let lock = h.lock().await;
+                lock.get_character_flag(id, flag)
+                    .await
+                    .map_err(mlua::Error::external)
+            }
+        })?,
+    )?;
+    let h = harness.clone();
+    globals.set(
+        "set_account_flag",
+        lua.create_async_function(move |_, (id, flag, value): (u32, u32, u8)| {
+            let h = h.clone();
+            async move {
+                let lock = h.lock().await;
+                lock.set_account_flag(id, flag, value)
+                    .await
+                    .map_err(mlua::Error::external)
+            }
+        })?,
+    )?;
+    let h = harness.clone();
+    globals.set(
+        "set_character_flag",
+        lua.create_async_function(move |_, (id, flag, value): (u32, u32, u8)| {
+            let h = h.clone();
+            async move {
+                let lock = h.lock().await;
+                lock.set_character_flag(id, flag, value)
+                    .await
+                    .map_err(mlua::Error::external)
+            }
+        })?,
+    )?;
+    lua.load(
+        "function find_event(events, type, player)
+            for _, event in ipairs(events) do
+                if event.type == type and (player == nil or event.player == player) then
+                    return event
+                end
+            end
+        end
+        function find_packet(events, name, player)
+            for _, event in ipairs(events) do
+                if event.type == \"packet\" and (player == nil or event.player == player)
+                    and type(event.packet) == \"table\" and event.packet[name] ~= nil then
+                    return event.packet[name]
+                end
+            end
+        end",
+    )
+    .exec()?;
+
+    lua.load(code).set_name(name).exec_async().await?;
+    Ok(())
+}
+
+/// Lobby used for `move_lobby`.
+fn lobby_data() -> MapData {
+    MapData {
+        zones: vec![ZoneData {
+            name: "lobby".into(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
+
+/// Loads a map directory the same way as `data_compiler`.
+fn load_map_dir(path: &Path) -> Result<MapData, Error> {
+    let Some(data_file) = ["data.json", "data.toml", "map.json", "map.toml"]
+        .into_iter()
+        .map(|name| path.join(name))
+        .find(|p| p.is_file())
+    else {
+        return Err(Error::InvalidInput("load_map_dir"));
+    };
+    let mut map = MapData::load_file(data_file)?;
+    map.luas = read_luas(&path.join("luas"))?;
+    for_each_file(&path.join("objects"), |p| {
+        map.objects.append(&mut Vec::load_file(p)?);
+        Ok(())
+    })?;
+    for_each_file(&path.join("transporters"), |p| {
+        map.transporters.append(&mut Vec::load_file(p)?);
+        Ok(())
+    })?;
+    for_each_file(&path.join("events"), |p| {
+        map.events.append(&mut Vec::load_file(p)?);
+        Ok(())
+    })?;
+    for_each_file(&path.join("npcs"), |p| {
+        map.npcs.append(&mut Vec::load_file(p)?);
+        Ok(())
+    })?;
+    let Some(init_zone) = map.zones.iter().find(|z| z.zone_id == map.init_map) else {
+        return Err(Error::InvalidInput("load_map_dir, init zone"));
+    };
+    map.map_data.settings = init_zone.settings.clone();
+    map.map_data.other_settings = map
+        .zones
+        .iter()
+        .filter(|z| !z.is_special_zone)
+        .map(|z| z.settings.clone())
+        .collect();
+    Ok(map)
+}
+
+fn for_each_file(
+    path: &Path,
+    mut callback: impl FnMut(&Path) -> Result<(), Error>,
+) -> Result<(), Error> {
+    if !path.is_dir() {
+        return Ok(());
+    }
+    let mut dirs = vec![path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?.path();
+            if entry.is_dir() {
+                dirs.push(entry);
+            } else {
+                callback(&entry)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_lua_test, MapHarness, ScriptEvent};
+    use pso2packetlib::protocol::Packet;
+    use std::path::{Path, PathBuf};
+
+    fn data_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("../data")
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lobby_cutscene() {
+        let data = MapHarness::load_map_dir(data_dir().join("maps/lobby")).unwrap();
+        let mut harness = MapHarness::new(data).await.unwrap();
+        harness.add_player(1).await.unwrap();
+        harness.move_player(1, "cafe").await.unwrap();
+
+        let events = harness.map_loaded(1).await.unwrap();
+        assert!(events.iter().any(
+            |e| matches!(e, ScriptEvent::Move { player: 1, zone } if zone == "cafe_cutscene")
+        ));
+        let events = harness.map_loaded(1).await.unwrap();
+        assert!(events.iter().any(|e| matches!(
+            e,
+            ScriptEvent::Packet { player: 1, packet } if matches!(**packet, Packet::StartCutscene(_))
+        )));
+        harness.cutscene_end(1, "pr_043070").await.unwrap();
+        assert_eq!(harness.get_zone(1).await.as_deref(), Some("cafe"));
+
+        harness.set_character_flag(1, 9518, 1).await.unwrap();
+        let events = harness.map_loaded(1).await.unwrap();
+        assert!(!events.iter().any(|e| matches!(e, ScriptEvent::Move { .. })));
+    }
+
+    /// Runs every `tests/*.lua` file of the maps and quests in the data directory.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lua_scripts() {
+        let mut map_dirs = vec![];
+        let mut dirs = vec![data_dir().join("maps"), data_dir().join("quests")];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let entry = entry.unwrap().path();
+                if !entry.is_dir() {
+                    continue;
+                }
+                if entry.join("tests").is_dir() {
+                    map_dirs.push(entry.clone());
+                }
+                dirs.push(entry);
+            }
+        }
+        for map_dir in map_dirs {
+            for test in std::fs::read_dir(map_dir.join("tests")).unwrap() {
+                let test = test.unwrap().path();
+                if test.extension().is_none_or(|e| e != "lua") {
+                    continue;
+                }
+                let data = MapHarness::load_map_dir(&map_dir).unwrap();
+                let code = std::fs::read_to_string(&test).unwrap();
+                let name = test.strip_prefix(data_dir()).unwrap_or(&test);
+                let name = name.display().to_string();
+                if let Err(e) = run_lua_test(data, &name, &code).await {
+                    panic!("{name} failed: {e}");
+                }
+            }
+        }
+    }
+}
@@ -99,11 +99,7 @@ impl MasterConnection {
             }
         })
         .await?;
-        tokio::fs::write(
-            "hostkeys.toml",
-            toml::to_string_pretty(&hostkeys)?.as_bytes(),
-        )
-        .await?;
+        tokio::fs::write(key_file, toml::to_string_pretty(&hostkeys)?.as_bytes()).await?;
         let (send, recv) = tokio::sync::mpsc::channel(10);
         let master_conn = Self {
             send_ch: send,
@@ -127,6 +123,35 @@ impl MasterConnection {
             _ => Err(Error::MSUnexpected),
         }
     }
+    /// Connection without a master ship, every request fails with `MSNoResponse`.
+    #[cfg(test)]
+    pub fn disconnected() -> Self {
+        let (send, mut recv) = tokio::sync::mpsc::channel::<(MAS, Sender<MAS>)>(10);
+        tokio::spawn(async move { while recv.recv().await.is_some() {} });
+        Self {
+            send_ch: send,
+            local_addr: Ipv4Addr::LOCALHOST,
+            ship_id: 0.into(),
+        }
+    }
+    /// Connection answering every request with `handler` instead of a master ship.
+    #[cfg(test)]
+    pub fn fake<F>(mut handler: F) -> Self
+    where
+        F: FnMut(MAS) -> MAS + Send + 'static,
+    {
+        let (send, mut recv) = tokio::sync::mpsc::channel::<(MAS, Sender<MAS>)>(10);
+        tokio::spawn(async move {
+            while let Some((action, response)) = recv.recv().await {
+                let _ = response.send(handler(action)).await;
+            }
+        });
+        Self {
+            send_ch: send,
+            local_addr: Ipv4Addr::LOCALHOST,
+            ship_id: 0.into(),
+        }
+    }
     pub async fn run_action(&self, action: MAS) -> Result<MAS, Error> {
         log::trace!("Request to master ship: {action:?}");
         let (send, mut recv) = tokio::sync::mpsc::channel(1);
@@ -167,6 +192,17 @@ impl MasterConnection {
             _ => Err(Error::MSUnexpected),
         }
     }
+    pub async fn unregister_ship(&self) -> Result<(), Error> {
+        let ship_id = self.ship_id.swap(0, std::sync::atomic::Ordering::Relaxed);
+        if ship_id == 0 {
+            return Ok(());
+        }
+        match self.run_action(MAS::UnregisterShip(ship_id)).await? {
+            MAS::Ok => Ok(()),
+            MAS::Error(e) => Err(Error::MSError(e)),
+            _ => Err(Error::MSUnexpected),
+        }
+    }
 }
 
 impl MasterConnectionImpl {
@@ -0,0 +1,118 @@
+use crate::{mutex::Mutex, BlockData, Error, User};
+use data_structs::master_ship::PermissionLevel;
+use pso2packetlib::protocol::unk19::MessageType;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Muted accounts shared by all blocks. Mutes are kept until the ship is restarted.
+#[derive(Default)]
+pub struct Mutes {
+    accounts: parking_lot::Mutex<HashMap<u32, Instant>>,
+}
+
+impl Mutes {
+    /// Mutes the player, returns `false` if the duration is out of range.
+    pub fn mute(&self, player_id: u32, duration: Duration) -> bool {
+        let Some(until) = Instant::now().checked_add(duration) else {
+            return false;
+        };
+        self.accounts.lock().insert(player_id, until);
+        true
+    }
+    pub fn unmute(&self, player_id: u32) -> bool {
+        self.accounts.lock().remove(&player_id).is_some()
+    }
+    /// Returns the remaining mute time of the player.
+    pub fn remaining(&self, player_id: u32) -> Option<Duration> {
+        let mut accounts = self.accounts.lock();
+        let until = *accounts.get(&player_id)?;
+        match until.checked_duration_since(Instant::now()) {
+            Some(remaining) if !remaining.is_zero() => Some(remaining),
+            _ => {
+                accounts.remove(&player_id);
+                None
+            }
+        }
+    }
+}
+
+/// Finds a player on the block.
+pub async fn find_player(block: &BlockData, player_id: u32) -> Option<Arc<Mutex<User>>> {
+    let clients: Vec<_> = block
+        .clients
+        .lock()
+        .await
+        .iter()
+        .map(|(_, c)| c.clone())
+        .collect();
+    for client in clients {
+        if client.lock().await.get_user_id() == player_id {
+            return Some(client);
+        }
+    }
+    None
+}
+
+/// Players can only be moderated by players with a higher permission level.
+pub fn can_moderate(moderator: PermissionLevel, target: &User) -> bool {
+    moderator > target.user_data.permission
+}
+
+/// Saves and disconnects the player.
+pub async fn kick(user: &mut User) {
+    let _ = user
+        .send_localized_msg("kicked", &[], MessageType::AdminMessage)
+        .await;
+    user.kick();
+}
+
+/// Moves the player to the zone and the position of the target. The target can be in another
+/// map of the same block.
+pub async fn teleport(player: &Arc<Mutex<User>>, target: &Arc<Mutex<User>>) -> Result<(), Error> {
+    let (target_map, zone_id, position) = {
+        let target = target.lock().await;
+        (
+            target.get_current_map(),
+            target.get_zone_id(),
+            target.position,
+        )
+    };
+    let (player_id, player_map) = {
+        let player = player.lock().await;
+        (player.get_user_id(), player.get_current_map())
+    };
+    let (Some(target_map), Some(player_map)) = (target_map, player_map) else {
+        return Err(Error::InvalidInput("teleport"));
+    };
+    if Arc::ptr_eq(&target_map, &player_map) {
+        let mut map = target_map.lock().await;
+        return map.move_player_to(player_id, zone_id, Some(position)).await;
+    }
+    // only one map is locked at a time
+    let Some(player) = player_map.lock().await.remove_player(player_id).await else {
+        return Err(Error::NoUser);
+    };
+    player.lock().await.set_map(target_map.clone());
+    let mut map = target_map.lock().await;
+    map.reattach_player(player, zone_id, position).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mutes;
+    use std::time::Duration;
+
+    #[test]
+    fn test_mutes() {
+        let mutes = Mutes::default();
+        assert!(mutes.mute(1, Duration::from_secs(60)));
+        assert!(mutes.remaining(1).is_some());
+        assert!(!mutes.mute(2, Duration::MAX));
+        assert!(mutes.remaining(2).is_none());
+        assert!(mutes.unmute(1));
+        assert!(mutes.remaining(1).is_none());
+    }
+}
@@ -3,6 +3,7 @@ use crate::{
     map::Map,
     mutex::{Mutex, MutexGuard, RwLock},
     quests::PartyQuest,
+    script_store::SharedScriptStore,
     BlockData, Error, User,
 };
 use pso2packetlib::protocol::{
@@ -24,6 +25,8 @@ pub struct Party {
     settings: party::PartySettingsPacket,
     questname: String,
     quest: Option<PartyQuest>,
+    /// State saved by Lua scripts, lives as long as the party.
+    script_store: SharedScriptStore,
 }
 
 impl Drop for Party {
@@ -46,6 +49,7 @@ impl Party {
             settings: Default::default(),
             questname: String::new(),
             quest: None,
+            script_store: Default::default(),
         }
     }
     fn add_color(&mut self, id: u32) -> Color {
@@ -83,6 +87,8 @@ impl Party {
             return Ok(());
         }
         let mut np_lock = new_id.lock().await;
+        // scripts use the store without locking the party
+        np_lock.party_store = self.script_store.clone();
         let (hp, max_hp) = np_lock.get_stats().get_hp();
         let color = self.add_color(np_lock.get_user_id());
         let new_player_obj = np_lock.create_object_header();
@@ -507,6 +513,29 @@ impl Party {
 
         Ok(())
     }
+    /// Replaces the player of a resumed session and sends the party state to them.
+    pub async fn rejoin_player(&mut self, user: Arc<Mutex<User>>) -> Result<(), Error> {
+        let player = user.lock().await.create_object_header();
+        let was_leader = self.leader.id == player.id;
+        // the player might have been removed from the party while disconnected
+        if self.players.iter().any(|(id, _)| *id == player.id) {
+            self.remove_player(player.id).await?;
+        }
+        self.add_player(user.clone()).await?;
+        if was_leader && self.leader.id != player.id {
+            self.change_leader(player).await?;
+        }
+        if let Some(quest) = &self.quest {
+            let mut set_packet = quest.set_party_packet();
+            set_packet.player = self.leader;
+            let mut info_packet = quest.set_info_packet();
+            info_packet.player = self.leader;
+            let mut lock = user.lock().await;
+            lock.send_packet(&Packet::SetQuestInfo(info_packet)).await?;
+            lock.send_packet(&Packet::SetPartyQuest(set_packet)).await?;
+        }
+        Ok(())
+    }
     pub async fn change_leader(&mut self, leader: ObjectHeader) -> Result<(), Error> {
         self.leader = leader;
         let packet = Packet::NewLeader(party::NewLeaderPacket { leader });
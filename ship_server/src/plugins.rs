@@ -0,0 +1,509 @@
+use crate::{
+    map::{exec_limited, is_limit_violation, spawn_blocking},
+    mutex::Mutex,
+    settings::LuaLimitSettings,
+    BlockData, Error, User,
+};
+use data_structs::master_ship::PermissionLevel;
+use mlua::{Function, IntoLuaMulti, Lua, LuaOptions, LuaSerdeExt, MultiValue, StdLib, Table};
+use pso2packetlib::protocol::{
+    models::character::Class,
+    unk19::{MessageType, SystemMessagePacket},
+    Packet,
+};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Registry table of the registered commands (name -> { level, callback }).
+const COMMANDS: &str = "plugin_commands";
+/// Registry table of the event hooks (event name -> array of callbacks).
+const HOOKS: &str = "plugin_hooks";
+
+/// Player event passed to the plugin hooks.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// on("login", function(player) end)
+    Login { player: u32 },
+    /// on("logout", function(player) end)
+    Logout { player: u32 },
+    /// on("level_up", function(player, class, level) end)
+    LevelUp {
+        player: u32,
+        class: Class,
+        level: u16,
+    },
+    /// on("quest_start", function(player, quest_name_id) end)
+    QuestStart { player: u32, quest: u32 },
+    /// on("enemy_kill", function(player, enemy_name) end)
+    EnemyKill { player: u32, enemy: String },
+}
+
+const EVENT_NAMES: [&str; 5] = ["login", "logout", "level_up", "quest_start", "enemy_kill"];
+
+impl Event {
+    const fn name(&self) -> &'static str {
+        match self {
+            Self::Login { .. } => "login",
+            Self::Logout { .. } => "logout",
+            Self::LevelUp { .. } => "level_up",
+            Self::QuestStart { .. } => "quest_start",
+            Self::EnemyKill { .. } => "enemy_kill",
+        }
+    }
+    fn to_args(&self, lua: &Lua) -> mlua::Result<MultiValue> {
+        match self {
+            Self::Login { player } | Self::Logout { player } => player.into_lua_multi(lua),
+            Self::LevelUp {
+                player,
+                class,
+                level,
+            } => (*player, lua.to_value(class)?, *level).into_lua_multi(lua),
+            Self::QuestStart { player, quest } => (*player, *quest).into_lua_multi(lua),
+            Self::EnemyKill { player, enemy } => (*player, enemy.as_str()).into_lua_multi(lua),
+        }
+    }
+}
+
+/// Outcome of a chat command lookup.
+pub enum CommandCheck {
+    Unknown,
+    Denied,
+    Allowed,
+}
+
+struct Plugin {
+    name: String,
+    lua: Mutex<Lua>,
+    commands: Vec<(String, PermissionLevel)>,
+    events: Vec<&'static str>,
+}
+
+/// Server-wide Lua plugins loaded from the plugin directory at startup.
+#[derive(Default)]
+pub struct Plugins {
+    plugins: Vec<Plugin>,
+    limits: Arc<LuaLimitSettings>,
+}
+
+impl Plugins {
+    /// Loads every `.lua` file of the directory. Plugins that fail to load are skipped.
+    pub fn load(dir: impl AsRef<Path>, limits: Arc<LuaLimitSettings>) -> Result<Self, Error> {
+        let dir = dir.as_ref();
+        let mut plugins = Self {
+            plugins: vec![],
+            limits,
+        };
+        if !dir.is_dir() {
+            log::warn!("Plugin directory {} doesn't exist", dir.display());
+            return Ok(plugins);
+        }
+        let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<Result<_, _>>()?;
+        files.retain(|p| p.is_file() && p.extension().is_some_and(|e| e == "lua"));
+        files.sort();
+        for file in files {
+            let name = file
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            let code = std::fs::read_to_string(&file)?;
+            match plugins.load_plugin(&name, &code) {
+                Ok(plugin) => {
+                    log::info!(
+                        "Loaded plugin {name} ({} commands, {} hooks)",
+                        plugin.commands.len(),
+                        plugin.events.len()
+                    );
+                    plugins.plugins.push(plugin);
+                }
+                Err(e) => log::error!("Failed to load plugin {name}: {e}"),
+            }
+        }
+        Ok(plugins)
+    }
+    fn load_plugin(&self, name: &str, code: &str) -> Result<Plugin, Error> {
+        let lua = Lua::new_with(
+            StdLib::STRING | StdLib::TABLE | StdLib::MATH,
+            LuaOptions::default(),
+        )?;
+        if self.limits.memory != 0 {
+            lua.set_memory_limit(self.limits.memory)?;
+        }
+        lua.set_named_registry_value(COMMANDS, lua.create_table()?)?;
+        lua.set_named_registry_value(HOOKS, lua.create_table()?)?;
+        let globals = lua.globals();
+        // register_command(name: string, level: "player" | "moderator" | "gm" | "admin",
+        //     callback: function(player, args))
+        // (the callback may return a message for the player)
+        globals.set(
+            "register_command",
+            lua.create_function(|lua, (name, level, callback): (String, String, Function)| {
+                level
+                    .parse::<PermissionLevel>()
+                    .map_err(mlua::Error::runtime)?;
+                let command = lua.create_table()?;
+                command.set("level", level)?;
+                command.set("callback", callback)?;
+                let commands: Table = lua.named_registry_value(COMMANDS)?;
+                commands.set(name.trim_start_matches('!'), command)
+            })?,
+        )?;
+        // on(event: string, callback: function)
+        globals.set(
+            "on",
+            lua.create_function(|lua, (event, callback): (String, Function)| {
+                if !EVENT_NAMES.contains(&event.as_str()) {
+                    return Err(mlua::Error::runtime(format!("Unknown event {event}")));
+                }
+                let hooks: Table = lua.named_registry_value(HOOKS)?;
+                let callbacks = match hooks.get::<Option<Table>>(event.as_str())? {
+                    Some(callbacks) => callbacks,
+                    None => {
+                        let callbacks = lua.create_table()?;
+                        hooks.set(event, &callbacks)?;
+                        callbacks
+                    }
+                };
+                callbacks.push(callback)
+            })?,
+        )?;
+        exec_limited(&lua, &self.limits, || lua.load(code).set_name(name).exec())?;
+        // registration is only possible while loading
+        globals.raw_remove("register_command")?;
+        globals.raw_remove("on")?;
+
+        let mut commands = vec![];
+        let table: Table = lua.named_registry_value(COMMANDS)?;
+        for pair in table.pairs::<String, Table>() {
+            let (command, data) = pair?;
+            let level = data
+                .get::<String>("level")?
+                .parse()
+                .expect("Level is checked on registration");
+            if self.find_command(&command).is_some() {
+                log::warn!("Plugin {name}: command !{command} is already registered");
+                continue;
+            }
+            commands.push((command, level));
+        }
+        let hooks: Table = lua.named_registry_value(HOOKS)?;
+        let events = EVENT_NAMES
+            .into_iter()
+            .filter(|e| hooks.contains_key(*e).unwrap_or(false))
+            .collect();
+        drop(globals);
+        Ok(Plugin {
+            name: name.to_string(),
+            lua: Mutex::new(lua),
+            commands,
+            events,
+        })
+    }
+    fn find_command(&self, command: &str) -> Option<(&Plugin, PermissionLevel)> {
+        self.plugins.iter().find_map(|p| {
+            p.commands
+                .iter()
+                .find(|(c, _)| c == command)
+                .map(|(_, level)| (p, *level))
+        })
+    }
+    /// Checks if a plugin provides the command (without "!") and if the player may use it.
+    pub fn check_command(&self, command: &str, level: PermissionLevel) -> CommandCheck {
+        match self.find_command(command) {
+            None => CommandCheck::Unknown,
+            Some((_, required)) if required > level => CommandCheck::Denied,
+            Some(_) => CommandCheck::Allowed,
+        }
+    }
+    /// Runs the command for the player, the permission must be checked before. The caller must
+    /// not be locked.
+    pub async fn run_command(
+        &self,
+        block: &BlockData,
+        player: u32,
+        command: &str,
+        args: Vec<String>,
+    ) -> Result<(), Error> {
+        let Some((plugin, _)) = self.find_command(command) else {
+            return Ok(());
+        };
+        let lua = plugin.lua.lock().await;
+        let result = spawn_blocking(|| {
+            self.call(&lua, block, |lua| {
+                let commands: Table = lua.named_registry_value(COMMANDS)?;
+                let callback: Function = commands.get::<Table>(command)?.get("callback")?;
+                callback.call::<Option<String>>((player, args))
+            })
+        })
+        .await?;
+        drop(lua);
+        let users: Vec<_> = block
+            .clients
+            .lock()
+            .await
+            .iter()
+            .map(|(_, u)| u.clone())
+            .collect();
+        for user in users {
+            let mut user = user.lock().await;
+            if user.get_user_id() != player {
+                continue;
+            }
+            match result {
+                Ok(Some(message)) => user.send_packet(&system_message(message)).await?,
+                Ok(None) => {}
+                Err(e) => {
+                    log::warn!("Plugin {}: command !{command} failed: {e}", plugin.name);
+                    user.send_system_msg("command_failed", &[]).await?;
+                }
+            }
+            return Ok(());
+        }
+        Ok(())
+    }
+    /// Passes the event to the plugin hooks in the background.
+    pub fn dispatch(block: &Arc<BlockData>, event: Event) {
+        let name = event.name();
+        if !block
+            .plugins
+            .plugins
+            .iter()
+            .any(|p| p.events.contains(&name))
+        {
+            return;
+        }
+        let block = block.clone();
+        tokio::spawn(async move { block.plugins.run_event(&block, event).await });
+    }
+    async fn run_event(&self, block: &BlockData, event: Event) {
+        let name = event.name();
+        for plugin in self.plugins.iter().filter(|p| p.events.contains(&name)) {
+            let lua = plugin.lua.lock().await;
+            let result = spawn_blocking(|| {
+                self.call(&lua, block, |lua| {
+                    let hooks: Table = lua.named_registry_value(HOOKS)?;
+                    let callbacks: Table = hooks.get(name)?;
+                    for callback in callbacks.sequence_values::<Function>() {
+                        callback?.call::<()>(event.to_args(lua)?)?;
+                    }
+                    Ok(())
+                })
+            })
+            .await
+            .and_then(|r| r.map_err(Error::from));
+            if let Err(e) = result {
+                log::warn!("Plugin {}: {name} hook failed: {e}", plugin.name);
+            }
+        }
+    }
+    /// Calls into the plugin with the block functions available. The functions lock the users, so
+    /// this runs on the blocking thread pool.
+    fn call<R>(
+        &self,
+        lua: &Lua,
+        block: &BlockData,
+        f: impl FnOnce(&Lua) -> mlua::Result<R>,
+    ) -> mlua::Result<R> {
+        let globals = lua.globals();
+        let result = lua.scope(|scope| {
+            /* LUA FUNCTIONS */
+
+            let get_player = move |id: u32| {
+                find_player(block, id)
+                    .ok_or_else(|| mlua::Error::runtime(format!("Player {id} is not online")))
+            };
+            // send_system_message(player: integer, message: string)
+            globals.set(
+                "send_system_message",
+                scope.create_function(move |_, (receiver, message): (u32, String)| {
+                    get_player(receiver)?
+                        .lock_blocking()
+                        .send_packet_block(&system_message(message))
+                        .map_err(mlua::Error::external)
+                })?,
+            )?;
+            // send_block_message(message: string)
+            globals.set(
+                "send_block_message",
+                scope.create_function(move |_, message: String| {
+                    let packet = system_message(message);
+                    for user in block_users(block) {
+                        let _ = user.lock_blocking().send_packet_block(&packet);
+                    }
+                    Ok(())
+                })?,
+            )?;
+            // get_players() -> ids of the players on the block
+            globals.set(
+                "get_players",
+                scope.create_function(move |_, ()| {
+                    Ok(block_users(block)
+                        .into_iter()
+                        .map(|u| u.lock_blocking().get_user_id())
+                        .collect::<Vec<_>>())
+                })?,
+            )?;
+            // get_player_name(player: integer) -> character name: string
+            globals.set(
+                "get_player_name",
+                scope.create_function(move |_, receiver: u32| {
+                    let player = get_player(receiver)?;
+                    let lock = player.lock_blocking();
+                    let Some(char) = &lock.character else {
+                        return Err(mlua::Error::runtime("Character isn't loaded"));
+                    };
+                    Ok(char.character.name.clone())
+                })?,
+            )?;
+            // get_player_level(player: integer) -> main class level: integer, subclass level: integer
+            globals.set(
+                "get_player_level",
+                scope.create_function(move |_, receiver: u32| {
+                    let player = get_player(receiver)?;
+                    let lock = player.lock_blocking();
+                    let Some(char) = &lock.character else {
+                        return Err(mlua::Error::runtime("Character isn't loaded"));
+                    };
+                    Ok((
+                        char.character.get_level().level1,
+                        char.character.get_sublevel().level1,
+                    ))
+                })?,
+            )?;
+
+            /* LUA FUNCTIONS END */
+
+            exec_limited(lua, &self.limits, || f(lua))
+        });
+        for name in [
+            "send_system_message",
+            "send_block_message",
+            "get_players",
+            "get_player_name",
+            "get_player_level",
+        ] {
+            globals.raw_remove(name)?;
+        }
+        if let Err(e) = &result {
+            if is_limit_violation(e) {
+                log::warn!("Plugin script exceeded its resource limits: {e}");
+            }
+        }
+        result
+    }
+}
+
+fn system_message(message: String) -> Packet {
+    Packet::SystemMessage(SystemMessagePacket {
+        message,
+        msg_type: MessageType::SystemMessage,
+        ..Default::default()
+    })
+}
+
+/// Users are cloned out of the list, so that they aren't locked with the client list.
+fn block_users(block: &BlockData) -> Vec<Arc<Mutex<User>>> {
+    block
+        .clients
+        .lock_blocking()
+        .iter()
+        .map(|(_, u)| u.clone())
+        .collect()
+}
+
+fn find_player(block: &BlockData, id: u32) -> Option<Arc<Mutex<User>>> {
+    block_users(block)
+        .into_iter()
+        .find(|u| u.lock_blocking().get_user_id() == id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CommandCheck, Event, PermissionLevel, Plugins};
+    use crate::map_harness::{MapHarness, ScriptEvent};
+    use data_structs::map::{MapData, ZoneData};
+    use pso2packetlib::protocol::Packet;
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn test_plugin_registration() {
+        let mut plugins = Plugins::default();
+        let plugin = plugins
+            .load_plugin(
+                "test",
+                r#"
+                register_command("!hello", "player", function(player, args) return "hi" end)
+                register_command("heal", "gm", function(player, args) end)
+                on("login", function(player) end)
+                "#,
+            )
+            .unwrap();
+        assert_eq!(plugin.events, ["login"]);
+        plugins.plugins.push(plugin);
+        assert!(matches!(
+            plugins.check_command("hello", PermissionLevel::Player),
+            CommandCheck::Allowed
+        ));
+        assert!(matches!(
+            plugins.check_command("heal", PermissionLevel::Player),
+            CommandCheck::Denied
+        ));
+        assert!(matches!(
+            plugins.check_command("heal", PermissionLevel::Gm),
+            CommandCheck::Allowed
+        ));
+        assert!(matches!(
+            plugins.check_command("missing", PermissionLevel::Gm),
+            CommandCheck::Unknown
+        ));
+
+        // duplicate commands are ignored
+        let plugin = plugins
+            .load_plugin("dup", r#"register_command("hello", "gm", function() end)"#)
+            .unwrap();
+        assert!(plugin.commands.is_empty());
+
+        assert!(plugins.load_plugin("bad", r#"on("jump", print)"#).is_err());
+        assert!(plugins
+            .load_plugin("bad", r#"register_command("x", "root", print)"#)
+            .is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_hook_waits_for_locked_user() {
+        let mut plugins = Plugins::default();
+        let plugin = plugins
+            .load_plugin(
+                "test",
+                r#"on("login", function(player) send_system_message(player, "welcome") end)"#,
+            )
+            .unwrap();
+        plugins.plugins.push(plugin);
+        let data = MapData {
+            zones: vec![ZoneData::default()],
+            ..Default::default()
+        };
+        let mut harness = MapHarness::with_block(data, |b| b.plugins = Arc::new(plugins))
+            .await
+            .unwrap();
+        harness.add_player(1).await.unwrap();
+
+        let user = harness.get_user(1).unwrap();
+        let lock = user.lock().await;
+        Plugins::dispatch(&harness.get_block(), Event::Login { player: 1 });
+        // the hook waits on the blocking pool while the workers keep running
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(lock);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let events = harness.collect_events().await.unwrap();
+        assert!(events.iter().any(|e| matches!(
+            e,
+            ScriptEvent::Packet { player: 1, packet }
+                if matches!(&**packet, Packet::SystemMessage(m) if m.message == "welcome")
+        )));
+    }
+}
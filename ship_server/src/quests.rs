@@ -1,6 +1,6 @@
 use std::sync::{atomic::AtomicU32, Arc};
 
-use crate::{map::Map, mutex::Mutex, Error};
+use crate::{map::Map, mutex::Mutex, settings::BlockType, Error};
 use data_structs::quest::QuestData;
 use pso2packetlib::protocol::{
     party::{SetPartyQuestPacket, SetQuestInfoPacket},
@@ -9,6 +9,7 @@ use pso2packetlib::protocol::{
         QuestCategoryPacket, QuestDifficulty, QuestType,
     },
 };
+use std::collections::HashMap;
 
 pub struct PartyQuest {
     quest: QuestData,
@@ -16,6 +17,7 @@ pub struct PartyQuest {
     map: Arc<Mutex<Map>>,
 }
 
+#[derive(Clone)]
 pub struct Quests {
     quests: Vec<QuestData>,
 }
@@ -24,12 +26,13 @@ impl Quests {
     pub const fn load(quests: Vec<QuestData>) -> Self {
         Self { quests }
     }
-    pub fn get_availiable(&self, unlocked: &[u32]) -> AvailableQuestsPacket {
+    pub fn get_availiable(&self, unlocked: &[u32], block_type: BlockType) -> AvailableQuestsPacket {
         let mut available = AvailableQuestsPacket::default();
         for quest in self
             .quests
             .iter()
             .filter(|q| unlocked.contains(&q.definition.name_id))
+            .filter(|q| block_type.is_quest_allowed(q.definition.quest_type))
         {
             match quest.definition.quest_type {
                 QuestType::Unk0 => {
@@ -305,12 +308,18 @@ impl Quests {
         available
     }
     //FIXME: this will not work for limited time quests
-    pub fn get_category(&self, category: QuestType, unlocked: &[u32]) -> QuestCategoryPacket {
+    pub fn get_category(
+        &self,
+        category: QuestType,
+        unlocked: &[u32],
+        block_type: BlockType,
+    ) -> QuestCategoryPacket {
         QuestCategoryPacket {
             quests: self
                 .quests
                 .iter()
                 .filter(|q| unlocked.contains(&q.definition.name_id))
+                .filter(|q| block_type.is_quest_allowed(q.definition.quest_type))
                 .filter(|q| q.definition.quest_type == category)
                 .map(|q| q.definition.clone())
                 .collect(),
@@ -326,10 +335,12 @@ impl Quests {
         &self,
         packet: AcceptQuestPacket,
         map_obj_id: &AtomicU32,
+        block_type: BlockType,
     ) -> Result<PartyQuest, Error> {
         let Some(quest) = self
             .quests
             .iter()
+            .filter(|q| block_type.is_quest_allowed(q.definition.quest_type))
             .find(|q| q.definition.quest_obj.id == packet.quest_obj.id)
         else {
             return Err(Error::InvalidInput("get_quest"));
@@ -350,10 +361,12 @@ impl Quests {
         &self,
         packet: AcceptStoryQuestPacket,
         map_obj_id: &AtomicU32,
+        block_type: BlockType,
     ) -> Result<PartyQuest, Error> {
         let Some(quest) = self
             .quests
             .iter()
+            .filter(|q| block_type.is_quest_allowed(q.definition.quest_type))
             .find(|q| q.definition.name_id == packet.name_id)
         else {
             return Err(Error::InvalidInput("get_quest"));
@@ -370,9 +383,22 @@ impl Quests {
     pub fn get_quest_by_nameid(&self, id: u32) -> Option<&QuestData> {
         self.quests.iter().find(|q| q.definition.name_id == id)
     }
+    /// Replaces the Lua scripts used by new maps of a quest.
+    pub fn set_luas(&mut self, name_id: u32, luas: HashMap<String, String>) {
+        if let Some(quest) = self
+            .quests
+            .iter_mut()
+            .find(|q| q.definition.name_id == name_id)
+        {
+            quest.map.luas = luas;
+        }
+    }
 }
 
 impl PartyQuest {
+    pub const fn get_name_id(&self) -> u32 {
+        self.quest.definition.name_id
+    }
     pub fn set_party_packet(&self) -> SetPartyQuestPacket {
         SetPartyQuestPacket {
             name: self.quest.definition.name_id,
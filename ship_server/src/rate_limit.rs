@@ -0,0 +1,164 @@
+use crate::settings::{RateLimit, RateLimitSettings};
+use pso2packetlib::protocol::Packet;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketCategory {
+    Chat,
+    SymbolArt,
+    Movement,
+    Other,
+}
+
+impl PacketCategory {
+    const COUNT: usize = 4;
+
+    pub const fn of(packet: &Packet) -> Self {
+        match packet {
+            Packet::ChatMessage(_) => Self::Chat,
+            Packet::SymbolArtData(_) | Packet::SymbolArtClientDataRequest(_) => Self::SymbolArt,
+            Packet::Movement(_)
+            | Packet::MovementAction(_)
+            | Packet::MovementEnd(_)
+            | Packet::ActionUpdate(_)
+            | Packet::ActionEnd(_) => Self::Movement,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Packet limit that was exceeded.
+pub struct Violation {
+    pub category: PacketCategory,
+    /// Total number of violations in this category.
+    pub count: u32,
+}
+
+pub struct TokenBucket {
+    tokens: f32,
+    limit: RateLimit,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            tokens: limit.burst as f32,
+            limit,
+            last_refill: Instant::now(),
+        }
+    }
+    pub fn try_take(&mut self) -> bool {
+        if self.limit.rate <= 0.0 {
+            return true;
+        }
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f32();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst as f32);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Per-connection packet rate limiter.
+pub struct RateLimiter {
+    buckets: [TokenBucket; PacketCategory::COUNT],
+    violations: [u32; PacketCategory::COUNT],
+}
+
+impl RateLimiter {
+    pub fn new(settings: &RateLimitSettings) -> Self {
+        Self {
+            buckets: [
+                TokenBucket::new(settings.chat),
+                TokenBucket::new(settings.symbol_art),
+                TokenBucket::new(settings.movement),
+                TokenBucket::new(settings.other),
+            ],
+            violations: [0; PacketCategory::COUNT],
+        }
+    }
+    /// Takes a token for the packet, returns the violation if the limit is exceeded.
+    pub fn check(&mut self, packet: &Packet) -> Result<(), Violation> {
+        let category = PacketCategory::of(packet);
+        if self.buckets[category as usize].try_take() {
+            return Ok(());
+        }
+        let count = &mut self.violations[category as usize];
+        *count += 1;
+        Err(Violation {
+            category,
+            count: *count,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PacketCategory, RateLimiter, TokenBucket};
+    use crate::settings::{RateLimit, RateLimitSettings};
+    use pso2packetlib::protocol::Packet;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(RateLimit {
+            rate: 50.0,
+            burst: 3,
+        });
+        for _ in 0..3 {
+            assert!(bucket.try_take());
+        }
+        assert!(!bucket.try_take());
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(bucket.try_take());
+
+        let mut unlimited = TokenBucket::new(RateLimit {
+            rate: 0.0,
+            burst: 0,
+        });
+        for _ in 0..1000 {
+            assert!(unlimited.try_take());
+        }
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limit = RateLimit {
+            rate: 0.001,
+            burst: 2,
+        };
+        let mut limiter = RateLimiter::new(&RateLimitSettings {
+            chat: limit,
+            movement: RateLimit {
+                rate: 0.0,
+                burst: 0,
+            },
+            other: limit,
+            ..Default::default()
+        });
+        let chat = Packet::ChatMessage(Default::default());
+        assert!(limiter.check(&chat).is_ok());
+        assert!(limiter.check(&chat).is_ok());
+        let violation = limiter.check(&chat).unwrap_err();
+        assert_eq!(violation.category, PacketCategory::Chat);
+        assert_eq!(violation.count, 1);
+        assert_eq!(limiter.check(&chat).unwrap_err().count, 2);
+
+        // categories are limited separately
+        for _ in 0..100 {
+            assert!(limiter
+                .check(&Packet::MovementEnd(Default::default()))
+                .is_ok());
+        }
+        assert!(limiter.check(&Packet::ServerPong).is_ok());
+        assert!(limiter.check(&Packet::ServerPong).is_ok());
+        let violation = limiter.check(&Packet::ServerPong).unwrap_err();
+        assert_eq!(violation.category, PacketCategory::Other);
+        assert_eq!(violation.count, 1);
+    }
+}
@@ -0,0 +1,89 @@
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, sync::Arc};
+
+/// Key/value store of Lua scripts.
+pub type ScriptStore = BTreeMap<String, ScriptValue>;
+/// Store shared by the members of a party. It is locked only while a value is read or written.
+pub type SharedScriptStore = Arc<parking_lot::Mutex<ScriptStore>>;
+
+/// Largest serialized size of a store, characters are saved with their store.
+pub const MAX_STORE_SIZE: usize = 64 * 1024;
+
+/// Value saved by a Lua script. Tables are saved as arrays or as tables with string keys.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ScriptValue {
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    String(String),
+    Array(Vec<ScriptValue>),
+    Table(BTreeMap<String, ScriptValue>),
+}
+
+/// Sets a value in the store, `None` removes the key. Fails if the store would exceed
+/// [`MAX_STORE_SIZE`], the old value is kept in that case.
+pub fn set_value(
+    store: &mut ScriptStore,
+    key: String,
+    value: Option<ScriptValue>,
+) -> Result<(), Error> {
+    let Some(value) = value else {
+        store.remove(&key);
+        return Ok(());
+    };
+    let old = store.insert(key.clone(), value);
+    if rmp_serde::to_vec(store)?.len() > MAX_STORE_SIZE {
+        match old {
+            Some(old) => store.insert(key, old),
+            None => store.remove(&key),
+        };
+        return Err(Error::ScriptStoreFull);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{set_value, ScriptStore, ScriptValue, MAX_STORE_SIZE};
+    use mlua::{Lua, LuaSerdeExt};
+
+    #[test]
+    fn test_script_values() {
+        let lua = Lua::new();
+        let value: mlua::Value = lua
+            .load("return { wave = 2, switches = { true, false }, name = 'boss', hp = 0.5 }")
+            .eval()
+            .unwrap();
+        let value: ScriptValue = lua.from_value(value).unwrap();
+        let mut store = ScriptStore::new();
+        store.insert("state".into(), value);
+
+        let data = rmp_serde::to_vec(&store).unwrap();
+        let loaded: ScriptStore = rmp_serde::from_slice(&data).unwrap();
+        assert_eq!(loaded, store);
+
+        let value = lua.to_value(&loaded["state"]).unwrap();
+        lua.globals().set("state", value).unwrap();
+        let check: bool = lua
+            .load("return state.wave == 2 and state.switches[1] and state.name == 'boss'")
+            .eval()
+            .unwrap();
+        assert!(check);
+    }
+
+    #[test]
+    fn test_store_size() {
+        let mut store = ScriptStore::new();
+        let value = |len| Some(ScriptValue::String("a".repeat(len)));
+        set_value(&mut store, "a".into(), value(MAX_STORE_SIZE / 2)).unwrap();
+        set_value(&mut store, "b".into(), value(MAX_STORE_SIZE / 2)).unwrap_err();
+        assert!(!store.contains_key("b"));
+        set_value(&mut store, "a".into(), value(MAX_STORE_SIZE)).unwrap_err();
+        assert_eq!(store.get("a"), value(MAX_STORE_SIZE / 2).as_ref());
+
+        set_value(&mut store, "a".into(), None).unwrap();
+        set_value(&mut store, "b".into(), value(MAX_STORE_SIZE / 2)).unwrap();
+    }
+}
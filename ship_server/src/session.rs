@@ -0,0 +1,168 @@
+use crate::{mutex::Mutex, BlockData, User, UserState};
+use std::{sync::Arc, time::Instant};
+
+/// In-game session of a player that lost connection.
+///
+/// The user is kept alive, so that the party and the map still have the player until the session
+/// is resumed or expires.
+pub struct SuspendedSession {
+    user_id: u32,
+    expires: Instant,
+    user: Arc<Mutex<User>>,
+}
+
+/// Keeps the session of a disconnected player for the grace period, players that aren't in game
+/// are dropped.
+pub async fn suspend(block: &BlockData, user: Arc<Mutex<User>>) {
+    let Some(grace) = block.reconnect_grace else {
+        return;
+    };
+    let lock = user.lock().await;
+    if lock.state != UserState::InGame {
+        return;
+    }
+    let user_id = lock.get_user_id();
+    drop(lock);
+    log::info!(
+        "User {user_id} lost connection, keeping the session for {} seconds",
+        grace.as_secs()
+    );
+    block.suspended.lock().await.push(SuspendedSession {
+        user_id,
+        expires: Instant::now() + grace,
+        user,
+    });
+}
+
+/// Removes the suspended session of the user.
+pub async fn take(block: &BlockData, user_id: u32) -> Option<Arc<Mutex<User>>> {
+    let mut sessions = block.suspended.lock().await;
+    let pos = sessions.iter().position(|s| s.user_id == user_id)?;
+    Some(sessions.swap_remove(pos).user)
+}
+
+/// Drops sessions with an expired grace period, which saves their characters and removes them
+/// from their parties and maps.
+pub async fn expire(block: &BlockData) {
+    let now = Instant::now();
+    let expired: Vec<_> = block
+        .suspended
+        .lock()
+        .await
+        .extract_if(.., |s| s.expires <= now)
+        .collect();
+    for session in expired {
+        log::info!("Session of user {} expired", session.user_id);
+    }
+}
+
+/// Saves and drops every suspended session.
+pub async fn save_all(block: &BlockData) {
+    let sessions = std::mem::take(&mut *block.suspended.lock().await);
+    for session in sessions {
+        if let Err(e) = session.user.lock().await.save().await {
+            log::warn!("Failed to save user {}: {e}", session.user_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expire, suspend, take};
+    use crate::{map_harness::MapHarness, master_conn::MasterConnection, sql::Sql, UserState};
+    use data_structs::master_ship::MasterShipAction as MAS;
+    use std::{path::Path, sync::Arc, time::Duration};
+
+    async fn harness(grace: Option<Duration>) -> MapHarness {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../data/maps/lobby");
+        let data = MapHarness::load_map_dir(path).unwrap();
+        let mut harness = MapHarness::with_block(data, |b| b.reconnect_grace = grace)
+            .await
+            .unwrap();
+        harness.add_player(1).await.unwrap();
+        harness.add_player(2).await.unwrap();
+        harness
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_suspend_and_take() {
+        let harness = harness(Some(Duration::from_secs(60))).await;
+        let block = harness.get_block();
+        let user = harness.get_user(1).unwrap();
+        suspend(&block, user.clone()).await;
+        assert!(take(&block, 2).await.is_none());
+        let taken = take(&block, 1).await.expect("Session should be suspended");
+        assert!(Arc::ptr_eq(&taken, &user));
+        assert!(take(&block, 1).await.is_none());
+
+        // only players in game are kept
+        let user = harness.get_user(2).unwrap();
+        user.lock().await.state = UserState::LoggingIn;
+        suspend(&block, user).await;
+        assert!(take(&block, 2).await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_expire() {
+        let harness = harness(Some(Duration::from_millis(200))).await;
+        let block = harness.get_block();
+        suspend(&block, harness.get_user(1).unwrap()).await;
+        expire(&block).await;
+        assert_eq!(block.suspended.lock().await.len(), 1);
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        expire(&block).await;
+        assert!(block.suspended.lock().await.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_no_grace() {
+        let harness = harness(None).await;
+        let block = harness.get_block();
+        suspend(&block, harness.get_user(1).unwrap()).await;
+        assert!(take(&block, 1).await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_other_block() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../data/maps/lobby");
+        let data = MapHarness::load_map_dir(path).unwrap();
+        let sql = Arc::new(
+            Sql::in_memory(MasterConnection::fake(|_| MAS::Ok))
+                .await
+                .unwrap(),
+        );
+        let mut old_block = MapHarness::with_block(data.clone(), |b| {
+            b.reconnect_grace = Some(Duration::from_secs(60));
+            b.sql = sql;
+        })
+        .await
+        .unwrap();
+        old_block.add_player(1).await.unwrap();
+        let suspended = old_block.get_block().suspended.clone();
+        let mut new_block = MapHarness::with_block(data, |b| {
+            b.block_id = 2;
+            b.suspended = suspended;
+        })
+        .await
+        .unwrap();
+        new_block.add_player(1).await.unwrap();
+
+        let old = old_block.get_user(1).unwrap();
+        suspend(&old_block.get_block(), old.clone()).await;
+        // the balancer sent the player to another block
+        let taken = take(&new_block.get_block(), 1)
+            .await
+            .expect("Session should be found on every block");
+        assert!(Arc::ptr_eq(&taken, &old));
+        let new = new_block.get_user(1).unwrap();
+        let resumed = new
+            .lock()
+            .await
+            .take_over_session(&mut *taken.lock().await, 0)
+            .await
+            .unwrap();
+        // the old session was saved and can't save again when dropped
+        assert!(!resumed);
+        assert!(old.lock().await.character.is_none());
+    }
+}
@@ -1,5 +1,6 @@
-use crate::Error;
+use crate::{announcements::AnnouncementSettings, Error};
 use clap::Parser;
+use pso2packetlib::protocol::{questlist::QuestType, PacketType};
 use rsa::{
     pkcs8::{DecodePrivateKey, EncodePrivateKey},
     RsaPrivateKey,
@@ -25,6 +26,36 @@ pub struct Settings {
     pub log_dir: String,
     pub file_log_level: log::LevelFilter,
     pub console_log_level: log::LevelFilter,
+    /// Delay between the shutdown request and the disconnection of players (in seconds)
+    pub shutdown_countdown: u64,
+    /// How often modified characters are saved (in seconds). 0 disables autosaving
+    pub autosave_interval: u64,
+    /// How long the party, map and quest of a disconnected player are kept (in seconds).
+    /// 0 disables session resumption
+    pub reconnect_grace: u64,
+    /// Enable the interactive operator console on stdin
+    pub console: bool,
+    /// Location of the operator console socket (unix only)
+    pub console_socket: Option<String>,
+    /// Uncompiled data directory of the developer mode, edited Lua scripts are reloaded
+    pub dev_data_dir: Option<String>,
+    /// Directory of the server-wide Lua plugins loaded at startup
+    pub plugin_dir: Option<String>,
+    pub rate_limits: RateLimitSettings,
+    pub validation: ValidationSettings,
+    pub lua_limits: LuaLimitSettings,
+    pub capture: CaptureSettings,
+    pub chat_log: ChatLogSettings,
+    pub announcements: Vec<AnnouncementSettings>,
+
+    #[serde(skip)]
+    pub account_transfer: Option<AccountTransfer>,
+}
+
+/// One-off account operation requested from the command line.
+pub enum AccountTransfer {
+    Export { id: u32, path: String },
+    Import { path: String },
 }
 
 #[derive(Parser, Debug)]
@@ -75,6 +106,27 @@ struct Args {
     /// Location of complied server data file
     #[arg(short, long)]
     data_path: Option<String>,
+    /// Don't start the interactive console
+    #[arg(long, default_value_t = false)]
+    headless: bool,
+    /// Location of the operator console socket
+    #[arg(long)]
+    console_socket: Option<String>,
+    /// Location of the uncompiled data directory (enables Lua script reloading)
+    #[arg(long)]
+    dev_data_dir: Option<String>,
+    /// Directory of the Lua plugins
+    #[arg(long)]
+    plugin_dir: Option<String>,
+    /// Export the account with this player ID to an archive and exit
+    #[arg(long, requires = "archive_path")]
+    export_account: Option<u32>,
+    /// Import the account from an archive and exit
+    #[arg(long, requires = "archive_path", conflicts_with = "export_account")]
+    import_account: bool,
+    /// Location of the account archive
+    #[arg(long)]
+    archive_path: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -84,6 +136,143 @@ pub struct BlockSettings {
     pub name: String,
     pub max_players: u32,
     pub lobby_map: String,
+    /// Relative share of players sent to this block by the balancer. 0 excludes the block
+    pub balance_weight: f32,
+    /// Preferred blocks are filled before the other ones
+    pub preferred: bool,
+    pub block_type: BlockType,
+    /// Record every connection to this block
+    pub capture: bool,
+}
+
+/// Packet rate limits of a single connection.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    /// What happens to clients that exceed the limits
+    pub action: RateLimitAction,
+    pub chat: RateLimit,
+    pub symbol_art: RateLimit,
+    pub movement: RateLimit,
+    pub other: RateLimit,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Sustained number of packets per second, 0 disables the limit
+    pub rate: f32,
+    /// Maximum number of packets in a burst
+    pub burst: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitAction {
+    /// Excess chat, symbol art and movement packets are ignored, other packets disconnect.
+    #[default]
+    Drop,
+    /// Client is disconnected.
+    Kick,
+}
+
+/// Limits used to detect modified clients.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ValidationSettings {
+    pub enabled: bool,
+    /// Maximum movement speed (in units per second)
+    pub max_speed: f32,
+    /// Maximum distance between the player and the hit position
+    pub max_attack_range: f32,
+    /// Sustained number of damage packets per second
+    pub damage_rate: f32,
+    /// Maximum number of damage packets in a burst
+    pub damage_burst: u32,
+    /// Number of suspicious events before the player is kicked, 0 disables kicking
+    pub kick_threshold: u32,
+    /// Time after which a suspicious event no longer counts towards a kick (in seconds), 0 counts
+    /// every event of the session
+    pub strike_window: u64,
+}
+
+/// Resource budgets of map Lua scripts.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LuaLimitSettings {
+    /// Maximum number of instructions executed by a single script call, 0 disables the limit
+    pub instructions: u64,
+    /// Maximum memory used by the scripts of a single map (in bytes), 0 disables the limit
+    pub memory: usize,
+}
+
+/// Recording of client sessions to PPAC files.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaptureSettings {
+    /// Directory where the captures are stored
+    pub directory: String,
+    /// Player IDs of accounts that are recorded on every login
+    pub accounts: Vec<u32>,
+}
+
+/// Log of every chat message, including whispers.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatLogSettings {
+    pub enabled: bool,
+    /// Location of the current log file
+    pub file: String,
+    /// Size after which the file is rotated (in bytes), 0 disables rotation
+    pub max_size: u64,
+    /// Number of rotated files that are kept
+    pub max_files: u32,
+}
+
+/// Mode of the block, restricts available content and who can enter.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockType {
+    /// Regular block, every quest type is available.
+    #[default]
+    Normal,
+    /// Battle arena block, only battle quests are available and PvP is allowed.
+    Battle,
+    /// Challenge quest block, only challenge quests are available.
+    Challenge,
+    /// Only GMs can enter.
+    GmOnly,
+    /// Only Vita clients can enter.
+    VitaOnly,
+}
+
+impl BlockType {
+    pub fn is_quest_allowed(self, quest_type: QuestType) -> bool {
+        match self {
+            Self::Battle => quest_type == QuestType::BattleBroken,
+            Self::Challenge => quest_type == QuestType::Challenge,
+            _ => true,
+        }
+    }
+    pub const fn is_pvp_allowed(self) -> bool {
+        matches!(self, Self::Battle)
+    }
+    pub fn can_enter(self, isgm: bool, packet_type: PacketType) -> bool {
+        match self {
+            Self::GmOnly => isgm,
+            Self::VitaOnly => isgm || packet_type == PacketType::Vita,
+            _ => true,
+        }
+    }
+    /// Name of the block as shown in the block list.
+    pub fn display_name(self, name: &str) -> String {
+        match self {
+            Self::Normal => name.to_string(),
+            Self::Battle => format!("{name} (Battle)"),
+            Self::Challenge => format!("{name} (Challenge)"),
+            Self::GmOnly => format!("{name} (GM)"),
+            Self::VitaOnly => format!("{name} (Vita)"),
+        }
+    }
 }
 
 macro_rules! args_to_settings {
@@ -120,6 +309,20 @@ impl Settings {
         args_to_settings!(args.file_log_level => settings.file_log_level);
         args_to_settings!(args.console_log_level => settings.console_log_level);
         settings.data_file = args.data_path.or(settings.data_file);
+        settings.console &= !args.headless;
+        settings.console_socket = args.console_socket.or(settings.console_socket);
+        settings.dev_data_dir = args.dev_data_dir.or(settings.dev_data_dir);
+        settings.plugin_dir = args.plugin_dir.or(settings.plugin_dir);
+        settings.account_transfer = match (args.export_account, args.import_account) {
+            (Some(id), _) => Some(AccountTransfer::Export {
+                id,
+                path: args.archive_path.unwrap_or_default(),
+            }),
+            (None, true) => Some(AccountTransfer::Import {
+                path: args.archive_path.unwrap_or_default(),
+            }),
+            (None, false) => None,
+        };
 
         Ok(settings)
     }
@@ -168,6 +371,64 @@ impl Default for Settings {
             log_dir: String::from("logs"),
             file_log_level: log::LevelFilter::Info,
             console_log_level: log::LevelFilter::Debug,
+            shutdown_countdown: 30,
+            autosave_interval: 300,
+            reconnect_grace: 120,
+            console: true,
+            console_socket: None,
+            dev_data_dir: None,
+            plugin_dir: None,
+            rate_limits: Default::default(),
+            validation: Default::default(),
+            lua_limits: Default::default(),
+            capture: Default::default(),
+            chat_log: Default::default(),
+            announcements: vec![],
+            account_transfer: None,
+        }
+    }
+}
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        Self {
+            action: RateLimitAction::Drop,
+            chat: RateLimit {
+                rate: 2.0,
+                burst: 5,
+            },
+            symbol_art: RateLimit {
+                rate: 1.0,
+                burst: 5,
+            },
+            movement: RateLimit {
+                rate: 30.0,
+                burst: 60,
+            },
+            other: RateLimit {
+                rate: 50.0,
+                burst: 100,
+            },
+        }
+    }
+}
+impl Default for ValidationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_speed: 60.0,
+            max_attack_range: 100.0,
+            damage_rate: 30.0,
+            damage_burst: 60,
+            kick_threshold: 20,
+            strike_window: 600,
+        }
+    }
+}
+impl Default for LuaLimitSettings {
+    fn default() -> Self {
+        Self {
+            instructions: 10_000_000,
+            memory: 16 * 1024 * 1024,
         }
     }
 }
@@ -178,6 +439,29 @@ impl Default for BlockSettings {
             name: "Block 1".to_string(),
             max_players: 32,
             lobby_map: "lobby".to_string(),
+            balance_weight: 1.0,
+            preferred: false,
+            block_type: BlockType::Normal,
+            capture: false,
+        }
+    }
+}
+impl Default for CaptureSettings {
+    fn default() -> Self {
+        Self {
+            directory: "captures".to_string(),
+            accounts: vec![],
+        }
+    }
+}
+
+impl Default for ChatLogSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            file: "logs/chat.log".to_string(),
+            max_size: 10 * 1024 * 1024,
+            max_files: 10,
         }
     }
 }
@@ -196,3 +480,46 @@ impl Settings {
         Ok(settings)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::BlockType;
+    use pso2packetlib::protocol::{questlist::QuestType, PacketType};
+
+    #[test]
+    fn test_block_quests() {
+        let normal = BlockType::Normal;
+        assert!(normal.is_quest_allowed(QuestType::Extreme));
+        // existing deployments only have normal blocks
+        assert!(normal.is_quest_allowed(QuestType::Challenge));
+        assert!(normal.is_quest_allowed(QuestType::BattleBroken));
+        assert!(BlockType::Battle.is_quest_allowed(QuestType::BattleBroken));
+        assert!(!BlockType::Battle.is_quest_allowed(QuestType::Extreme));
+        assert!(BlockType::Challenge.is_quest_allowed(QuestType::Challenge));
+        assert!(!BlockType::Challenge.is_quest_allowed(QuestType::BattleBroken));
+        assert!(BlockType::GmOnly.is_quest_allowed(QuestType::Extreme));
+        assert!(BlockType::GmOnly.is_quest_allowed(QuestType::Challenge));
+        assert!(BlockType::Battle.is_pvp_allowed());
+        assert!(!normal.is_pvp_allowed());
+    }
+
+    #[test]
+    fn test_block_entry() {
+        assert!(BlockType::Normal.can_enter(false, PacketType::NGS));
+        assert!(BlockType::Battle.can_enter(false, PacketType::Vita));
+        assert!(!BlockType::GmOnly.can_enter(false, PacketType::NGS));
+        assert!(BlockType::GmOnly.can_enter(true, PacketType::NGS));
+        assert!(!BlockType::VitaOnly.can_enter(false, PacketType::NA));
+        assert!(BlockType::VitaOnly.can_enter(false, PacketType::Vita));
+        assert!(BlockType::VitaOnly.can_enter(true, PacketType::JP));
+    }
+
+    #[test]
+    fn test_block_names() {
+        assert_eq!(BlockType::Normal.display_name("Block 1"), "Block 1");
+        assert_eq!(BlockType::Battle.display_name("B"), "B (Battle)");
+        assert_eq!(BlockType::Challenge.display_name("B"), "B (Challenge)");
+        assert_eq!(BlockType::GmOnly.display_name("B"), "B (GM)");
+        assert_eq!(BlockType::VitaOnly.display_name("B"), "B (Vita)");
+    }
+}
@@ -1,8 +1,15 @@
-use crate::{inventory::Inventory, master_conn::MasterConnection, palette::Palette, Error};
+use crate::{
+    inventory::Inventory, master_conn::MasterConnection, palette::Palette,
+    script_store::ScriptStore, Error,
+};
 use data_structs::{
     flags::Flags,
     inventory::AccountStorages,
-    master_ship::{MasterShipAction, SetNicknameResult, UserCreds, UserLoginResult},
+    master_ship::{
+        AccountExport, ImportAccountResult, MasterShipAction, PermissionLevel, ServerDataResult,
+        SetNicknameResult, UserCreds, UserLoginResult,
+    },
+    ServerData,
 };
 use pso2packetlib::{
     protocol::{
@@ -27,10 +34,16 @@ pub struct User {
     pub lang: Language,
     pub packet_type: PacketType,
     pub accountflags: Flags,
-    pub isgm: bool,
+    pub permission: PermissionLevel,
     pub last_uuid: u64,
 }
 
+impl User {
+    pub fn is_gm(&self) -> bool {
+        self.permission >= PermissionLevel::Gm
+    }
+}
+
 #[derive(Default, serde::Serialize, serde::Deserialize)]
 #[serde(default)]
 struct UserData {
@@ -38,6 +51,8 @@ struct UserData {
     symbol_arts: Vec<u128>,
     unlocked_quests: Vec<u32>,
     unlocked_quests_notif: Vec<u32>,
+    /// Team used by the alliance chat channel.
+    team: Option<String>,
 }
 
 #[derive(Default, serde::Serialize, serde::Deserialize, Clone)]
@@ -50,6 +65,29 @@ pub struct CharData {
     pub unlocked_quests: Vec<u32>,
     pub unlocked_quests_notif: Vec<u32>,
     pub play_time: Duration,
+    /// Story state saved by Lua scripts.
+    pub script_store: ScriptStore,
+}
+
+/// Portable copy of an account, including the master ship data.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct AccountArchive {
+    pub master: AccountExport,
+    pub characters: Vec<CharData>,
+    pub symbol_art_list: Vec<u128>,
+    pub symbol_arts: Vec<SymbolArtEntry>,
+    pub unlocked_quests: Vec<u32>,
+    pub unlocked_quests_notif: Vec<u32>,
+    pub team: Option<String>,
+}
+
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SymbolArtEntry {
+    pub uuid: u128,
+    pub name: String,
+    pub data: Vec<u8>,
 }
 
 #[derive(Default, serde::Serialize, serde::Deserialize)]
@@ -74,9 +112,31 @@ impl Sql {
         })
     }
 
+    /// Database that only lives in memory, used by tests.
+    #[cfg(test)]
+    pub async fn in_memory(master_ship: MasterConnection) -> Result<Self, Error> {
+        // every connection opens a new in-memory database
+        let conn = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await?;
+        Self::create_tables(&conn).await?;
+        Ok(Self {
+            connection: conn,
+            master_ship,
+        })
+    }
+
     async fn create_db(path: &str) -> Result<sqlx::SqlitePool, Error> {
         sqlx::Sqlite::create_database(path).await?;
         let conn = sqlx::SqlitePool::connect(path).await?;
+        Self::create_tables(&conn).await?;
+        Ok(conn)
+    }
+
+    async fn create_tables(conn: &sqlx::SqlitePool) -> Result<(), Error> {
         conn.execute(
             "
             create table if not exists Users (
@@ -114,12 +174,60 @@ impl Sql {
         ",
         )
         .await?;
-        Ok(conn)
+        let has_teams = sqlx::query(
+            "select count(*) from sqlite_master where type = 'table' and name = 'Teams'",
+        )
+        .fetch_one(conn)
+        .await?
+        .try_get::<i64, _>(0)?
+            != 0;
+        conn.execute(
+            "
+            create table if not exists Teams (
+                Name text primary key,
+                Members integer
+            );
+        ",
+        )
+        .await?;
+        if !has_teams {
+            // teams joined before their names were reserved
+            for row in sqlx::query("select Data from Users")
+                .fetch_all(conn)
+                .await?
+            {
+                let user_data: UserData = rmp_serde::from_slice(row.try_get("Data")?)?;
+                if let Some(team) = user_data.team {
+                    sqlx::query(
+                        "insert into Teams (Name, Members) values (?, 1) \
+                         on conflict (Name) do update set Members = Members + 1",
+                    )
+                    .bind(team)
+                    .execute(conn)
+                    .await?;
+                }
+            }
+        }
+        Ok(())
     }
 
     pub async fn run_action(&self, action: MasterShipAction) -> Result<MasterShipAction, Error> {
         self.master_ship.run_action(action).await
     }
+    pub async fn get_server_data(&self) -> Result<ServerData, Error> {
+        match self.run_action(MasterShipAction::ServerDataRequest).await? {
+            MasterShipAction::ServerDataResponse(ServerDataResult::Ok(data)) => Ok(*data),
+            MasterShipAction::ServerDataResponse(ServerDataResult::NotAvailable) => {
+                log::error!("No data available from master ship!");
+                Err(Error::NoShipData)
+            }
+            MasterShipAction::Error(e) => Err(Error::MSError(e)),
+            _ => Err(Error::MSUnexpected),
+        }
+    }
+    pub async fn unregister_ship(&self) -> Result<(), Error> {
+        self.master_ship.unregister_ship().await
+    }
 
     pub async fn get_sega_user(
         &self,
@@ -139,7 +247,7 @@ impl Sql {
                 id,
                 nickname,
                 accountflags,
-                isgm,
+                permission,
                 last_uuid,
             }) => {
                 let _: UserData = if let Some(row) =
@@ -157,7 +265,7 @@ impl Sql {
                     id,
                     nickname,
                     accountflags,
-                    isgm,
+                    permission,
                     last_uuid,
                     ..Default::default()
                 })
@@ -168,6 +276,9 @@ impl Sql {
             MasterShipAction::UserLoginResult(UserLoginResult::NotFound) => {
                 self.create_sega_user(username, password).await
             }
+            MasterShipAction::UserLoginResult(UserLoginResult::Banned { .. }) => {
+                Err(Error::AccountBanned)
+            }
             MasterShipAction::Error(e) => Err(Error::MSError(e)),
             _ => Err(Error::MSUnexpected),
         }
@@ -185,7 +296,7 @@ impl Sql {
                 id,
                 nickname,
                 accountflags,
-                isgm,
+                permission,
                 last_uuid,
             }) => {
                 let _: UserData = if let Some(row) =
@@ -203,7 +314,7 @@ impl Sql {
                     id,
                     nickname,
                     accountflags,
-                    isgm,
+                    permission,
                     last_uuid,
                     ..Default::default()
                 })
@@ -214,6 +325,9 @@ impl Sql {
             MasterShipAction::UserLoginResult(UserLoginResult::NotFound) => {
                 self.create_psn_user(username).await
             }
+            MasterShipAction::UserLoginResult(UserLoginResult::Banned { .. }) => {
+                Err(Error::AccountBanned)
+            }
             MasterShipAction::Error(e) => Err(Error::MSError(e)),
             _ => Err(Error::MSUnexpected),
         }
@@ -231,13 +345,13 @@ impl Sql {
                 id,
                 nickname,
                 accountflags,
-                isgm,
+                permission,
                 last_uuid,
             }) => Ok(User {
                 id,
                 nickname,
                 accountflags,
-                isgm,
+                permission,
                 last_uuid,
                 ..Default::default()
             }),
@@ -260,13 +374,13 @@ impl Sql {
                 id,
                 nickname,
                 accountflags,
-                isgm,
+                permission,
                 last_uuid,
             }) => Ok(User {
                 id,
                 nickname,
                 accountflags,
-                isgm,
+                permission,
                 last_uuid,
                 ..Default::default()
             }),
@@ -320,6 +434,54 @@ impl Sql {
             _ => Err(Error::MSUnexpected),
         }
     }
+    pub async fn get_permission(&self, user_id: u32) -> Result<PermissionLevel, Error> {
+        let result = self
+            .run_action(MasterShipAction::GetPermission(user_id))
+            .await?;
+        match result {
+            MasterShipAction::GetPermissionResult(p) => Ok(p),
+            MasterShipAction::Error(e) => Err(Error::MSError(e)),
+            _ => Err(Error::MSUnexpected),
+        }
+    }
+    pub async fn set_permission(
+        &self,
+        user_id: u32,
+        permission: PermissionLevel,
+    ) -> Result<(), Error> {
+        let result = self
+            .run_action(MasterShipAction::SetPermission {
+                id: user_id,
+                permission,
+            })
+            .await?;
+        match result {
+            MasterShipAction::Ok => Ok(()),
+            MasterShipAction::Error(e) => Err(Error::MSError(e)),
+            _ => Err(Error::MSUnexpected),
+        }
+    }
+    /// Bans the account until the Unix timestamp (in seconds), `None` bans permanently.
+    pub async fn ban_user(&self, user_id: u32, until: Option<u64>) -> Result<(), Error> {
+        let result = self
+            .run_action(MasterShipAction::BanUser { id: user_id, until })
+            .await?;
+        match result {
+            MasterShipAction::Ok => Ok(()),
+            MasterShipAction::Error(e) => Err(Error::MSError(e)),
+            _ => Err(Error::MSUnexpected),
+        }
+    }
+    pub async fn unban_user(&self, user_id: u32) -> Result<(), Error> {
+        let result = self
+            .run_action(MasterShipAction::UnbanUser(user_id))
+            .await?;
+        match result {
+            MasterShipAction::Ok => Ok(()),
+            MasterShipAction::Error(e) => Err(Error::MSError(e)),
+            _ => Err(Error::MSUnexpected),
+        }
+    }
     pub async fn new_challenge(
         &self,
         user_id: u32,
@@ -353,7 +515,7 @@ impl Sql {
                 id,
                 nickname,
                 accountflags,
-                isgm,
+                permission,
                 last_uuid,
             }) => {
                 let row = sqlx::query("select * from Challenges where Challenge = ?")
@@ -375,7 +537,7 @@ impl Sql {
                     lang: challenge_data.lang,
                     packet_type: challenge_data.packet_type,
                     accountflags,
-                    isgm,
+                    permission,
                     last_uuid,
                 })
             }
@@ -383,6 +545,9 @@ impl Sql {
                 Err(Error::MSUnexpected)
             }
             MasterShipAction::UserLoginResult(UserLoginResult::NotFound) => Err(Error::NoUser),
+            MasterShipAction::UserLoginResult(UserLoginResult::Banned { .. }) => {
+                Err(Error::AccountBanned)
+            }
             MasterShipAction::Error(e) => Err(Error::MSError(e)),
             _ => Err(Error::MSUnexpected),
         }
@@ -505,6 +670,64 @@ impl Sql {
         self.update_userdata(id, |user_data| user_data.symbol_arts = uuids)
             .await
     }
+    pub async fn get_team(&self, id: u32) -> Result<Option<String>, Error> {
+        let row = sqlx::query("select Data from Users where Id = ?")
+            .bind(id as i64)
+            .fetch_one(&self.connection)
+            .await?;
+        let user_data: UserData = rmp_serde::from_slice(row.try_get("Data")?)?;
+        Ok(user_data.team)
+    }
+    /// Moves the user to the team, returns `false` if the team doesn't exist. Teams are deleted
+    /// when their last member leaves.
+    pub async fn set_team(&self, id: u32, team: Option<String>) -> Result<bool, Error> {
+        let mut transaction = self.connection.begin().await?;
+        let row = sqlx::query("select Data from Users where Id = ?")
+            .bind(id as i64)
+            .fetch_one(&mut *transaction)
+            .await?;
+        let mut user_data: UserData = rmp_serde::from_slice(row.try_get("Data")?)?;
+        if user_data.team == team {
+            return Ok(true);
+        }
+        if let Some(new_team) = &team {
+            let joined = sqlx::query("update Teams set Members = Members + 1 where Name = ?")
+                .bind(new_team)
+                .execute(&mut *transaction)
+                .await?
+                .rows_affected()
+                == 1;
+            if !joined {
+                return Ok(false);
+            }
+        }
+        if let Some(old_team) = &user_data.team {
+            sqlx::query("update Teams set Members = Members - 1 where Name = ?")
+                .bind(old_team)
+                .execute(&mut *transaction)
+                .await?;
+            sqlx::query("delete from Teams where Name = ? and Members <= 0")
+                .bind(old_team)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        user_data.team = team;
+        sqlx::query("update Users set Data = ? where Id = ?")
+            .bind(rmp_serde::to_vec(&user_data)?)
+            .bind(id as i64)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(true)
+    }
+    /// Reserves the team name without members, returns `false` if the team already exists.
+    pub async fn create_team(&self, name: &str) -> Result<bool, Error> {
+        let result = sqlx::query("insert or ignore into Teams (Name, Members) values (?, 0)")
+            .bind(name)
+            .execute(&self.connection)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
     pub async fn get_symbol_art(&self, uuid: u128) -> Result<Option<Vec<u8>>, Error> {
         let row = sqlx::query("select * from SymbolArts where UUID = ?")
             .bind(format!("{uuid:X}").as_bytes())
@@ -574,6 +797,149 @@ impl Sql {
             _ => Err(Error::MSUnexpected),
         }
     }
+    pub async fn export_account(&self, user_id: u32) -> Result<AccountArchive, Error> {
+        let master = match self
+            .run_action(MasterShipAction::ExportAccount(user_id))
+            .await?
+        {
+            MasterShipAction::ExportAccountResult(data) => *data,
+            MasterShipAction::Error(e) => return Err(Error::MSError(e)),
+            _ => return Err(Error::MSUnexpected),
+        };
+        let Some(row) = sqlx::query("select Data from Users where Id = ?")
+            .bind(user_id as i64)
+            .fetch_optional(&self.connection)
+            .await?
+        else {
+            return Err(Error::NoUser);
+        };
+        let user_data: UserData = rmp_serde::from_slice(row.try_get("Data")?)?;
+        let mut symbol_arts = vec![];
+        for &uuid in user_data.symbol_arts.iter().filter(|&&u| u != 0) {
+            let row = sqlx::query("select * from SymbolArts where UUID = ?")
+                .bind(format!("{uuid:X}").as_bytes())
+                .fetch_optional(&self.connection)
+                .await?;
+            if let Some(row) = row {
+                symbol_arts.push(SymbolArtEntry {
+                    uuid,
+                    name: String::from_utf8_lossy(row.try_get("Name")?).into_owned(),
+                    data: row.try_get("Data")?,
+                });
+            }
+        }
+        Ok(AccountArchive {
+            master,
+            characters: self.get_characters(user_id).await?,
+            symbol_art_list: user_data.symbol_arts,
+            symbol_arts,
+            unlocked_quests: user_data.unlocked_quests,
+            unlocked_quests_notif: user_data.unlocked_quests_notif,
+            team: user_data.team,
+        })
+    }
+    /// Creates a new account from the archive. Returns the new player id.
+    ///
+    /// The local data is written in a single transaction and the master account is deleted
+    /// again if it fails, so a failed import doesn't leave half of an account behind.
+    pub async fn import_account(&self, archive: AccountArchive) -> Result<u32, Error> {
+        let characters = archive
+            .characters
+            .iter()
+            .map(rmp_serde::to_vec)
+            .collect::<Result<Vec<_>, _>>()?;
+        let id = match self
+            .run_action(MasterShipAction::ImportAccount(Box::new(archive.master)))
+            .await?
+        {
+            MasterShipAction::ImportAccountResult(ImportAccountResult::Success(id)) => id,
+            MasterShipAction::ImportAccountResult(ImportAccountResult::AlreadyExists) => {
+                return Err(Error::UserExists)
+            }
+            MasterShipAction::Error(e) => return Err(Error::MSError(e)),
+            _ => return Err(Error::MSUnexpected),
+        };
+        let user_data = UserData {
+            character_ids: vec![],
+            symbol_arts: archive.symbol_art_list,
+            unlocked_quests: archive.unlocked_quests,
+            unlocked_quests_notif: archive.unlocked_quests_notif,
+            team: archive.team,
+        };
+        let result = self
+            .import_local(id, user_data, characters, archive.symbol_arts)
+            .await;
+        if let Err(e) = result {
+            if let Err(delete_e) = self.delete_master_account(id).await {
+                log::error!("Failed to delete imported master account {id}: {delete_e}");
+            }
+            return Err(e);
+        }
+        Ok(id)
+    }
+    async fn import_local(
+        &self,
+        id: u32,
+        mut user_data: UserData,
+        characters: Vec<Vec<u8>>,
+        symbol_arts: Vec<SymbolArtEntry>,
+    ) -> Result<(), Error> {
+        let mut transaction = self.connection.begin().await?;
+        if let Some(team) = &user_data.team {
+            // existing teams can only be joined with an invite
+            let created = sqlx::query("insert or ignore into Teams (Name, Members) values (?, 1)")
+                .bind(team)
+                .execute(&mut *transaction)
+                .await?
+                .rows_affected()
+                == 1;
+            if !created {
+                user_data.team = None;
+            }
+        }
+        for sa in symbol_arts {
+            let uuid = format!("{:X}", sa.uuid);
+            let exists = sqlx::query("select count(*) from SymbolArts where UUID = ?")
+                .bind(uuid.as_bytes())
+                .fetch_one(&mut *transaction)
+                .await?
+                .try_get::<i64, _>(0)?
+                != 0;
+            if !exists {
+                sqlx::query("insert into SymbolArts (UUID, Name, Data) values (?, ?, ?)")
+                    .bind(uuid.as_bytes())
+                    .bind(sa.name.as_bytes())
+                    .bind(&sa.data)
+                    .execute(&mut *transaction)
+                    .await?;
+            }
+        }
+        for data in characters {
+            let char_id = sqlx::query("insert into Characters (Data) values (?) returning Id")
+                .bind(&data)
+                .fetch_one(&mut *transaction)
+                .await?
+                .try_get::<i64, _>("Id")?;
+            user_data.character_ids.push(char_id as u32);
+        }
+        sqlx::query("insert into Users (Id, Data) values (?,?)")
+            .bind(id as i64)
+            .bind(rmp_serde::to_vec(&user_data)?)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+    async fn delete_master_account(&self, user_id: u32) -> Result<(), Error> {
+        match self
+            .run_action(MasterShipAction::DeleteAccount(user_id))
+            .await?
+        {
+            MasterShipAction::Ok => Ok(()),
+            MasterShipAction::Error(e) => Err(Error::MSError(e)),
+            _ => Err(Error::MSUnexpected),
+        }
+    }
     pub async fn set_account_data(&self, data: User) -> Result<(), Error> {
         self.put_account_flags(data.id, data.accountflags).await?;
         self.put_uuid(data.id, data.last_uuid).await?;
@@ -599,3 +965,104 @@ impl Sql {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{CharData, Sql, UserData};
+    use crate::master_conn::MasterConnection;
+    use data_structs::master_ship::{AccountExport, ImportAccountResult, MasterShipAction as MAS};
+    use sqlx::Row;
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    #[tokio::test]
+    async fn test_account_round_trip() {
+        let deleted = Arc::new(AtomicU32::new(0));
+        let deleted_master = deleted.clone();
+        let master = MasterConnection::fake(move |action| match action {
+            MAS::ExportAccount(_) => MAS::ExportAccountResult(Box::new(AccountExport {
+                username: "user".into(),
+                ..Default::default()
+            })),
+            MAS::ImportAccount(data) if data.username == "user" => {
+                MAS::ImportAccountResult(ImportAccountResult::Success(2))
+            }
+            MAS::DeleteAccount(id) => {
+                deleted_master.store(id, Ordering::Relaxed);
+                MAS::Ok
+            }
+            _ => MAS::Error("unexpected".into()),
+        });
+        let db = Sql::in_memory(master).await.expect("DB creation failed");
+
+        sqlx::query("insert into Users (Id, Data) values (1, ?)")
+            .bind(rmp_serde::to_vec(&UserData::default()).unwrap())
+            .execute(&db.connection)
+            .await
+            .unwrap();
+        let mut char = CharData::default();
+        char.character.name = "Character".into();
+        char.unlocked_quests = vec![1, 2];
+        db.put_character(1, char).await.unwrap();
+        db.add_symbol_art(5, &[1, 2, 3], "art").await.unwrap();
+        db.set_symbol_art_list(vec![5, 0], 1).await.unwrap();
+        db.create_team("team").await.unwrap();
+        db.set_team(1, Some("team".into())).await.unwrap();
+
+        let archive = db.export_account(1).await.expect("Export failed");
+        let bytes = rmp_serde::to_vec(&archive).unwrap();
+        // the last member left, so the imported account can take the team name
+        assert!(db.set_team(1, None).await.unwrap());
+        let id = db.import_account(archive).await.expect("Import failed");
+        assert_eq!(id, 2);
+        let imported = db.export_account(2).await.unwrap();
+        assert_eq!(imported.characters.len(), 1);
+        assert_eq!(imported.characters[0].character.name, "Character");
+        assert_eq!(imported.characters[0].unlocked_quests, [1, 2]);
+        assert_eq!(imported.symbol_art_list, [5, 0]);
+        assert_eq!(imported.symbol_arts.len(), 1);
+        assert_eq!(imported.symbol_arts[0].data, [1, 2, 3]);
+        assert_eq!(imported.team.as_deref(), Some("team"));
+        assert_eq!(deleted.load(Ordering::Relaxed), 0);
+
+        // the master returns an id that already exists locally, nothing should be imported
+        let archive = rmp_serde::from_slice(&bytes).unwrap();
+        assert!(db.import_account(archive).await.is_err());
+        assert_eq!(deleted.load(Ordering::Relaxed), 2);
+        let chars = sqlx::query("select count(*) from Characters")
+            .fetch_one(&db.connection)
+            .await
+            .unwrap()
+            .try_get::<i64, _>(0)
+            .unwrap();
+        assert_eq!(chars, 2);
+    }
+
+    #[tokio::test]
+    async fn test_teams() {
+        let master = MasterConnection::fake(|_| MAS::Error("unexpected".into()));
+        let db = Sql::in_memory(master).await.expect("DB creation failed");
+        assert!(db.create_team("team").await.unwrap());
+        assert!(!db.create_team("team").await.unwrap());
+        assert!(db.create_team("other team").await.unwrap());
+        for id in [1, 2] {
+            sqlx::query("insert into Users (Id, Data) values (?, ?)")
+                .bind(id)
+                .bind(rmp_serde::to_vec(&UserData::default()).unwrap())
+                .execute(&db.connection)
+                .await
+                .unwrap();
+        }
+        assert!(db.set_team(1, Some("team".into())).await.unwrap());
+        assert!(db.set_team(2, Some("team".into())).await.unwrap());
+        assert!(!db.set_team(1, Some("missing".into())).await.unwrap());
+        assert_eq!(db.get_team(1).await.unwrap().as_deref(), Some("team"));
+
+        // the team is deleted when the last member leaves
+        assert!(db.set_team(1, Some("other team".into())).await.unwrap());
+        assert!(db.set_team(2, None).await.unwrap());
+        assert!(db.create_team("team").await.unwrap());
+    }
+}
@@ -1,11 +1,57 @@
 use super::HResult;
-use crate::{mutex::MutexGuard, user::User, Action};
+use crate::{
+    chat_log::ChatEntry,
+    moderation,
+    mutex::{Mutex, MutexGuard},
+    plugins::CommandCheck,
+    user::{User, UserState},
+    Action, BlockData, Error,
+};
+use data_structs::master_ship::PermissionLevel;
 use indicatif::HumanBytes;
-use memory_stats::memory_stats;
 use pso2packetlib::protocol::{
-    chat::MessageChannel, flag::FlagType, items::ItemId, playerstatus, ObjectType, Packet,
+    chat::{ChatMessage, MessageChannel},
+    flag::FlagType,
+    items::ItemId,
+    playerstatus, ObjectHeader, ObjectType, Packet,
+};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+/// Maximum length of a team name (in characters).
+const MAX_TEAM_NAME: usize = 32;
+
+/// Required permission levels of the built-in commands. Commands missing from this table are
+/// never run as built-ins.
+const COMMAND_LEVELS: &[(&str, PermissionLevel)] = &[
+    ("!mem", PermissionLevel::Player),
+    ("!get_pos", PermissionLevel::Player),
+    ("!calc_stats", PermissionLevel::Player),
+    ("!team", PermissionLevel::Player),
+    ("!w", PermissionLevel::Player),
+    ("!get_close_obj", PermissionLevel::Moderator),
+    ("!start_con", PermissionLevel::Gm),
+    ("!start_cutscene", PermissionLevel::Gm),
+    ("!send_con", PermissionLevel::Gm),
+    ("!set_acc_flag", PermissionLevel::Gm),
+    ("!set_char_flag", PermissionLevel::Gm),
+    ("!add_item", PermissionLevel::Gm),
+    ("!change_lvl", PermissionLevel::Gm),
+    ("!force_quest", PermissionLevel::Gm),
+    ("!spawn_enemy", PermissionLevel::Gm),
+    ("!capture", PermissionLevel::Gm),
+    ("!players", PermissionLevel::Moderator),
+    ("!kick", PermissionLevel::Moderator),
+    ("!mute", PermissionLevel::Moderator),
+    ("!unmute", PermissionLevel::Moderator),
+    ("!tp", PermissionLevel::Moderator),
+    ("!summon", PermissionLevel::Moderator),
+    ("!ban", PermissionLevel::Gm),
+    ("!unban", PermissionLevel::Gm),
+];
+
 pub async fn send_chat(mut user: MutexGuard<'_, User>, packet: Packet) -> HResult {
     let Packet::ChatMessage(ref data) = packet else {
         unreachable!()
@@ -13,23 +59,33 @@ pub async fn send_chat(mut user: MutexGuard<'_, User>, packet: Packet) -> HResul
     if data.message.starts_with('!') {
         let mut args = data.message.split(' ');
         let cmd = args.next().expect("Should always contain some data");
+        let level = user.user_data.permission;
+        let Some(required) = command_level(cmd) else {
+            plugin_command(user, cmd, args).await?;
+            return Ok(Action::Nothing);
+        };
+        if level < required {
+            log_denied(&user, cmd);
+            user.send_system_msg("command_denied", &[]).await?;
+            return Ok(Action::Nothing);
+        }
+        // commands can change the character, unlike plain chat messages
+        user.dirty = true;
         match cmd {
-            "!mem" => {
-                let mem_data_msg = if let Some(mem) = memory_stats() {
-                    format!(
-                        "Physical memory: {}\nVirtual memory: {}",
-                        HumanBytes(mem.physical_mem as u64),
-                        HumanBytes(mem.virtual_mem as u64),
-                    )
-                } else {
-                    "Couldn't gather memory info".into()
-                };
-                user.send_system_msg(&mem_data_msg).await?;
-            }
+            "!mem" => match memory_stats::memory_stats() {
+                Some(mem) => {
+                    let physical = HumanBytes(mem.physical_mem as u64);
+                    let virtual_mem = HumanBytes(mem.virtual_mem as u64);
+                    let args: [(_, &(dyn std::fmt::Display + Sync)); 2] =
+                        [("physical", &physical), ("virtual", &virtual_mem)];
+                    user.send_system_msg("memory_usage", &args).await?;
+                }
+                None => user.send_system_msg("memory_unavailable", &[]).await?,
+            },
             "!start_con" => {
                 let name = args.next();
                 if name.is_none() {
-                    user.send_system_msg("No concert name provided").await?;
+                    user.send_system_msg("no_concert_name", &[]).await?;
                     return Ok(Action::Nothing);
                 }
                 let name = name.unwrap();
@@ -56,7 +112,7 @@ pub async fn send_chat(mut user: MutexGuard<'_, User>, packet: Packet) -> HResul
             }
             "!start_cutscene" => {
                 let Some(name) = args.next() else {
-                    user.send_system_msg("No cutscene name provided").await?;
+                    user.send_system_msg("no_cutscene_name", &[]).await?;
                     return Ok(Action::Nothing);
                 };
                 user.send_packet(&Packet::StartCutscene(
@@ -70,7 +126,7 @@ pub async fn send_chat(mut user: MutexGuard<'_, User>, packet: Packet) -> HResul
             "!send_con" => {
                 let name = args.next();
                 if name.is_none() {
-                    user.send_system_msg("No action provided").await?;
+                    user.send_system_msg("no_action", &[]).await?;
                     return Ok(Action::Nothing);
                 }
                 let name = name.unwrap();
@@ -98,7 +154,9 @@ pub async fn send_chat(mut user: MutexGuard<'_, User>, packet: Packet) -> HResul
             "!get_pos" => {
                 let pos = user.position;
                 let pos: pso2packetlib::protocol::models::EulerPosition = pos.into();
-                user.send_system_msg(&format!("{pos:?}")).await?;
+                let pos = format!("{pos:?}");
+                user.send_system_msg("position", &[("position", &pos)])
+                    .await?;
             }
             "!get_close_obj" => {
                 let dist = args.next().and_then(|n| n.parse().ok()).unwrap_or(1.0);
@@ -110,28 +168,28 @@ pub async fn send_chat(mut user: MutexGuard<'_, User>, packet: Packet) -> HResul
                 let objs = lock.get_close_objects(mapid, |p| user.position.dist_2d(p) < dist);
                 let user_pos = user.position;
                 for obj in objs {
-                    user.send_system_msg(&format!(
-                        "Id: {}, Name: {}, Dist: {}",
-                        obj.object.id,
-                        obj.name,
-                        user_pos.dist_2d(&obj.position)
-                    ))
-                    .await?;
+                    let dist = user_pos.dist_2d(&obj.position);
+                    let args: [(_, &(dyn std::fmt::Display + Sync)); 3] = [
+                        ("id", &obj.object.id),
+                        ("name", &obj.name),
+                        ("distance", &dist),
+                    ];
+                    user.send_system_msg("close_object", &args).await?;
                 }
             }
             "!set_acc_flag" => set_flag_parse(&mut user, FlagType::Account, &mut args).await?,
             "!set_char_flag" => set_flag_parse(&mut user, FlagType::Character, &mut args).await?,
             "!add_item" => {
                 let Some(item_type) = args.next().and_then(|a| a.parse().ok()) else {
-                    user.send_system_msg("No item type provided").await?;
+                    user.send_system_msg("no_item_type", &[]).await?;
                     return Ok(Action::Nothing);
                 };
                 let Some(id) = args.next().and_then(|a| a.parse().ok()) else {
-                    user.send_system_msg("No id provided").await?;
+                    user.send_system_msg("no_item_id", &[]).await?;
                     return Ok(Action::Nothing);
                 };
                 let Some(subid) = args.next().and_then(|a| a.parse().ok()) else {
-                    user.send_system_msg("No subid provided").await?;
+                    user.send_system_msg("no_item_subid", &[]).await?;
                     return Ok(Action::Nothing);
                 };
                 let item_id = ItemId {
@@ -149,15 +207,15 @@ pub async fn send_chat(mut user: MutexGuard<'_, User>, packet: Packet) -> HResul
             }
             "!change_lvl" => {
                 let Some(level) = args.next().and_then(|a| a.parse().ok()) else {
-                    user.send_system_msg("No level provided").await?;
+                    user.send_system_msg("no_level", &[]).await?;
                     return Ok(Action::Nothing);
                 };
                 let Some(exp) = args.next().and_then(|a| a.parse().ok()) else {
-                    user.send_system_msg("No EXP provided").await?;
+                    user.send_system_msg("no_exp", &[]).await?;
                     return Ok(Action::Nothing);
                 };
                 let Some(char) = user.character.as_mut() else {
-                    user.send_system_msg("No character loaded").await?;
+                    user.send_system_msg("no_character", &[]).await?;
                     return Ok(Action::Nothing);
                 };
                 let stats = char.character.get_level_mut();
@@ -189,16 +247,17 @@ pub async fn send_chat(mut user: MutexGuard<'_, User>, packet: Packet) -> HResul
                 user.send_packet(&packet).await?;
             }
             "!calc_stats" => {
-                let msg = format!("Stats: {:?}", user.battle_stats);
-                user.send_system_msg(&msg).await?;
+                let stats = format!("{:?}", user.battle_stats);
+                user.send_system_msg("battle_stats", &[("stats", &stats)])
+                    .await?;
             }
             "!force_quest" => {
                 let Some(quest_id) = args.next().and_then(|a| a.parse().ok()) else {
-                    user.send_system_msg("No quest id provided").await?;
+                    user.send_system_msg("no_quest_id", &[]).await?;
                     return Ok(Action::Nothing);
                 };
                 let Some(diff) = args.next().and_then(|a| a.parse().ok()) else {
-                    user.send_system_msg("No difficulty provided").await?;
+                    user.send_system_msg("no_difficulty", &[]).await?;
                     return Ok(Action::Nothing);
                 };
                 let packet = pso2packetlib::protocol::questlist::AcceptQuestPacket {
@@ -214,7 +273,7 @@ pub async fn send_chat(mut user: MutexGuard<'_, User>, packet: Packet) -> HResul
             }
             "!spawn_enemy" => {
                 let Some(name) = args.next() else {
-                    user.send_system_msg("No enemy name provided").await?;
+                    user.send_system_msg("no_enemy_name", &[]).await?;
                     return Ok(Action::Nothing);
                 };
                 let map_id = user.get_zone_id();
@@ -223,11 +282,79 @@ pub async fn send_chat(mut user: MutexGuard<'_, User>, packet: Packet) -> HResul
                 drop(user);
                 map.lock().await.spawn_enemy(name, pos, map_id).await?;
             }
-            _ => user.send_system_msg("Unknown command").await?,
+            "!capture" => {
+                let enabled = match args.next() {
+                    Some("on") => true,
+                    Some("off") => false,
+                    _ => {
+                        user.send_system_msg("capture_usage", &[]).await?;
+                        return Ok(Action::Nothing);
+                    }
+                };
+                let id = user.get_user_id();
+                let target = args.next().and_then(|a| a.parse().ok()).unwrap_or(id);
+                if target == id {
+                    user.blockdata.captures.set_captured(id, enabled);
+                    user.set_capture(enabled);
+                    let msg = if enabled {
+                        "capture_enabled"
+                    } else {
+                        "capture_disabled"
+                    };
+                    user.send_system_msg(msg, &[]).await?;
+                    return Ok(Action::Nothing);
+                }
+                let blockdata = user.blockdata.clone();
+                let conn_id = user.conn_id;
+                // other users are locked by `set_capture`
+                drop(user);
+                let online = crate::capture::set_capture(&blockdata, target, enabled).await;
+                let msg = match (online, enabled) {
+                    (true, true) => "capture_player_enabled",
+                    (true, false) => "capture_player_disabled",
+                    (false, true) => "capture_offline_enabled",
+                    (false, false) => "capture_offline_disabled",
+                };
+                let this = blockdata
+                    .clients
+                    .lock()
+                    .await
+                    .iter()
+                    .find(|(c_conn_id, _)| *c_conn_id == conn_id)
+                    .map(|(_, c)| c.clone());
+                if let Some(this) = this {
+                    this.lock()
+                        .await
+                        .send_system_msg(msg, &[("player", &target)])
+                        .await?;
+                }
+            }
+            "!team" => team_command(user, &mut args).await?,
+            "!w" => whisper(user, &mut args).await?,
+            "!players" => list_players(user, args.next() == Some("block")).await?,
+            "!kick" | "!mute" | "!unmute" | "!tp" | "!summon" | "!ban" | "!unban" => {
+                moderate(user, cmd, &mut args).await?
+            }
+            _ => {
+                log::error!("Command {cmd} has a permission level but no handler");
+                user.send_system_msg("unknown_command", &[]).await?
+            }
         }
         return Ok(Action::Nothing);
     }
     let id = user.get_user_id();
+    if is_muted(&mut user).await? {
+        return Ok(Action::Nothing);
+    }
+    let channel = match data.channel {
+        MessageChannel::Map => "map",
+        MessageChannel::Party => "party",
+        MessageChannel::Alliance => "team",
+        MessageChannel::Whisper => "whisper",
+        MessageChannel::Group => "block",
+        MessageChannel::Undefined => "unknown",
+    };
+    log_chat(&user, channel, None, &data.message);
     match data.channel {
         MessageChannel::Map => {
             let map = user.get_current_map();
@@ -243,11 +370,539 @@ pub async fn send_chat(mut user: MutexGuard<'_, User>, packet: Packet) -> HResul
                 party.read().await.send_message(packet, id).await;
             }
         }
+        MessageChannel::Group => {
+            let blockdata = user.blockdata.clone();
+            drop(user);
+            send_to_block(&blockdata, packet, id, None).await;
+        }
+        MessageChannel::Alliance => {
+            let Some(team) = user.team.clone() else {
+                user.send_system_msg("no_team", &[]).await?;
+                return Ok(Action::Nothing);
+            };
+            let blocks = user.blockdata.running_blocks.read().await.clone();
+            drop(user);
+            // teams are shared by every block of the ship
+            for block in blocks {
+                send_to_block(&block, packet.clone(), id, Some(&team)).await;
+            }
+        }
+        // the receiver isn't known, whispers are sent with "!w"
         _ => {}
     }
     Ok(Action::Nothing)
 }
 
+/// Sends a chat message to every player of the block or only to the members of the team.
+async fn send_to_block(blockdata: &BlockData, mut packet: Packet, id: u32, team: Option<&str>) {
+    if let Packet::ChatMessage(ref mut data) = packet {
+        data.object = ObjectHeader {
+            id,
+            entity_type: ObjectType::Player,
+            ..Default::default()
+        };
+    }
+    let clients: Vec<_> = blockdata
+        .clients
+        .lock()
+        .await
+        .iter()
+        .map(|(_, c)| c.clone())
+        .collect();
+    for client in clients {
+        let mut client = client.lock().await;
+        if client.state != UserState::InGame {
+            continue;
+        }
+        if team.is_some_and(|team| client.team.as_deref() != Some(team)) {
+            continue;
+        }
+        let _ = client.try_send_packet(&packet);
+    }
+}
+
+/// Sends the remaining mute time if the player can't chat.
+async fn is_muted(user: &mut User) -> Result<bool, Error> {
+    let Some(remaining) = user.blockdata.mutes.remaining(user.get_user_id()) else {
+        return Ok(false);
+    };
+    let minutes = remaining.as_secs().div_ceil(60);
+    user.send_system_msg("still_muted", &[("minutes", &minutes)])
+        .await?;
+    Ok(true)
+}
+
+fn log_chat(user: &User, channel: &str, receiver_id: Option<u32>, message: &str) {
+    let sender_name = user
+        .character
+        .as_ref()
+        .map(|c| c.character.name.to_string())
+        .unwrap_or_default();
+    user.blockdata.chat_log.write(ChatEntry {
+        block_id: user.blockdata.block_id,
+        channel,
+        sender_id: user.get_user_id(),
+        sender_name: &sender_name,
+        receiver_id,
+        message,
+    });
+}
+
+/// Shows, joins or leaves the team of the alliance channel.
+async fn team_command<'a>(
+    mut user: MutexGuard<'_, User>,
+    args: &mut (impl Iterator<Item = &'a str> + Send),
+) -> Result<(), Error> {
+    let id = user.get_user_id();
+    match args.next() {
+        None => match user.team.clone() {
+            Some(team) => {
+                user.send_system_msg("team_info", &[("team", &team)])
+                    .await?
+            }
+            None => user.send_system_msg("no_team", &[]).await?,
+        },
+        Some("create") => {
+            let name = args.collect::<Vec<_>>().join(" ");
+            let name = name.trim();
+            if name.is_empty() || name.chars().count() > MAX_TEAM_NAME {
+                let max = MAX_TEAM_NAME;
+                user.send_system_msg("invalid_team_name", &[("max", &max)])
+                    .await?;
+                return Ok(());
+            }
+            if !user.blockdata.sql.create_team(name).await? {
+                user.send_system_msg("team_exists", &[("team", &name)])
+                    .await?;
+                return Ok(());
+            }
+            user.blockdata
+                .sql
+                .set_team(id, Some(name.to_string()))
+                .await?;
+            user.team = Some(name.to_string());
+            user.send_system_msg("team_created", &[("team", &name)])
+                .await?;
+        }
+        Some("invite") => team_invite(user, args.next()).await?,
+        Some("join") => {
+            // teams are only joined with an invite, so their chat stays private
+            let Some(team) = user.team_invite.take() else {
+                user.send_system_msg("no_team_invite", &[]).await?;
+                return Ok(());
+            };
+            if !user.blockdata.sql.set_team(id, Some(team.clone())).await? {
+                user.send_system_msg("team_disbanded", &[("team", &team)])
+                    .await?;
+                return Ok(());
+            }
+            user.send_system_msg("team_joined", &[("team", &team)])
+                .await?;
+            user.team = Some(team);
+        }
+        Some("leave") => {
+            if user.team.take().is_none() {
+                user.send_system_msg("no_team", &[]).await?;
+                return Ok(());
+            }
+            user.blockdata.sql.set_team(id, None).await?;
+            user.send_system_msg("team_left", &[]).await?;
+        }
+        Some(_) => user.send_system_msg("team_usage", &[]).await?,
+    }
+    Ok(())
+}
+
+/// Invites a player of the block to the team of the user.
+async fn team_invite(mut user: MutexGuard<'_, User>, target: Option<&str>) -> Result<(), Error> {
+    let Some(target_id) = target.and_then(|a| a.parse::<u32>().ok()) else {
+        user.send_system_msg("team_usage", &[]).await?;
+        return Ok(());
+    };
+    let Some(team) = user.team.clone() else {
+        user.send_system_msg("no_team", &[]).await?;
+        return Ok(());
+    };
+    let id = user.get_user_id();
+    let blockdata = user.blockdata.clone();
+    let conn_id = user.conn_id;
+    drop(user);
+    let target = moderation::find_player(&blockdata, target_id).await;
+    let Some(this) = find_conn(&blockdata, conn_id).await else {
+        return Ok(());
+    };
+    let player: [(_, &(dyn std::fmt::Display + Sync)); 1] = [("player", &target_id)];
+    let Some(target) = target else {
+        this.lock()
+            .await
+            .send_system_msg("player_not_found", &player)
+            .await?;
+        return Ok(());
+    };
+    {
+        let mut target = target.lock().await;
+        target.team_invite = Some(team.clone());
+        let args: [(_, &(dyn std::fmt::Display + Sync)); 2] = [("player", &id), ("team", &team)];
+        target.send_system_msg("team_invited", &args).await?;
+    }
+    this.lock()
+        .await
+        .send_system_msg("team_invite_sent", &player)
+        .await?;
+    Ok(())
+}
+
+/// Sends a private message to a player of the block.
+async fn whisper<'a>(
+    mut user: MutexGuard<'_, User>,
+    args: &mut (impl Iterator<Item = &'a str> + Send),
+) -> Result<(), Error> {
+    let target_id = args.next().and_then(|a| a.parse::<u32>().ok());
+    let message = args.collect::<Vec<_>>().join(" ");
+    let Some(target_id) = target_id.filter(|_| !message.is_empty()) else {
+        user.send_system_msg("whisper_usage", &[]).await?;
+        return Ok(());
+    };
+    if is_muted(&mut user).await? {
+        return Ok(());
+    }
+    log_chat(&user, "whisper", Some(target_id), &message);
+    let packet = Packet::ChatMessage(ChatMessage {
+        object: ObjectHeader {
+            id: user.get_user_id(),
+            entity_type: ObjectType::Player,
+            ..Default::default()
+        },
+        channel: MessageChannel::Whisper,
+        message,
+        ..Default::default()
+    });
+    let blockdata = user.blockdata.clone();
+    let conn_id = user.conn_id;
+    drop(user);
+    let target = moderation::find_player(&blockdata, target_id).await;
+    let Some(this) = find_conn(&blockdata, conn_id).await else {
+        return Ok(());
+    };
+    match target {
+        Some(target) => {
+            let _ = target.lock().await.try_send_packet(&packet);
+            let _ = this.lock().await.try_send_packet(&packet);
+        }
+        None => {
+            this.lock()
+                .await
+                .send_system_msg("player_not_found", &[("player", &target_id)])
+                .await?
+        }
+    }
+    Ok(())
+}
+
+/// Lists the players in the zone of the user or on the whole block.
+async fn list_players(user: MutexGuard<'_, User>, whole_block: bool) -> Result<(), Error> {
+    let this = user.get_current_map();
+    let zone_id = user.get_zone_id();
+    let blockdata = user.blockdata.clone();
+    let conn_id = user.conn_id;
+    drop(user);
+    let mut entries = vec![];
+    let clients: Vec<_> = blockdata.clients.lock().await.clone();
+    for (_, client) in clients {
+        let client = client.lock().await;
+        let Some(map) = client.get_current_map() else {
+            continue;
+        };
+        let in_zone = this
+            .as_ref()
+            .is_some_and(|m| Arc::ptr_eq(m, &map) && client.get_zone_id() == zone_id);
+        if !whole_block && !in_zone {
+            continue;
+        }
+        let name = client
+            .character
+            .as_ref()
+            .map(|c| c.character.name.to_string())
+            .unwrap_or_default();
+        entries.push((client.get_user_id(), name, map, client.get_zone_id()));
+    }
+    let Some(this) = find_conn(&blockdata, conn_id).await else {
+        return Ok(());
+    };
+    let mut lines = vec![];
+    for (id, name, map, zone_id) in entries {
+        let zone = map
+            .lock()
+            .await
+            .get_zone_name(zone_id)
+            .unwrap_or_default()
+            .to_string();
+        lines.push((id, name, zone));
+    }
+    let mut this = this.lock().await;
+    let count = lines.len();
+    let msg = if whole_block {
+        "block_players"
+    } else {
+        "zone_players"
+    };
+    this.send_system_msg(msg, &[("count", &count)]).await?;
+    for (id, name, zone) in lines {
+        let args: [(_, &(dyn std::fmt::Display + Sync)); 3] =
+            [("player", &id), ("name", &name), ("zone", &zone)];
+        this.send_system_msg("player_entry", &args).await?;
+    }
+    Ok(())
+}
+
+/// Runs a moderation command against another player of the block.
+async fn moderate<'a>(
+    user: MutexGuard<'_, User>,
+    cmd: &str,
+    args: &mut (impl Iterator<Item = &'a str> + Send),
+) -> Result<(), Error> {
+    let blockdata = user.blockdata.clone();
+    let conn_id = user.conn_id;
+    let moderator_id = user.get_user_id();
+    let level = user.user_data.permission;
+    // other users are locked while looking for the target
+    drop(user);
+    let Some(this) = find_conn(&blockdata, conn_id).await else {
+        return Ok(());
+    };
+    let Some(target_id) = args.next().and_then(|a| a.parse::<u32>().ok()) else {
+        this.lock()
+            .await
+            .send_system_msg("no_player_id", &[])
+            .await?;
+        return Ok(());
+    };
+    let target = moderation::find_player(&blockdata, target_id).await;
+    let player: [(_, &(dyn std::fmt::Display + Sync)); 1] = [("player", &target_id)];
+    // offline players can still be muted and banned
+    let needs_target = matches!(cmd, "!kick" | "!tp" | "!summon");
+    let Some(target) = target else {
+        if needs_target {
+            this.lock()
+                .await
+                .send_system_msg("player_not_found", &player)
+                .await?;
+            return Ok(());
+        }
+        // the permission level of players on other blocks is only known by the master ship
+        let target_level = match blockdata.sql.get_permission(target_id).await {
+            Ok(target_level) => target_level,
+            Err(e) => {
+                log::warn!("Failed to get the permission level of player {target_id}: {e}");
+                this.lock()
+                    .await
+                    .send_system_msg("player_not_found", &player)
+                    .await?;
+                return Ok(());
+            }
+        };
+        if level <= target_level {
+            log::warn!("Player {moderator_id} ({level}) was denied {cmd} on player {target_id}");
+            this.lock()
+                .await
+                .send_system_msg("target_denied", &player)
+                .await?;
+            return Ok(());
+        }
+        if mute_or_ban(&this, cmd, target_id, args).await?.is_some() {
+            log::info!("Player {moderator_id} ({level}) used {cmd} on player {target_id}");
+        }
+        return Ok(());
+    };
+    let allowed = moderation::can_moderate(level, &*target.lock().await);
+    // teleporting only moves the moderator
+    if !allowed && cmd != "!tp" {
+        log::warn!("Player {moderator_id} ({level}) was denied {cmd} on player {target_id}");
+        this.lock()
+            .await
+            .send_system_msg("target_denied", &player)
+            .await?;
+        return Ok(());
+    }
+    match cmd {
+        "!kick" => {
+            moderation::kick(&mut *target.lock().await).await;
+            this.lock()
+                .await
+                .send_system_msg("player_kicked", &player)
+                .await?;
+        }
+        "!tp" => {
+            moderation::teleport(&this, &target).await?;
+            this.lock()
+                .await
+                .send_system_msg("teleported", &player)
+                .await?;
+        }
+        "!summon" => {
+            moderation::teleport(&target, &this).await?;
+            this.lock()
+                .await
+                .send_system_msg("player_summoned", &player)
+                .await?;
+        }
+        _ => {
+            let Some(notice) = mute_or_ban(&this, cmd, target_id, args).await? else {
+                return Ok(());
+            };
+            let mut target = target.lock().await;
+            match notice {
+                Notice::Muted(minutes) => {
+                    target
+                        .send_system_msg("muted_notice", &[("minutes", &minutes)])
+                        .await?
+                }
+                Notice::Banned => moderation::kick(&mut target).await,
+            }
+        }
+    }
+    log::info!("Player {moderator_id} ({level}) used {cmd} on player {target_id}");
+    Ok(())
+}
+
+/// Message for the target of a command that also works for offline players.
+enum Notice {
+    Muted(u64),
+    Banned,
+}
+
+/// Mutes or bans the account, the target doesn't have to be online.
+async fn mute_or_ban<'a>(
+    this: &Mutex<User>,
+    cmd: &str,
+    target_id: u32,
+    args: &mut (impl Iterator<Item = &'a str> + Send),
+) -> Result<Option<Notice>, Error> {
+    let player: [(_, &(dyn std::fmt::Display + Sync)); 1] = [("player", &target_id)];
+    let mut this = this.lock().await;
+    let blockdata = this.blockdata.clone();
+    let notice = match cmd {
+        "!mute" => {
+            let duration = args
+                .next()
+                .and_then(|a| a.parse::<u64>().ok())
+                .and_then(|m| Some((m, m.checked_mul(60)?)));
+            let Some((minutes, secs)) = duration else {
+                this.send_system_msg("no_duration", &[]).await?;
+                return Ok(None);
+            };
+            if !blockdata.mutes.mute(target_id, Duration::from_secs(secs)) {
+                this.send_system_msg("no_duration", &[]).await?;
+                return Ok(None);
+            }
+            let args: [(_, &(dyn std::fmt::Display + Sync)); 2] =
+                [("player", &target_id), ("minutes", &minutes)];
+            this.send_system_msg("player_muted", &args).await?;
+            Some(Notice::Muted(minutes))
+        }
+        "!unmute" => {
+            let msg = if blockdata.mutes.unmute(target_id) {
+                "player_unmuted"
+            } else {
+                "player_not_muted"
+            };
+            this.send_system_msg(msg, &player).await?;
+            None
+        }
+        "!ban" => {
+            // permanent if no duration is provided
+            let hours = args.next().and_then(|a| a.parse::<u64>().ok());
+            let until = match hours {
+                Some(h) => {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                    let until = h
+                        .checked_mul(3600)
+                        .and_then(|s| now.as_secs().checked_add(s));
+                    if until.is_none() {
+                        this.send_system_msg("no_duration", &[]).await?;
+                        return Ok(None);
+                    }
+                    until
+                }
+                None => None,
+            };
+            blockdata.sql.ban_user(target_id, until).await?;
+            match hours {
+                Some(hours) => {
+                    let args: [(_, &(dyn std::fmt::Display + Sync)); 2] =
+                        [("player", &target_id), ("hours", &hours)];
+                    this.send_system_msg("player_banned_for", &args).await?
+                }
+                None => this.send_system_msg("player_banned", &player).await?,
+            }
+            Some(Notice::Banned)
+        }
+        "!unban" => {
+            blockdata.sql.unban_user(target_id).await?;
+            this.send_system_msg("player_unbanned", &player).await?;
+            None
+        }
+        _ => unreachable!("Only mute and ban commands are passed"),
+    };
+    Ok(notice)
+}
+
+async fn find_conn(blockdata: &BlockData, conn_id: usize) -> Option<Arc<Mutex<User>>> {
+    blockdata
+        .clients
+        .lock()
+        .await
+        .iter()
+        .find(|(c_conn_id, _)| *c_conn_id == conn_id)
+        .map(|(_, c)| c.clone())
+}
+
+/// Returns the required permission level of a built-in command or `None` if it isn't one.
+fn command_level(cmd: &str) -> Option<PermissionLevel> {
+    COMMAND_LEVELS
+        .iter()
+        .find(|(c, _)| *c == cmd)
+        .map(|&(_, level)| level)
+}
+
+async fn plugin_command<'a>(
+    mut user: MutexGuard<'_, User>,
+    cmd: &str,
+    args: impl Iterator<Item = &'a str>,
+) -> Result<(), Error> {
+    let command = &cmd[1..];
+    let blockdata = user.blockdata.clone();
+    match blockdata
+        .plugins
+        .check_command(command, user.user_data.permission)
+    {
+        CommandCheck::Unknown => user.send_system_msg("unknown_command", &[]).await?,
+        CommandCheck::Denied => {
+            log_denied(&user, cmd);
+            user.send_system_msg("command_denied", &[]).await?
+        }
+        CommandCheck::Allowed => {
+            let id = user.get_user_id();
+            let args = args.map(String::from).collect();
+            // plugins may lock the caller
+            drop(user);
+            blockdata
+                .plugins
+                .run_command(&blockdata, id, command, args)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+fn log_denied(user: &User, cmd: &str) {
+    log::warn!(
+        "Player {} ({}) was denied the command {cmd}",
+        user.get_user_id(),
+        user.user_data.permission
+    );
+}
+
 async fn set_flag_parse<'a>(
     user: &mut User,
     ftype: FlagType,
@@ -256,7 +911,7 @@ async fn set_flag_parse<'a>(
     let range = match args.next() {
         Some(r) => r,
         None => {
-            user.send_system_msg("No range provided").await?;
+            user.send_system_msg("no_range", &[]).await?;
             return Ok(());
         }
     };
@@ -266,11 +921,11 @@ async fn set_flag_parse<'a>(
         let lower = split.next().and_then(|r| r.parse().ok());
         let upper = split.next().and_then(|r| r.parse().ok());
         let (Some(lower), Some(upper)) = (lower, upper) else {
-            user.send_system_msg("Invalid range").await?;
+            user.send_system_msg("invalid_range", &[]).await?;
             return Ok(());
         };
         if lower > upper {
-            user.send_system_msg("Invalid range").await?;
+            user.send_system_msg("invalid_range", &[]).await?;
             return Ok(());
         }
         for i in lower..=upper {
@@ -280,7 +935,7 @@ async fn set_flag_parse<'a>(
         let id = match range.parse() {
             Ok(i) => i,
             Err(_) => {
-                user.send_system_msg("Invalid id").await?;
+                user.send_system_msg("invalid_flag_id", &[]).await?;
                 return Ok(());
             }
         };
@@ -312,3 +967,21 @@ async fn set_flag(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{command_level, COMMAND_LEVELS};
+    use data_structs::master_ship::PermissionLevel;
+
+    #[test]
+    fn test_command_levels() {
+        assert_eq!(command_level("!kick"), Some(PermissionLevel::Moderator));
+        assert_eq!(command_level("!ban"), Some(PermissionLevel::Gm));
+        // plugin commands aren't built-ins
+        assert_eq!(command_level("!plugin_cmd"), None);
+        for (i, (cmd, _)) in COMMAND_LEVELS.iter().enumerate() {
+            assert!(cmd.starts_with('!'));
+            assert!(!COMMAND_LEVELS[i + 1..].iter().any(|(c, _)| c == cmd));
+        }
+    }
+}
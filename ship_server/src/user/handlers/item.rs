@@ -61,7 +61,8 @@ pub async fn move_storages(user: &mut User, packet: MoveStoragesRequestPacket) -
 }
 
 pub async fn get_description(user: &mut User, packet: GetItemDescriptionPacket) -> HResult {
-    let names_ref = &user.blockdata.server_data.item_params;
+    let server_data = user.blockdata.server_data.load_full();
+    let names_ref = &server_data.item_params;
     match names_ref.names.iter().find(|x| x.id == packet.item) {
         Some(name) => {
             let packet = LoadItemDescriptionPacket {
@@ -93,6 +94,7 @@ pub async fn equip_item(mut user: MutexGuard<'_, User>, packet: EquipItemRequest
         let block_data = user.get_blockdata();
         let clothing_stats = block_data
             .server_data
+            .load()
             .item_params
             .attrs
             .human_costumes
@@ -1,5 +1,8 @@
 use super::HResult;
-use crate::{battle_stats::PlayerStats, user::UserState, Action, Error, User};
+use crate::{
+    battle_stats::PlayerStats, session, settings::BlockType, user::UserState, Action, BlockInfo,
+    Error, User,
+};
 use data_structs::master_ship::SetNicknameResult;
 use pso2packetlib::protocol::{
     self,
@@ -21,13 +24,64 @@ pub async fn encryption_request(user: &mut User, _: login::EncryptionRequestPack
     Ok(Action::Nothing)
 }
 
+/// Checks if the block has more connections than allowed.
+async fn is_block_full(user: &User) -> bool {
+    let lock = user.blockdata.blocks.read().await;
+    lock.iter()
+        .find(|b| b.id == user.blockdata.block_id)
+        .is_some_and(|b| b.players > b.max_players)
+}
+
+/// Checks if the logged in user is allowed to enter this block type.
+fn can_enter_block(user: &User) -> bool {
+    user.blockdata
+        .block_type
+        .can_enter(user.user_data.is_gm(), user.user_data.packet_type)
+}
+
+/// GM-only blocks are hidden from regular players.
+fn is_block_visible(user: &User, block: &BlockInfo) -> bool {
+    block.id == user.blockdata.block_id
+        || block.block_type != BlockType::GmOnly
+        || user.user_data.is_gm()
+}
+
+/// Vita clients send a language code instead of the language ID.
+fn vita_language(code: &str) -> login::Language {
+    if code.to_ascii_lowercase().starts_with("en") {
+        login::Language::English
+    } else {
+        login::Language::Japanese
+    }
+}
+
+async fn send_block_full(user: &mut User) -> HResult {
+    send_login_failure(user, "block_full").await
+}
+
+/// Sends a login error from the message catalog and disconnects the user.
+async fn send_login_failure(user: &mut User, error_id: &str) -> HResult {
+    user.send_packet(&Packet::LoginResponse(login::LoginResponsePacket {
+        status: login::LoginStatus::Failure,
+        error: user.localize(error_id, &[]),
+        blockname: user.blockdata.block_name.clone().into(),
+        ..Default::default()
+    }))
+    .await?;
+    Ok(Action::Disconnect)
+}
+
 pub async fn login_request(user: &mut User, packet: Packet) -> HResult {
+    if is_block_full(user).await {
+        return send_block_full(user).await;
+    }
     let (mut status, mut error) = Default::default();
     let ip = user.get_ip()?;
     match packet {
         Packet::SegaIDLogin(packet) => {
+            user.user_data.lang = packet.text_lang;
             user.user_data.packet_type = PacketType::NA;
-            user.connection.change_packet_type(PacketType::NA);
+            user.change_packet_type(PacketType::NA);
             let sega_user = user
                 .blockdata
                 .sql
@@ -45,38 +99,43 @@ pub async fn login_request(user: &mut User, packet: Packet) -> HResult {
                 }
                 Err(Error::InvalidPassword) => {
                     status = login::LoginStatus::Failure;
-                    error = "Invalid username or password".to_string();
+                    error = "invalid_login";
                 }
                 Err(Error::InvalidInput(_)) => {
                     status = login::LoginStatus::Failure;
-                    error = "Empty username or password".to_string();
+                    error = "empty_login";
+                }
+                Err(Error::AccountBanned) => {
+                    status = login::LoginStatus::Failure;
+                    error = "account_banned";
                 }
                 Err(e) => return Err(e),
             }
         }
         Packet::VitaLogin(packet) => {
             user.user_data.packet_type = PacketType::Vita;
-            user.connection.change_packet_type(PacketType::Vita);
-            let mut user_psn = user
-                .blockdata
-                .sql
-                .get_psn_user(&packet.username, ip)
-                .await?;
+            user.change_packet_type(PacketType::Vita);
+            let mut user_psn = match user.blockdata.sql.get_psn_user(&packet.username, ip).await {
+                Ok(data) => data,
+                Err(Error::AccountBanned) => {
+                    return send_login_failure(user, "account_banned").await
+                }
+                Err(e) => return Err(e),
+            };
             user_psn.packet_type = user.user_data.packet_type;
+            user_psn.lang = vita_language(&packet.language);
             user.user_data = user_psn;
         }
         _ => unreachable!(),
     }
 
+    if status != login::LoginStatus::Failure && !can_enter_block(user) {
+        status = login::LoginStatus::Failure;
+        error = "block_not_allowed";
+    }
+
     if status == login::LoginStatus::Failure {
-        user.send_packet(&Packet::LoginResponse(login::LoginResponsePacket {
-            status,
-            error,
-            blockname: user.blockdata.block_name.clone().into(),
-            ..Default::default()
-        }))
-        .await?;
-        return Ok(Action::Disconnect);
+        return send_login_failure(user, error).await;
     }
 
     if user.user_data.nickname.is_empty() {
@@ -91,6 +150,9 @@ pub async fn login_request(user: &mut User, packet: Packet) -> HResult {
 
 pub async fn on_successful_login(user: &mut User) -> HResult {
     let id = user.get_user_id();
+    if user.blockdata.captures.is_captured(id) {
+        user.set_capture(true);
+    }
     user.send_packet(&Packet::LoginResponse(login::LoginResponsePacket {
         status: login::LoginStatus::Success,
         error: String::new(),
@@ -145,10 +207,10 @@ pub async fn block_list(user: &mut User) -> HResult {
         unk: 0,
     };
     let lock = user.blockdata.blocks.read().await;
-    for block in lock.iter() {
+    for block in lock.iter().filter(|b| is_block_visible(user, b)) {
         blocks.blocks.push(login::BlockInfo {
             block_id: block.id as u16,
-            blockname: block.name.to_string().into(),
+            blockname: block.block_type.display_name(&block.name).into(),
             ip: block.ip,
             port: block.port,
             cur_capacity: block.players as f32 / block.max_players as f32,
@@ -181,10 +243,10 @@ pub async fn all_block_list(user: &mut User) -> HResult {
         unk: 0,
     };
     let lock = user.blockdata.blocks.read().await;
-    for block in lock.iter() {
+    for block in lock.iter().filter(|b| is_block_visible(user, b)) {
         blocks.blocks.push(login::BlockInfo {
             block_id: block.id as u16,
-            blockname: block.name.to_string().into(),
+            blockname: block.block_type.display_name(&block.name).into(),
             ip: block.ip,
             port: block.port,
             cur_capacity: block.players as f32 / block.max_players as f32,
@@ -212,30 +274,39 @@ pub async fn all_block_list(user: &mut User) -> HResult {
 }
 
 pub async fn challenge_login(user: &mut User, packet: login::BlockLoginPacket) -> HResult {
+    if is_block_full(user).await {
+        return send_block_full(user).await;
+    }
     let user_id = packet.player_id as u32;
     let challenge = packet.challenge;
     let pso_user = user.blockdata.sql.login_challenge(user_id, challenge).await;
-    let (mut id, mut status, mut error) = Default::default();
-    match pso_user {
+    let id = match pso_user {
+        Ok(x)
+            if !user
+                .blockdata
+                .block_type
+                .can_enter(x.is_gm(), x.packet_type) =>
+        {
+            user.user_data.lang = x.lang;
+            return send_login_failure(user, "block_not_allowed").await;
+        }
         Ok(x) => {
-            id = x.id;
-            user.connection.change_packet_type(x.packet_type);
+            let id = x.id;
+            user.change_packet_type(x.packet_type);
             user.send_packet(&Packet::ChallengeRequest(login::ChallengeRequestPacket {
                 data: vec![0x0C, 0x47, 0x29, 0x91, 0x27, 0x8E, 0x52, 0x22].into(),
             }))
             .await?;
             user.user_data = x;
+            id
         }
-        Err(Error::NoUser) => {
-            status = login::LoginStatus::Failure;
-            error = "Invalid user".to_string();
-        }
+        Err(Error::NoUser) => return send_login_failure(user, "invalid_user").await,
+        Err(Error::AccountBanned) => return send_login_failure(user, "account_banned").await,
 
         Err(e) => return Err(e),
-    }
+    };
     user.send_packet(&Packet::LoginResponse(login::LoginResponsePacket {
-        status,
-        error,
+        status: login::LoginStatus::Success,
         blockname: user.blockdata.block_name.clone().into(),
         player: ObjectHeader {
             id,
@@ -245,9 +316,6 @@ pub async fn challenge_login(user: &mut User, packet: login::BlockLoginPacket) -
         ..Default::default()
     }))
     .await?;
-    if let login::LoginStatus::Failure = status {
-        return Ok(Action::Disconnect);
-    }
 
     on_successful_login(user).await
 }
@@ -411,6 +479,7 @@ pub async fn new_character(user: &mut User, packet: login::CharacterCreatePacket
         let clothes = user
             .blockdata
             .server_data
+            .load()
             .item_params
             .attrs
             .human_costumes
@@ -438,9 +507,9 @@ pub async fn new_character(user: &mut User, packet: login::CharacterCreatePacket
     }
     // add items
     {
-        let block_data = user.blockdata.clone();
-        let class_data = &block_data.server_data.default_classes.classes
-            [char_data.character.classes.main_class as usize];
+        let server_data = user.blockdata.server_data.load_full();
+        let class_data =
+            &server_data.default_classes.classes[char_data.character.classes.main_class as usize];
         for item in &class_data.items {
             let uuid = user.user_data.last_uuid;
             user.user_data.last_uuid += 1;
@@ -481,16 +550,27 @@ pub async fn new_character(user: &mut User, packet: login::CharacterCreatePacket
 }
 
 pub async fn start_game(user: &mut User, packet: login::StartGamePacket) -> HResult {
-    let char = user
-        .blockdata
-        .sql
-        .get_character(user.get_user_id(), packet.char_id)
-        .await?;
-    user.character = Some(char);
-    user.session_start = std::time::Instant::now();
+    let mut resumed = false;
+    if let Some(old) = session::take(&user.blockdata, user.get_user_id()).await {
+        resumed = user
+            .take_over_session(&mut *old.lock().await, packet.char_id)
+            .await?;
+    }
+    if resumed {
+        log::info!("User {} resumed the session", user.get_user_id());
+    } else {
+        let char = user
+            .blockdata
+            .sql
+            .get_character(user.get_user_id(), packet.char_id)
+            .await?;
+        user.character = Some(char);
+        user.session_start = std::time::Instant::now();
+        user.battle_stats = PlayerStats::build(user)?;
+    }
+    user.team = user.blockdata.sql.get_team(user.get_user_id()).await?;
     user.send_packet(&Packet::LoadingScreenTransition).await?;
     user.state = UserState::PreInGame;
-    user.battle_stats = PlayerStats::build(user)?;
     Ok(Action::Nothing)
 }
 
@@ -3,27 +3,29 @@ use crate::{mutex::MutexGuard, Action, User};
 use pso2packetlib::protocol::{objects, Packet};
 
 pub async fn movement(mut user: MutexGuard<'_, User>, packet: objects::MovementPacket) -> HResult {
+    let mut pos = user.position;
     if let Some(n) = packet.rot_x {
-        user.position.rot_x = n;
+        pos.rot_x = n;
     }
     if let Some(n) = packet.rot_y {
-        user.position.rot_y = n;
+        pos.rot_y = n;
     }
     if let Some(n) = packet.rot_z {
-        user.position.rot_z = n;
+        pos.rot_z = n;
     }
     if let Some(n) = packet.rot_w {
-        user.position.rot_w = n;
+        pos.rot_w = n;
     }
     if let Some(n) = packet.cur_x {
-        user.position.pos_x = n;
+        pos.pos_x = n;
     }
     if let Some(n) = packet.cur_y {
-        user.position.pos_y = n;
+        pos.pos_y = n;
     }
     if let Some(n) = packet.cur_z {
-        user.position.pos_z = n;
+        pos.pos_z = n;
     }
+    user.update_position(pos);
     User::send_position(user, Packet::Movement(packet)).await
 }
 
@@ -1,5 +1,11 @@
 use super::HResult;
-use crate::{mutex::MutexGuard, quests::PartyQuest, Action, User};
+use crate::{
+    lua_reload::ScriptSource,
+    mutex::MutexGuard,
+    plugins::{Event, Plugins},
+    quests::PartyQuest,
+    Action, User,
+};
 use pso2packetlib::protocol::{
     flag::{CutsceneEndPacket, SkitItemAddRequestPacket},
     questlist::{
@@ -42,7 +48,7 @@ pub async fn counter_request(user: &mut User) -> HResult {
         data,
     )))
     .await?;
-    let quests = user.blockdata.quests.clone();
+    let quests = user.blockdata.quests.load_full();
     let char = user
         .character
         .as_mut()
@@ -74,8 +80,12 @@ pub async fn avaliable_quests(
         .character
         .as_ref()
         .expect("Character should be loaded at this moment");
-    let packet =
-        Packet::AvailableQuests(user.blockdata.quests.get_availiable(&char.unlocked_quests));
+    let packet = Packet::AvailableQuests(
+        user.blockdata
+            .quests
+            .load()
+            .get_availiable(&char.unlocked_quests, user.blockdata.block_type),
+    );
     user.send_packet(&packet).await?;
     Ok(Action::Nothing)
 }
@@ -85,10 +95,11 @@ pub async fn quest_category(user: &mut User, packet: QuestCategoryRequestPacket)
         .character
         .as_ref()
         .expect("Character should be loaded at this moment");
-    let packet = user
-        .blockdata
-        .quests
-        .get_category(packet.category, &char.unlocked_quests);
+    let packet = user.blockdata.quests.load().get_category(
+        packet.category,
+        &char.unlocked_quests,
+        user.blockdata.block_type,
+    );
     user.send_packet(&Packet::QuestCategory(packet)).await?;
     user.send_packet(&Packet::QuestCategoryStopper).await?;
 
@@ -97,7 +108,7 @@ pub async fn quest_category(user: &mut User, packet: QuestCategoryRequestPacket)
 
 pub async fn quest_difficulty(user: &mut User, packet: QuestDifficultyRequestPacket) -> HResult {
     for quest in packet.quests {
-        let diff = user.blockdata.quests.get_diff(quest.id);
+        let diff = user.blockdata.quests.load().get_diff(quest.id);
         if let Some(packet) = diff {
             user.send_packet(&Packet::QuestDifficulty(QuestDifficultyPacket {
                 quests: vec![packet],
@@ -110,10 +121,11 @@ pub async fn quest_difficulty(user: &mut User, packet: QuestDifficultyRequestPac
 }
 
 pub async fn set_quest(user: MutexGuard<'_, User>, packet: AcceptQuestPacket) -> HResult {
-    let quest = user
-        .blockdata
-        .quests
-        .get_quest(packet, &user.blockdata.latest_mapid)?;
+    let quest = user.blockdata.quests.load().get_quest(
+        packet,
+        &user.blockdata.latest_mapid,
+        user.blockdata.block_type,
+    )?;
     start_quest(user, quest).await
 }
 
@@ -141,10 +153,11 @@ pub async fn set_story_quest(
     user: MutexGuard<'_, User>,
     packet: AcceptStoryQuestPacket,
 ) -> HResult {
-    let quest = user
-        .blockdata
-        .quests
-        .get_story_quest(packet, &user.blockdata.latest_mapid)?;
+    let quest = user.blockdata.quests.load().get_story_quest(
+        packet,
+        &user.blockdata.latest_mapid,
+        user.blockdata.block_type,
+    )?;
     start_quest(user, quest).await
 }
 
@@ -154,7 +167,20 @@ pub async fn start_quest(user: MutexGuard<'_, User>, quest: PartyQuest) -> HResu
     let old_map = user.get_current_map().expect("User should have a map");
     let map = quest.get_map();
     // we are the only owner of the map, so this never blocks
-    map.lock_blocking().set_block_data(user.blockdata.clone());
+    {
+        let mut lock = map.lock_blocking();
+        lock.set_block_data(user.blockdata.clone());
+        lock.set_handle(&map);
+    }
+    if let Some(reloader) = &user.blockdata.lua_reloader {
+        let source = ScriptSource::Quest(quest.get_name_id());
+        reloader.register(source, &map).await;
+    }
+    let event = Event::QuestStart {
+        player: user_id,
+        quest: quest.get_name_id(),
+    };
+    Plugins::dispatch(&user.blockdata, event);
     let party = user.get_current_party();
     drop(user);
     if let Some(party) = party {
@@ -178,13 +204,11 @@ pub async fn minimap_reveal(
     mut user: MutexGuard<'_, User>,
     data: MinimapRevealRequestPacket,
 ) -> HResult {
-    user.send_packet(&Packet::SystemMessage(
-        pso2packetlib::protocol::unk19::SystemMessagePacket {
-            message: format!("Chunk ID: {}", data.chunk_id),
-            msg_type: pso2packetlib::protocol::unk19::MessageType::EventInformationYellow,
-            ..Default::default()
-        },
-    ))
+    user.send_localized_msg(
+        "minimap_chunk",
+        &[("chunk", &data.chunk_id)],
+        pso2packetlib::protocol::unk19::MessageType::EventInformationYellow,
+    )
     .await?;
     if let Some(map) = user.get_current_map() {
         let playerid = user.get_user_id();
@@ -1,5 +1,10 @@
 use super::HResult;
-use crate::{mutex::MutexGuard, party, Action, Error, User, UserState};
+use crate::{
+    mutex::MutexGuard,
+    party,
+    plugins::{Event, Plugins},
+    Action, Error, User, UserState,
+};
 use pso2packetlib::protocol::{
     self,
     flag::{FlagType, SetFlagPacket},
@@ -16,7 +21,14 @@ pub async fn initial_load(mut user: MutexGuard<'_, User>) -> HResult {
     let conn_id = user.conn_id;
     let blockdata = user.blockdata.clone();
 
-    user.set_map(blockdata.lobby.clone());
+    // resumed sessions already have a map and a party
+    let resumed = match (user.get_current_map(), user.get_current_party()) {
+        (Some(map), Some(party)) => Some((map, party, user.zone_id, user.position)),
+        _ => None,
+    };
+    if resumed.is_none() {
+        user.set_map(blockdata.lobby.clone());
+    }
     let party_id = blockdata.latest_partyid.fetch_add(1, Ordering::Relaxed);
     drop(user);
 
@@ -30,15 +42,25 @@ pub async fn initial_load(mut user: MutexGuard<'_, User>) -> HResult {
     };
     drop(clients);
 
-    party::Party::init_player(user.clone(), party_id).await?;
-    blockdata
-        .lobby
-        .lock()
-        .await
-        .init_add_player(user.clone())
-        .await?;
+    if let Some((map, party, zone_id, position)) = resumed {
+        party.write().await.rejoin_player(user.clone()).await?;
+        map.lock()
+            .await
+            .reattach_player(user.clone(), zone_id, position)
+            .await?;
+    } else {
+        party::Party::init_player(user.clone(), party_id).await?;
+        blockdata
+            .lobby
+            .lock()
+            .await
+            .init_add_player(user.clone())
+            .await?;
+    }
     let mut user_lock = user.lock().await;
     user_lock.state = UserState::InGame;
+    let player = user_lock.get_user_id();
+    Plugins::dispatch(&blockdata, Event::Login { player });
     Ok(Action::Nothing)
 }
 
@@ -137,7 +159,7 @@ pub async fn map_loaded(mut user_guard: MutexGuard<'_, User>, _: MapLoadedPacket
     let inventory_packets = character.inventory.send(
         user_id,
         character.character.name.clone(),
-        &user.blockdata.server_data.item_params,
+        &user.blockdata.server_data.load().item_params,
         user.user_data.lang,
     );
     let palette = character.palette.send_palette();
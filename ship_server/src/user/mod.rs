@@ -1,16 +1,21 @@
 pub(crate) mod handlers;
 use crate::{
     battle_stats::PlayerStats,
+    capture::Capture,
     invites::PartyInvite,
     map::Map,
     mutex::{Mutex, MutexGuard, RwLock},
     party::{self, Party},
+    plugins::Event,
+    script_store::{self, ScriptValue, SharedScriptStore},
     sql::{self, CharData},
+    validation::{self, Suspicion, Validator},
     Action, BlockData, Error,
 };
-use data_structs::flags::Flags;
+use data_structs::{flags::Flags, stats::WeaponClass};
 use pso2packetlib::{
     connection::{ConnectionError, ConnectionRead, ConnectionWrite},
+    ppac::Direction,
     protocol::{
         self as Pr,
         login::Language,
@@ -19,7 +24,7 @@ use pso2packetlib::{
             Position,
         },
         party::BusyState,
-        playerstatus::EXPReceiver,
+        playerstatus::{DealDamagePacket, EXPReceiver},
         spawn::CharacterSpawnPacket,
         ObjectHeader, Packet, PacketType,
     },
@@ -34,6 +39,8 @@ pub struct User {
     pub position: Position,
     map: Option<Arc<Mutex<Map>>>,
     pub party: Option<Arc<RwLock<Party>>>,
+    /// Script store of the current party.
+    pub party_store: SharedScriptStore,
     pub character: Option<CharData>,
     last_ping: Instant,
     failed_pings: u32,
@@ -46,8 +53,20 @@ pub struct User {
     battle_stats: PlayerStats,
     conn_id: usize,
     pub user_data: sql::User,
+    /// Team of the alliance chat channel.
+    pub team: Option<String>,
+    /// Team the player was invited to.
+    pub team_invite: Option<String>,
 
     session_start: Instant,
+    /// Character or account data changed since the last save.
+    dirty: bool,
+    last_save: Instant,
+    /// Disconnect the user on the next tick.
+    kick_pending: bool,
+    pub validator: Validator,
+    /// Recording of the connection packets.
+    capture: Option<Capture>,
 }
 
 impl User {
@@ -63,11 +82,23 @@ impl User {
             blockdata.key.clone(),
             PublicKey::None,
         );
-        match con.write_packet(&Packet::ServerHello(Pr::server::ServerHelloPacket {
+        let mut capture = None;
+        if blockdata.capture_all {
+            capture = Some(
+                blockdata
+                    .captures
+                    .start_connection(blockdata.block_id, conn_id),
+            );
+        }
+        let hello = Packet::ServerHello(Pr::server::ServerHelloPacket {
             unk1: 3,
             blockid: blockdata.block_id as u16,
             unk2: 68833280,
-        })) {
+        });
+        if let Some(capture) = &mut capture {
+            capture.write(Direction::ToClient, &hello);
+        }
+        match con.write_packet(&hello) {
             Ok(_) => {}
             Err(ConnectionError::Io(x)) if x.kind() == std::io::ErrorKind::WouldBlock => {}
             Err(x) => return Err(x.into()),
@@ -76,10 +107,10 @@ impl User {
         Ok((
             User {
                 connection: write,
-                blockdata,
                 character: None,
                 map: None,
                 party: None,
+                party_store: Default::default(),
                 position: Default::default(),
                 last_ping: Instant::now(),
                 failed_pings: 0,
@@ -94,11 +125,18 @@ impl User {
                 user_data: sql::User {
                     packet_type: PacketType::Classic,
                     lang: Language::Japanese,
-                    isgm: false,
                     last_uuid: 1,
                     ..Default::default()
                 },
+                team: None,
+                team_invite: None,
                 session_start: Instant::now(),
+                dirty: false,
+                last_save: Instant::now(),
+                kick_pending: false,
+                validator: Validator::new(&blockdata.validation),
+                capture,
+                blockdata,
             },
             read,
         ))
@@ -110,6 +148,12 @@ impl User {
             return Ok(Action::Disconnect);
         }
         if s.failed_pings >= 5 {
+            return Ok(Action::ConnectionLost);
+        }
+        if s.kick_pending {
+            if let Err(e) = s.save().await {
+                log::warn!("Failed to save user {}: {e}", s.get_user_id());
+            }
             return Ok(Action::Disconnect);
         }
         if s.last_ping.elapsed().as_secs() >= 10 {
@@ -117,6 +161,15 @@ impl User {
             s.failed_pings += 1;
             let _ = s.send_packet(&Packet::ServerPing).await;
         }
+        if let Some(interval) = s.blockdata.autosave_interval {
+            if s.dirty && s.last_save.elapsed() >= interval {
+                if let Err(e) = s.save().await {
+                    log::warn!("Failed to autosave user {}: {e}", s.get_user_id());
+                    // retry after the next interval instead of on every tick
+                    s.last_save = Instant::now();
+                }
+            }
+        }
         Ok(Action::Nothing)
     }
     // Helper functions
@@ -124,10 +177,12 @@ impl User {
         Ok(self.connection.get_ip()?)
     }
     pub async fn send_packet(&mut self, packet: &Packet) -> Result<(), Error> {
+        self.capture_packet(Direction::ToClient, packet);
         self.connection.write_packet_async(packet).await?;
         Ok(())
     }
     pub fn try_send_packet(&mut self, packet: &Packet) -> Result<(), Error> {
+        self.capture_packet(Direction::ToClient, packet);
         match self.connection.write_packet(packet) {
             Ok(_) => {}
             Err(ConnectionError::Io(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock => {}
@@ -136,11 +191,16 @@ impl User {
         Ok(())
     }
     pub fn send_packet_block(&mut self, packet: &Packet) -> Result<(), Error> {
+        self.capture_packet(Direction::ToClient, packet);
         match self.connection.write_packet(packet) {
             Ok(_) => return Ok(()),
             Err(ConnectionError::Io(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock => {}
             Err(e) => return Err(e.into()),
         };
+        self.flush_blocking()
+    }
+    /// Writes the buffered packets, blocking until they are sent.
+    pub(crate) fn flush_blocking(&mut self) -> Result<(), Error> {
         loop {
             match self.connection.flush() {
                 Ok(_) => return Ok(()),
@@ -149,6 +209,29 @@ impl User {
             }
         }
     }
+    fn capture_packet(&mut self, direction: Direction, packet: &Packet) {
+        if let Some(capture) = &mut self.capture {
+            capture.write(direction, packet);
+        }
+    }
+    /// Starts or stops recording the packets of this user.
+    pub fn set_capture(&mut self, enabled: bool) {
+        if !enabled {
+            self.capture = None;
+        } else if self.capture.is_none() {
+            let capture = self
+                .blockdata
+                .captures
+                .start_account(self.get_user_id(), self.user_data.packet_type);
+            self.capture = Some(capture);
+        }
+    }
+    pub fn change_packet_type(&mut self, packet_type: PacketType) {
+        self.connection.change_packet_type(packet_type);
+        if let Some(capture) = &mut self.capture {
+            capture.change_packet_type(packet_type);
+        }
+    }
     pub async fn spawn_character(&mut self, packet: CharacterSpawnPacket) -> Result<(), Error> {
         self.send_packet(&Packet::CharacterSpawn(packet)).await?;
         Ok(())
@@ -175,7 +258,7 @@ impl User {
     pub const fn get_stats(&self) -> &PlayerStats {
         &self.battle_stats
     }
-    pub fn get_stats_mut(&mut self) -> &mut PlayerStats {
+    pub const fn get_stats_mut(&mut self) -> &mut PlayerStats {
         &mut self.battle_stats
     }
     pub const fn create_object_header(&self) -> ObjectHeader {
@@ -186,12 +269,82 @@ impl User {
             map_id: 0,
         }
     }
+    /// Logs a suspicious event and kicks repeat offenders.
+    pub fn report_suspicious(&mut self, suspicion: Suspicion) {
+        let id = self.get_user_id();
+        log::warn!(target: validation::LOG_TARGET, "User {id} ({}): {suspicion}", self.user_data.nickname);
+        if suspicion.is_strike() && self.validator.add_strike(&self.blockdata.validation) {
+            log::warn!(target: validation::LOG_TARGET, "User {id} kicked for repeated suspicious activity");
+            self.kick();
+        }
+    }
+    /// Validates and stores a new position reported by the client.
+    pub fn update_position(&mut self, pos: Position) {
+        let settings = self.blockdata.validation.clone();
+        if settings.enabled {
+            if let Err(s) = self
+                .validator
+                .check_movement(&settings, &self.position, &pos)
+            {
+                self.report_suspicious(s);
+            }
+        }
+        self.position = pos;
+    }
+    /// Validates a damage packet of this user, returns `false` if it should be dropped.
+    pub fn validate_damage(&mut self, dmg: &DealDamagePacket, target_zone: u32) -> bool {
+        let settings = self.blockdata.validation.clone();
+        if !settings.enabled {
+            return true;
+        }
+        let equipped = self.equipped_weapon_class();
+        let attacks = &self.blockdata.server_data.load_full().attack_stats;
+        if let Err(s) = Validator::check_attack(attacks, dmg.attack_id, equipped) {
+            // attacks missing from the data are still applied
+            let is_strike = s.is_strike();
+            self.report_suspicious(s);
+            if is_strike {
+                return false;
+            }
+        }
+        let hit_pos = Position {
+            pos_x: dmg.x_pos,
+            pos_y: dmg.y_pos,
+            pos_z: dmg.z_pos,
+            ..Default::default()
+        };
+        let zones = (self.get_zone_id(), target_zone);
+        match self
+            .validator
+            .check_damage(&settings, &self.position, &hit_pos, zones)
+        {
+            Ok(()) => true,
+            Err(s) => {
+                self.report_suspicious(s);
+                false
+            }
+        }
+    }
+    /// Class of the weapon in the current palette, `None` if it is unknown.
+    fn equipped_weapon_class(&self) -> Option<WeaponClass> {
+        let char = self.character.as_ref()?;
+        match char.palette.get_current_item(&char.inventory) {
+            Ok(Some(item)) => WeaponClass::from_category(item.id.id),
+            Ok(None) => Some(WeaponClass::Unarmed),
+            Err(_) => None,
+        }
+    }
+    /// Saves and disconnects the user on the next tick.
+    pub const fn kick(&mut self) {
+        self.kick_pending = true;
+    }
     pub fn get_blockdata(&self) -> &BlockData {
         &self.blockdata
     }
     pub async fn send_item_attrs(&mut self) -> Result<(), Error> {
         let blockdata = self.blockdata.clone();
-        let item_attrs = &blockdata.server_data.item_params;
+        let server_data = blockdata.server_data.load_full();
+        let item_attrs = &server_data.item_params;
         let data = match self.user_data.packet_type {
             PacketType::Vita => &item_attrs.vita_attrs,
             _ => &item_attrs.pc_attrs,
@@ -209,30 +362,112 @@ impl User {
         }
         Ok(())
     }
-    pub async fn send_system_msg(
+    pub async fn send_message(
         &mut self,
         msg: &(impl std::fmt::Display + ?Sized + Sync),
+        msg_type: Pr::unk19::MessageType,
     ) -> Result<(), Error> {
         self.send_packet(&Packet::SystemMessage(Pr::unk19::SystemMessagePacket {
             message: msg.to_string(),
-            msg_type: Pr::unk19::MessageType::SystemMessage,
+            msg_type,
             ..Default::default()
         }))
         .await?;
         Ok(())
     }
+    /// Returns a message from the server message catalog in the language of the user.
+    pub fn localize(&self, id: &str, args: &[(&str, &(dyn std::fmt::Display + Sync))]) -> String {
+        self.blockdata
+            .server_data
+            .load()
+            .messages
+            .format(id, self.user_data.lang, args)
+    }
+    /// Sends a message from the server message catalog.
+    pub async fn send_localized_msg(
+        &mut self,
+        id: &str,
+        args: &[(&str, &(dyn std::fmt::Display + Sync))],
+        msg_type: Pr::unk19::MessageType,
+    ) -> Result<(), Error> {
+        let msg = self.localize(id, args);
+        self.send_message(&msg, msg_type).await
+    }
+    pub async fn send_system_msg(
+        &mut self,
+        id: &str,
+        args: &[(&str, &(dyn std::fmt::Display + Sync))],
+    ) -> Result<(), Error> {
+        self.send_localized_msg(id, args, Pr::unk19::MessageType::SystemMessage)
+            .await
+    }
     pub async fn send_error(
         &mut self,
-        msg: &(impl std::fmt::Display + ?Sized + Sync),
+        id: &str,
+        args: &[(&str, &(dyn std::fmt::Display + Sync))],
     ) -> Result<(), Error> {
-        self.send_packet(&Packet::SystemMessage(Pr::unk19::SystemMessagePacket {
-            message: msg.to_string(),
-            msg_type: Pr::unk19::MessageType::AdminMessageInstant,
-            ..Default::default()
-        }))
-        .await?;
+        self.send_localized_msg(id, args, Pr::unk19::MessageType::AdminMessageInstant)
+            .await
+    }
+    /// Persists the character, account storage, account flags and the last item UUID.
+    pub async fn save(&mut self) -> Result<(), Error> {
+        let player_id = self.get_user_id();
+        let Some(char) = self.character.as_mut() else {
+            self.dirty = false;
+            return Ok(());
+        };
+        char.play_time += self.session_start.elapsed();
+        self.session_start = Instant::now();
+        let sql = self.blockdata.sql.clone();
+        sql.update_character(char).await?;
+        sql.update_account_storage(player_id, &char.inventory)
+            .await?;
+        sql.put_account_flags(player_id, self.user_data.accountflags.clone())
+            .await?;
+        sql.put_uuid(player_id, self.user_data.last_uuid).await?;
+        self.dirty = false;
+        self.last_save = Instant::now();
         Ok(())
     }
+    /// Takes over the suspended session of the same account.
+    ///
+    /// The game state is only moved if the same character was selected on the same block,
+    /// otherwise the old character is saved and the session ends. Returns `true` if the session
+    /// was resumed.
+    pub async fn take_over_session(&mut self, old: &mut User, char_id: u32) -> Result<bool, Error> {
+        // the map and the party belong to the block of the old session
+        let resume = old.blockdata.block_id == self.blockdata.block_id
+            && old
+                .character
+                .as_ref()
+                .is_some_and(|c| c.character.character_id == char_id);
+        if !resume {
+            old.save().await?;
+            // dropping the session now only removes it from the party and the map
+            old.character = None;
+        }
+        // the old session has the latest account data
+        let (packet_type, lang) = (self.user_data.packet_type, self.user_data.lang);
+        std::mem::swap(&mut self.user_data, &mut old.user_data);
+        self.user_data.packet_type = packet_type;
+        self.user_data.lang = lang;
+        if !resume {
+            return Ok(false);
+        }
+        self.character = old.character.take();
+        self.party = old.party.take();
+        self.party_store = old.party_store.clone();
+        self.map = old.map.take();
+        self.position = old.position;
+        self.zone_id = old.zone_id;
+        self.battle_stats = std::mem::take(&mut old.battle_stats);
+        self.party_invites = std::mem::take(&mut old.party_invites);
+        self.party_ignore = old.party_ignore;
+        self.session_start = old.session_start;
+        self.dirty = old.dirty;
+        self.last_save = old.last_save;
+        Ok(true)
+    }
     pub async fn send_position(
         user: MutexGuard<'_, User>,
         packet: Packet,
@@ -245,7 +480,9 @@ impl User {
         }
         Ok(Action::Nothing)
     }
-    pub fn add_exp(&mut self, exp: u32) -> Result<EXPReceiver, Error> {
+    /// Level up events are returned, so that they are dispatched once the user is unlocked.
+    pub fn add_exp(&mut self, exp: u32) -> Result<(EXPReceiver, Vec<Event>), Error> {
+        self.dirty = true;
         let mut packet = EXPReceiver {
             object: self.create_object_header(),
             unk1: 1,
@@ -253,7 +490,7 @@ impl User {
             gained: exp as _,
             ..Default::default()
         };
-        let srv_data = &self.blockdata.server_data;
+        let srv_data = self.blockdata.server_data.load_full();
         let char = self
             .character
             .as_mut()
@@ -266,22 +503,31 @@ impl User {
             level: &mut ClassLevel,
             offset: usize,
             exp: u32,
-        ) {
+        ) -> bool {
             let stats = &srv_data.player_stats.stats[offset][level.level1 as usize - 1];
-            let new_exp = level.exp + exp;
+            let new_exp = level.exp.saturating_add(exp);
             if new_exp < stats.exp_to_next as _ {
-                return;
+                return false;
             }
             level.level1 += 1;
             level.level2 = level.level1;
+            true
         }
+        let player = self.user_data.id;
+        let main_class = char.character.classes.main_class;
+        let sub_class = char.character.classes.sub_class;
+        let mut level_ups = vec![];
 
         // main class
         {
             let level = char.character.get_level_mut();
-            let new_exp = level.exp + exp;
-            if level.level1 < 100 {
-                increase_level(srv_data, level, class_offset, exp);
+            let new_exp = level.exp.saturating_add(exp);
+            if level.level1 < 100 && increase_level(&srv_data, level, class_offset, exp) {
+                level_ups.push(Event::LevelUp {
+                    player,
+                    class: main_class,
+                    level: level.level1,
+                });
             }
             level.exp = new_exp;
             packet.total = level.exp as _;
@@ -293,9 +539,13 @@ impl User {
         if !matches!(char.character.classes.sub_class, Class::Unknown) {
             let level = char.character.get_sublevel_mut();
             let exp = if level.level1 >= 70 { 0 } else { exp };
-            let new_exp = level.exp + exp;
-            if level.level1 < 100 {
-                increase_level(srv_data, level, subclass_offset, exp);
+            let new_exp = level.exp.saturating_add(exp);
+            if level.level1 < 100 && increase_level(&srv_data, level, subclass_offset, exp) {
+                level_ups.push(Event::LevelUp {
+                    player,
+                    class: sub_class,
+                    level: level.level1,
+                });
             }
             level.exp = new_exp;
             packet.gained_sub = exp as _;
@@ -305,9 +555,10 @@ impl User {
         }
         packet.subclass = char.character.classes.sub_class;
         self.battle_stats = PlayerStats::build(self)?;
-        Ok(packet)
+        Ok((packet, level_ups))
     }
     pub async fn set_account_flag(&mut self, flag: u32, value: bool) -> Result<(), Error> {
+        self.dirty = true;
         self.user_data.accountflags.set(flag as _, value as _);
         self.send_packet(&Packet::ServerSetFlag(Pr::flag::ServerSetFlagPacket {
             flag_type: Pr::flag::FlagType::Account,
@@ -319,6 +570,7 @@ impl User {
         Ok(())
     }
     pub fn set_account_flag_block(&mut self, flag: u32, value: bool) -> Result<(), Error> {
+        self.dirty = true;
         self.user_data.accountflags.set(flag as _, value as _);
         self.send_packet_block(&Packet::ServerSetFlag(Pr::flag::ServerSetFlagPacket {
             flag_type: Pr::flag::FlagType::Account,
@@ -332,6 +584,7 @@ impl User {
         self.user_data.accountflags.clone()
     }
     pub async fn set_char_flag(&mut self, flag: u32, value: bool) -> Result<(), Error> {
+        self.dirty = true;
         if let Some(c) = self.character.as_mut() {
             c.flags.set(flag as _, value as _);
         }
@@ -345,6 +598,7 @@ impl User {
         Ok(())
     }
     pub fn set_char_flag_block(&mut self, flag: u32, value: bool) -> Result<(), Error> {
+        self.dirty = true;
         if let Some(c) = self.character.as_mut() {
             c.flags.set(flag as _, value as _);
         }
@@ -359,6 +613,48 @@ impl User {
     pub fn get_char_flags(&self) -> Option<Flags> {
         self.character.as_ref().map(|c| c.flags.clone())
     }
+    pub fn get_char_value(&self, key: &str) -> Option<ScriptValue> {
+        self.character
+            .as_ref()
+            .and_then(|c| c.script_store.get(key).cloned())
+    }
+    pub fn set_char_value(&mut self, key: String, value: Option<ScriptValue>) -> Result<(), Error> {
+        self.dirty = true;
+        match self.character.as_mut() {
+            Some(c) => script_store::set_value(&mut c.script_store, key, value),
+            None => Ok(()),
+        }
+    }
+    /// Returns the script store of the current party.
+    pub fn get_party_store(&self) -> Option<SharedScriptStore> {
+        self.party.as_ref().map(|_| self.party_store.clone())
+    }
+    pub fn add_item_block(&mut self, item_id: Pr::items::ItemId) -> Result<(), Error> {
+        self.dirty = true;
+        let Some(char) = self.character.as_mut() else {
+            return Ok(());
+        };
+        let packet = char
+            .inventory
+            .add_default_item(&mut self.user_data.last_uuid, item_id);
+        self.send_packet_block(&packet)
+    }
+    pub fn add_meseta_block(&mut self, amount: u64) -> Result<(), Error> {
+        self.dirty = true;
+        let Some(char) = self.character.as_mut() else {
+            return Ok(());
+        };
+        let packet = char.inventory.add_meseta(amount);
+        self.send_packet_block(&packet)
+    }
+    pub fn add_exp_block(&mut self, exp: u32) -> Result<Vec<Event>, Error> {
+        let (receiver, level_ups) = self.add_exp(exp)?;
+        self.send_packet_block(&Packet::GainedEXP(Pr::playerstatus::GainedEXPPacket {
+            sender: self.create_object_header(),
+            receivers: vec![receiver],
+        }))?;
+        Ok(level_ups)
+    }
 }
 
 pub async fn packet_handler(
@@ -366,7 +662,24 @@ pub async fn packet_handler(
     packet: Packet,
 ) -> Result<Action, Error> {
     let user: &mut User = &mut user_guard;
+    user.capture_packet(Direction::ToServer, &packet);
     let state = user.state;
+    // movement, chat and keepalive packets don't change any persistent data
+    if state >= UserState::PreInGame
+        && !matches!(
+            packet,
+            Packet::ServerPong
+                | Packet::ClientPing(..)
+                | Packet::ChatMessage(..)
+                | Packet::Movement(..)
+                | Packet::MovementAction(..)
+                | Packet::MovementEnd(..)
+                | Packet::ActionUpdate(..)
+                | Packet::ActionEnd(..)
+        )
+    {
+        user.dirty = true;
+    }
     // sidestep borrow checker
     let match_unit = (state, packet);
     use {handlers as H, Packet as P, UserState as US};
@@ -398,7 +711,7 @@ pub async fn packet_handler(
         }
         (US::InGame, P::ActionUpdate(..)) => User::send_position(user_guard, match_unit.1).await,
         (US::InGame, P::MovementEnd(ref data)) => {
-            user.position = data.cur_pos;
+            user.update_position(data.cur_pos);
             User::send_position(user_guard, match_unit.1).await
         }
         (US::InGame, P::ActionEnd(..)) => User::send_position(user_guard, match_unit.1).await,
@@ -499,7 +812,7 @@ pub async fn packet_handler(
         (_, P::AllBlocksListRequest) => H::login::all_block_list(user).await,
         (_, P::ChallengeResponse(..)) => {
             user.user_data.packet_type = PacketType::NA;
-            user.connection.change_packet_type(PacketType::NA);
+            user.change_packet_type(PacketType::NA);
             Ok(Action::Nothing)
         }
         (US::CharacterSelect, P::LoginHistoryRequest) => H::login::login_history(user).await,
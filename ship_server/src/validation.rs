@@ -0,0 +1,294 @@
+use crate::{
+    rate_limit::TokenBucket,
+    settings::{RateLimit, ValidationSettings},
+};
+use data_structs::stats::{AttackStats, WeaponClass};
+use pso2packetlib::protocol::models::Position;
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+/// Log target of the suspicious events log.
+pub const LOG_TARGET: &str = "suspicious";
+
+/// Client behaviour that is unlikely to come from an unmodified client.
+#[derive(Debug)]
+pub enum Suspicion {
+    Speed {
+        distance: f32,
+        elapsed: f32,
+    },
+    DamageRate,
+    OtherZone {
+        player_zone: u32,
+        target_zone: u32,
+    },
+    OutOfRange {
+        distance: f32,
+    },
+    UnknownAttack(u32),
+    WrongWeapon {
+        attack_id: u32,
+        attack_weapon: WeaponClass,
+        equipped: WeaponClass,
+    },
+}
+
+impl Suspicion {
+    /// Unknown attacks are likely to be missing data, so they are only logged.
+    pub const fn is_strike(&self) -> bool {
+        !matches!(self, Self::UnknownAttack(_))
+    }
+}
+
+impl Display for Suspicion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Speed { distance, elapsed } => {
+                write!(f, "moved {distance:.1} units in {elapsed:.2} seconds")
+            }
+            Self::DamageRate => write!(f, "exceeded the damage packet rate"),
+            Self::OtherZone {
+                player_zone,
+                target_zone,
+            } => write!(
+                f,
+                "attacked a target in zone {target_zone} from zone {player_zone}"
+            ),
+            Self::OutOfRange { distance } => {
+                write!(f, "attacked a target {distance:.1} units away")
+            }
+            Self::UnknownAttack(id) => write!(f, "used an unknown attack {id}"),
+            Self::WrongWeapon {
+                attack_id,
+                attack_weapon,
+                equipped,
+            } => write!(
+                f,
+                "used the {attack_weapon:?} attack {attack_id} with {equipped:?} equipped"
+            ),
+        }
+    }
+}
+
+/// Per-user validation state.
+pub struct Validator {
+    last_move: Instant,
+    damage: TokenBucket,
+    /// Times of the strikes within the strike window.
+    strikes: VecDeque<Instant>,
+}
+
+impl Validator {
+    pub fn new(settings: &ValidationSettings) -> Self {
+        Self {
+            last_move: Instant::now(),
+            damage: TokenBucket::new(RateLimit {
+                rate: settings.damage_rate,
+                burst: settings.damage_burst,
+            }),
+            strikes: VecDeque::new(),
+        }
+    }
+    pub fn check_movement(
+        &mut self,
+        settings: &ValidationSettings,
+        from: &Position,
+        to: &Position,
+    ) -> Result<(), Suspicion> {
+        let elapsed = self.last_move.elapsed().as_secs_f32();
+        self.last_move = Instant::now();
+        let distance = distance(from, to);
+        // packets can arrive in bursts, so allow at least a second worth of movement
+        if distance > settings.max_speed * elapsed.max(1.0) {
+            return Err(Suspicion::Speed { distance, elapsed });
+        }
+        Ok(())
+    }
+    pub fn check_damage(
+        &mut self,
+        settings: &ValidationSettings,
+        player_pos: &Position,
+        hit_pos: &Position,
+        zones: (u32, u32),
+    ) -> Result<(), Suspicion> {
+        if !self.damage.try_take() {
+            return Err(Suspicion::DamageRate);
+        }
+        let (player_zone, target_zone) = zones;
+        if player_zone != target_zone {
+            return Err(Suspicion::OtherZone {
+                player_zone,
+                target_zone,
+            });
+        }
+        let distance = distance(player_pos, hit_pos);
+        if distance > settings.max_attack_range {
+            return Err(Suspicion::OutOfRange { distance });
+        }
+        Ok(())
+    }
+    /// Checks that the attack exists and can be used with the equipped weapon class.
+    /// `equipped` is `None` if the class of the equipped weapon is unknown.
+    pub fn check_attack(
+        attacks: &[AttackStats],
+        attack_id: u32,
+        equipped: Option<WeaponClass>,
+    ) -> Result<(), Suspicion> {
+        let Some(attack) = attacks.iter().find(|a| a.attack_id == attack_id) else {
+            return Err(Suspicion::UnknownAttack(attack_id));
+        };
+        match (attack.weapon, equipped) {
+            (Some(attack_weapon), Some(equipped)) if attack_weapon != equipped => {
+                Err(Suspicion::WrongWeapon {
+                    attack_id,
+                    attack_weapon,
+                    equipped,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Counts a strike, returns `true` if the user should be kicked.
+    pub fn add_strike(&mut self, settings: &ValidationSettings) -> bool {
+        self.add_strike_at(settings, Instant::now())
+    }
+    fn add_strike_at(&mut self, settings: &ValidationSettings, now: Instant) -> bool {
+        let threshold = settings.kick_threshold as usize;
+        if threshold == 0 {
+            return false;
+        }
+        if settings.strike_window != 0 {
+            let window = Duration::from_secs(settings.strike_window);
+            while self
+                .strikes
+                .front()
+                .is_some_and(|&t| now.saturating_duration_since(t) > window)
+            {
+                self.strikes.pop_front();
+            }
+        }
+        self.strikes.push_back(now);
+        if self.strikes.len() > threshold {
+            self.strikes.pop_front();
+        }
+        self.strikes.len() >= threshold
+    }
+}
+
+fn distance(a: &Position, b: &Position) -> f32 {
+    let dx = a.pos_x.to_f32() - b.pos_x.to_f32();
+    let dy = a.pos_y.to_f32() - b.pos_y.to_f32();
+    let dz = a.pos_z.to_f32() - b.pos_z.to_f32();
+    (dx * dx + dy * dy + dz * dz).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Suspicion, Validator};
+    use crate::settings::ValidationSettings;
+    use data_structs::stats::{AttackStats, WeaponClass};
+    use pso2packetlib::protocol::models::Position;
+    use std::time::{Duration, Instant};
+
+    fn pos(x: f32, z: f32) -> Position {
+        Position {
+            pos_x: half::f16::from_f32(x),
+            pos_z: half::f16::from_f32(z),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_movement() {
+        let settings = ValidationSettings {
+            max_speed: 10.0,
+            ..Default::default()
+        };
+        let mut validator = Validator::new(&settings);
+        assert!(validator
+            .check_movement(&settings, &pos(0.0, 0.0), &pos(6.0, 8.0))
+            .is_ok());
+        let result = validator.check_movement(&settings, &pos(0.0, 0.0), &pos(30.0, 40.0));
+        assert!(matches!(result, Err(Suspicion::Speed { distance, .. }) if distance == 50.0));
+    }
+
+    #[test]
+    fn test_damage() {
+        let settings = ValidationSettings {
+            max_attack_range: 10.0,
+            damage_rate: 0.001,
+            damage_burst: 3,
+            ..Default::default()
+        };
+        let mut validator = Validator::new(&settings);
+        let player = pos(0.0, 0.0);
+        assert!(validator
+            .check_damage(&settings, &player, &pos(3.0, 4.0), (1, 1))
+            .is_ok());
+        let result = validator.check_damage(&settings, &player, &pos(3.0, 4.0), (1, 2));
+        assert!(matches!(
+            result,
+            Err(Suspicion::OtherZone {
+                player_zone: 1,
+                target_zone: 2
+            })
+        ));
+        let result = validator.check_damage(&settings, &player, &pos(12.0, 16.0), (1, 1));
+        assert!(matches!(result, Err(Suspicion::OutOfRange { distance }) if distance == 20.0));
+        let result = validator.check_damage(&settings, &player, &pos(3.0, 4.0), (1, 1));
+        assert!(matches!(result, Err(Suspicion::DamageRate)));
+    }
+
+    #[test]
+    fn test_attacks() {
+        let attacks = [
+            AttackStats {
+                attack_id: 1,
+                weapon: Some(WeaponClass::Rifle),
+                ..Default::default()
+            },
+            AttackStats {
+                attack_id: 2,
+                ..Default::default()
+            },
+        ];
+        let rifle = Some(WeaponClass::Rifle);
+        assert!(Validator::check_attack(&attacks, 1, rifle).is_ok());
+        assert!(Validator::check_attack(&attacks, 1, None).is_ok());
+        assert!(Validator::check_attack(&attacks, 2, Some(WeaponClass::Unarmed)).is_ok());
+        assert!(matches!(
+            Validator::check_attack(&attacks, 1, Some(WeaponClass::Unarmed)),
+            Err(Suspicion::WrongWeapon {
+                attack_id: 1,
+                attack_weapon: WeaponClass::Rifle,
+                equipped: WeaponClass::Unarmed
+            })
+        ));
+        assert!(matches!(
+            Validator::check_attack(&attacks, 3, rifle),
+            Err(Suspicion::UnknownAttack(3))
+        ));
+    }
+
+    #[test]
+    fn test_strikes() {
+        let settings = ValidationSettings {
+            kick_threshold: 2,
+            strike_window: 60,
+            ..Default::default()
+        };
+        let mut validator = Validator::new(&settings);
+        let now = Instant::now();
+        assert!(!validator.add_strike_at(&settings, now));
+        assert!(validator.add_strike_at(&settings, now + Duration::from_secs(30)));
+        // older strikes are forgotten
+        let later = now + Duration::from_secs(120);
+        assert!(!validator.add_strike_at(&settings, later));
+        assert!(validator.add_strike_at(&settings, later + Duration::from_secs(1)));
+        assert!(!Suspicion::UnknownAttack(1).is_strike());
+        assert!(Suspicion::DamageRate.is_strike());
+    }
+}